@@ -0,0 +1,95 @@
+//! Compares `utils::MovingMedian' with sorting the whole window for each value, as it was
+//! implemented before, across window sizes
+//!
+//! Run with `cargo bench --bench moving_median'.
+
+use clevo_fan::utils::{MovingMedian, Temperature};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::collections::VecDeque;
+
+/// Moving median, sorting a copy of the window for each value
+struct SortingMovingMedian<T> {
+    window_size: usize,
+    buf: VecDeque<T>,
+}
+
+impl<T> SortingMovingMedian<T>
+where
+    T: Clone + Ord,
+{
+    fn new(window_size: usize) -> Self {
+        SortingMovingMedian {
+            window_size,
+            buf: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, value: T) -> T {
+        self.buf.push_back(value);
+        if self.buf.len() > self.window_size {
+            self.buf.pop_front();
+        }
+
+        let mut buf = Vec::from(self.buf.clone());
+        buf.sort();
+        buf.remove(buf.len() / 2)
+    }
+}
+
+/// Temperatures wandering around 60°C, with some noise
+fn temperatures(count: usize) -> Vec<Temperature> {
+    let mut state: u32 = 0x2545_f491;
+    (0..count)
+        .map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let noise = (state % 1000) as f64 / 100.0 - 5.0;
+            let drift = 10.0 * (i as f64 / 500.0).sin();
+            Temperature::from_degrees_celsius(60.0 + drift + noise)
+        })
+        .collect()
+}
+
+/// Window sizes, from a few seconds to an hour of temperatures read each second
+const WINDOW_SIZES: [usize; 4] = [5, 60, 600, 3600];
+
+fn moving_median(c: &mut Criterion) {
+    let mut group = c.benchmark_group("moving_median");
+    for window_size in WINDOW_SIZES {
+        // Fill the windows first, so that only full windows are measured
+        let temps = temperatures(2 * window_size + 1000);
+        let (fill, measured) = temps.split_at(window_size);
+
+        let mut median = MovingMedian::new(window_size);
+        let mut sorting = SortingMovingMedian::new(window_size);
+        for temp in fill {
+            median.push(*temp);
+            sorting.push(*temp);
+        }
+        for temp in measured {
+            assert_eq!(median.push(*temp), sorting.push(*temp));
+        }
+
+        group.bench_with_input(
+            BenchmarkId::new("incremental", window_size),
+            &window_size,
+            |b, _| {
+                let mut values = measured.iter().cycle();
+                b.iter(|| median.push(black_box(*values.next().unwrap())))
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("sorting", window_size),
+            &window_size,
+            |b, _| {
+                let mut values = measured.iter().cycle();
+                b.iter(|| sorting.push(black_box(*values.next().unwrap())))
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, moving_median);
+criterion_main!(benches);
@@ -0,0 +1,46 @@
+use crate::fan;
+use std::{
+    io,
+    os::unix::net::UnixDatagram,
+    process::{self, Command},
+    thread,
+};
+
+/// Socket of the local syslog daemon
+const SYSLOG_PATH: &str = "/dev/log";
+/// Facility `daemon' with severity `warning', see RFC 3164
+const SYSLOG_PRIORITY: u8 = 3 * 8 + 4;
+
+/// Send a message to the local syslog daemon, if there is one
+pub fn syslog(message: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    let line = format!(
+        "<{}>clevo-fan[{}]: {}",
+        SYSLOG_PRIORITY,
+        process::id(),
+        message
+    );
+    match socket.send_to(line.as_bytes(), SYSLOG_PATH) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result.map(|_| ()),
+    }
+}
+
+/// Run the alert hook, without waiting for it to finish
+///
+/// The command is run by `sh -c', with the kind of event (`stall' or `recover'), the fan and the
+/// message in the environment variables `CLEVO_FAN_EVENT', `CLEVO_FAN_ID' and
+/// `CLEVO_FAN_MESSAGE'.
+pub fn run_hook(command: &str, event: &str, fan: fan::Id, message: &str) -> io::Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("CLEVO_FAN_EVENT", event)
+        .env("CLEVO_FAN_ID", fan.to_string())
+        .env("CLEVO_FAN_MESSAGE", message)
+        .spawn()?;
+
+    // Reap the hook whenever it is done, so that it does not stay a zombie
+    thread::spawn(move || child.wait());
+    Ok(())
+}
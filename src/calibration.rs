@@ -0,0 +1,372 @@
+use crate::{ec, fan, shutdown};
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_CALIBRATION_PATH: &str = "/var/lib/clevo-fan/calibration.toml";
+
+/// Duty, below which fans are assumed not to start, if not calibrated
+pub const DEFAULT_SPIN_UP_DUTY: f64 = 38.0;
+
+/// How often to read the fan speed while waiting for it to settle
+const SETTLE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Change between successive readings, up to which the fan speed is considered settled
+const SETTLE_TOLERANCE: f64 = 0.02;
+
+/// Measured behaviour of the fans, as written by `clevo-fan calibrate'
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Calibration {
+    pub fans: BTreeMap<fan::Id, FanCalibration>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FanCalibration {
+    /// Lowest duty, that starts the stopped fan, if any does
+    pub spin_up_duty: Option<fan::Duty>,
+    /// Lowest duty, that keeps the running fan spinning, if any does
+    pub stop_duty: Option<fan::Duty>,
+    /// Speed at full duty
+    pub max_rpm: u32,
+    /// Speed at each measured duty, with rising duty
+    pub curve: Vec<Point>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Point {
+    pub duty: fan::Duty,
+    pub rpm: u32,
+}
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "Cannot read calibration {}: {}", "_0.display()", _1)]
+    Read(PathBuf, io::Error),
+    #[display(fmt = "Invalid calibration {}: {}", "_0.display()", _1)]
+    Parse(PathBuf, toml::de::Error),
+    #[display(fmt = "Cannot write calibration {}: {}", "_0.display()", _1)]
+    Write(PathBuf, io::Error),
+    #[display(fmt = "{}", _0)]
+    Ec(ec::Error),
+    #[display(fmt = "Cannot read the speed of fan {}", _0)]
+    NoSpeed(fan::Id),
+    #[display(fmt = "Interrupted")]
+    Interrupted,
+}
+impl error::Error for Error {}
+
+impl Calibration {
+    /// Read the calibration, if there is one at `path'
+    pub fn load(path: &Path) -> Result<Option<Self>, Error> {
+        match fs::read_to_string(path) {
+            Ok(source) => toml::from_str(&source)
+                .map(Some)
+                .map_err(|err| Error::Parse(path.to_owned(), err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::Read(path.to_owned(), err)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let write_error = |err| Error::Write(path.to_owned(), err);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(write_error)?;
+        }
+        let source = toml::to_string(self)
+            .map_err(|err| write_error(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+        fs::write(path, source).map_err(write_error)
+    }
+
+    /// Lowest duty, that starts the given fan, assuming the default if it is not calibrated
+    pub fn spin_up_duty(calibration: Option<&Self>, id: fan::Id) -> Option<fan::Duty> {
+        match calibration.and_then(|calibration| calibration.fans.get(&id)) {
+            Some(fan) => fan.spin_up_duty,
+            None => Some(fan::Duty::from_saturating_percentage(DEFAULT_SPIN_UP_DUTY)),
+        }
+    }
+
+    /// Adjust a duty, that is too low for the given fan to have any effect
+    ///
+    /// Duties too low to keep the fan running are set to 0. When starting the fan, i.e. the duty
+    /// `applied' so far is 0, duties too low to start it are raised to the spin-up duty. Once it
+    /// runs, it is left at any duty that keeps it running. Uncalibrated fans are left alone.
+    pub fn clamp(&self, id: fan::Id, duty: fan::Duty, applied: fan::Duty) -> fan::Duty {
+        let fan = match self.fans.get(&id) {
+            Some(fan) => fan,
+            None => return duty,
+        };
+        match (fan.stop_duty, fan.spin_up_duty) {
+            (Some(stop), _) if duty < stop => fan::Duty::min(),
+            (_, Some(spin_up))
+                if duty < spin_up && duty > fan::Duty::min() && applied == fan::Duty::min() =>
+            {
+                spin_up
+            }
+            _ => duty,
+        }
+    }
+}
+
+/// Measure the speed of a fan over its whole duty range
+///
+/// The duty is first raised from 0 to 100% in steps of `step' percent, then lowered again, waiting
+/// for the speed to settle at each step, but at most `settle'. While the fan stands still, the whole
+/// `settle' time is waited for it to start. Each measurement is printed to stdout.
+pub fn calibrate(
+    fan: &mut shutdown::Guard,
+    ec: &mut dyn ec::Backend,
+    signals: &shutdown::Signals,
+    id: fan::Id,
+    step: f64,
+    settle: Duration,
+) -> Result<FanCalibration, Error> {
+    let duties: Vec<_> = (0..)
+        .map(|i| i as f64 * step)
+        .take_while(|duty| *duty < 100.0)
+        .chain(Some(100.0))
+        .map(fan::Duty::from_saturating_percentage)
+        .collect();
+
+    // Start with the fan stopped, so that the spin-up duty is measured correctly
+    measure(fan, ec, signals, id, fan::Duty::min(), settle)?;
+
+    let mut curve = Vec::new();
+    for duty in &duties {
+        let rpm = measure(fan, ec, signals, id, *duty, settle)?;
+        writeln!(
+            io::stdout(),
+            "Fan {} rising:  {:>7} -> {:>4} RPM",
+            id,
+            duty,
+            rpm
+        )
+        .ok();
+        curve.push(Point { duty: *duty, rpm });
+    }
+    let spin_up_duty = curve
+        .iter()
+        .find(|point| point.duty > fan::Duty::min() && point.rpm > 0)
+        .map(|point| point.duty);
+
+    let mut stop_duty = None;
+    for duty in duties
+        .iter()
+        .rev()
+        .skip(1)
+        .filter(|duty| **duty > fan::Duty::min())
+    {
+        let rpm = measure(fan, ec, signals, id, *duty, settle)?;
+        writeln!(
+            io::stdout(),
+            "Fan {} falling: {:>7} -> {:>4} RPM",
+            id,
+            duty,
+            rpm
+        )
+        .ok();
+        if rpm == 0 {
+            break;
+        }
+        stop_duty = Some(*duty);
+    }
+
+    Ok(FanCalibration {
+        spin_up_duty,
+        stop_duty,
+        max_rpm: curve.last().map_or(0, |point| point.rpm),
+        curve,
+    })
+}
+
+/// Set the duty and wait for the fan speed to settle, returning the settled speed
+fn measure(
+    fan: &mut shutdown::Guard,
+    ec: &mut dyn ec::Backend,
+    signals: &shutdown::Signals,
+    id: fan::Id,
+    duty: fan::Duty,
+    settle: Duration,
+) -> Result<u32, Error> {
+    fan.set_duty(id, duty).map_err(Error::Ec)?;
+
+    let started = Instant::now();
+    let mut last = None;
+    loop {
+        thread::sleep(SETTLE_POLL_INTERVAL);
+        if signals.terminate_requested() {
+            return Err(Error::Interrupted);
+        }
+
+        let rpm = ec
+            .registers()
+            .map_err(Error::Ec)?
+            .fan(id)
+            .ok_or(Error::NoSpeed(id))?
+            .speed
+            .as_rpm();
+        // A fan standing still may just not have spun up yet, so that is only taken as the speed
+        // once `settle' has passed
+        let settled = last.is_some_and(|last: u32| {
+            last.max(rpm) > 0
+                && (rpm as f64 - last as f64).abs() <= last.max(rpm) as f64 * SETTLE_TOLERANCE
+        });
+        if settled || started.elapsed() >= settle {
+            return Ok(rpm);
+        }
+        last = Some(rpm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model;
+    use std::{cell::RefCell, rc::Rc};
+
+    fn duty(percentage: f64) -> fan::Duty {
+        fan::Duty::from_saturating_percentage(percentage)
+    }
+
+    fn calibration() -> Calibration {
+        let mut calibration = Calibration::default();
+        calibration.fans.insert(
+            fan::Id::CPU,
+            FanCalibration {
+                spin_up_duty: Some(duty(40.0)),
+                stop_duty: Some(duty(25.0)),
+                max_rpm: 4000,
+                curve: vec![
+                    Point {
+                        duty: duty(0.0),
+                        rpm: 0,
+                    },
+                    Point {
+                        duty: duty(50.0),
+                        rpm: 2000,
+                    },
+                    Point {
+                        duty: duty(100.0),
+                        rpm: 4000,
+                    },
+                ],
+            },
+        );
+        calibration
+    }
+
+    #[test]
+    fn clamp() {
+        let calibration = calibration();
+        let clamp =
+            |percentage, applied| calibration.clamp(fan::Id::CPU, duty(percentage), duty(applied));
+        // Below the stop duty, the fan is stopped
+        assert_eq!(clamp(20.0, 0.0), duty(0.0));
+        assert_eq!(clamp(20.0, 50.0), duty(0.0));
+        // Between stop and spin-up duty, a stopped fan is started at the spin-up duty
+        assert_eq!(clamp(30.0, 0.0), duty(40.0));
+        assert_eq!(clamp(0.0, 0.0), duty(0.0));
+        // A running fan is left at any duty, that keeps it running
+        assert_eq!(clamp(30.0, 50.0), duty(30.0));
+        assert_eq!(clamp(60.0, 0.0), duty(60.0));
+        // Uncalibrated fans are left alone
+        assert_eq!(
+            calibration.clamp(fan::Id::GPU, duty(10.0), duty(0.0)),
+            duty(10.0)
+        );
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clevo-fan").join("calibration.toml");
+        assert!(Calibration::load(&path).unwrap().is_none());
+
+        calibration().save(&path).unwrap();
+        let loaded = Calibration::load(&path).unwrap().unwrap();
+        assert_eq!(format!("{:?}", loaded), format!("{:?}", calibration()));
+    }
+
+    /// EC with a fan, that starts at a duty register value of 150 and keeps running down to 100,
+    /// at 16 RPM per unit of the register
+    #[derive(Clone)]
+    struct Simulated {
+        registers: Rc<RefCell<[u8; ec::EC_REG_SIZE]>>,
+        model: Rc<model::Model>,
+    }
+
+    impl ec::Backend for Simulated {
+        fn read(&mut self) -> Result<[u8; ec::EC_REG_SIZE], ec::Error> {
+            Ok(*self.registers.borrow())
+        }
+
+        fn write(&mut self, cmd: u8, port: u8, value: u8) -> Result<(), ec::Error> {
+            let fan = &self.model.fans[0];
+            if (cmd, port) == (self.model.fan_control_cmd, self.model.fan_auto_port) {
+                return Ok(());
+            }
+            assert_eq!((cmd, port), (self.model.fan_control_cmd, fan.port));
+
+            let mut registers = self.registers.borrow_mut();
+            let running = registers[fan.rpm_lo as usize] > 0 || registers[fan.rpm_hi as usize] > 0;
+            let raw = if value >= 150 || (running && value >= 100) {
+                (self.model.rpm_magic / (value as u32 * 16)) as u16
+            } else {
+                0
+            };
+            registers[fan.duty as usize] = value;
+            registers[fan.rpm_hi as usize] = (raw >> 8) as u8;
+            registers[fan.rpm_lo as usize] = raw as u8;
+            Ok(())
+        }
+
+        fn model(&self) -> &model::Model {
+            &self.model
+        }
+    }
+
+    #[test]
+    fn calibrate_fan() {
+        let mut ec = Simulated {
+            registers: Rc::new(RefCell::new([0; ec::EC_REG_SIZE])),
+            model: Rc::new(model::Model::select(Some("clevo"), None, false).unwrap()),
+        };
+        let mut fan = shutdown::Guard::new(
+            fan::Control::new(Box::new(ec.clone())),
+            fan::Handover::Firmware,
+            vec![fan::Id::CPU],
+        );
+        let signals = shutdown::Signals::register().unwrap();
+        let result = calibrate(
+            &mut fan,
+            &mut ec,
+            &signals,
+            fan::Id::CPU,
+            25.0,
+            SETTLE_POLL_INTERVAL,
+        )
+        .unwrap();
+
+        // 75% is the first step to start the fan, which then keeps running down to 50%
+        assert_eq!(result.spin_up_duty, Some(duty(75.0)));
+        assert_eq!(result.stop_duty, Some(duty(50.0)));
+        let curve: Vec<_> = result
+            .curve
+            .iter()
+            .map(|point| (point.duty.as_percentage(), point.rpm))
+            .collect();
+        assert_eq!(curve.len(), 5);
+        assert_eq!(curve[..3], [(0.0, 0), (25.0, 0), (50.0, 0)]);
+        assert!((curve[3].1 as f64 - 191.0 * 16.0).abs() < 10.0);
+        assert_eq!(result.max_rpm, curve[4].1);
+        assert!((result.max_rpm as f64 - 255.0 * 16.0).abs() < 10.0);
+    }
+}
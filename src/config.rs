@@ -0,0 +1,1335 @@
+use crate::{calibration, ctl, fan, sensor, utils, AutoOptions, Options, Policies};
+use derive_more::Display;
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Contents of a configuration file
+///
+/// The layout mirrors the command line: Global options are top-level keys, options of `auto' are
+/// in the `[auto]' table and policy options in the `[policy]' table, all named like the long
+/// options.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct File {
+    ec_path: Option<PathBuf>,
+    simulate: bool,
+    lock_path: Option<PathBuf>,
+    model: Option<String>,
+    model_file: Option<PathBuf>,
+    calibration: Option<PathBuf>,
+    sysfs_root: Option<PathBuf>,
+    auto: AutoOptions,
+    policy: Policies,
+    /// Named curves, that can be referred to by `--curve <name>'
+    curves: BTreeMap<String, fan::policy::Curve>,
+    /// Named sets of options, that can be switched to by `clevo-fan ctl profile <name>'
+    profiles: BTreeMap<String, Profile>,
+    /// Settings of individual fans, that differ from the `[auto]' and `[policy]' tables
+    fans: BTreeMap<fan::Id, FanSection>,
+
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    source: String,
+}
+
+/// Options of `auto', that take precedence over all others while the profile is active
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    auto: AutoOptions,
+    policy: Policies,
+}
+
+/// Policy and input of a single fan, falling back to the general settings
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FanSection {
+    input: Option<Input>,
+    policy: Policies,
+    /// Further inputs with policies of their own, see `FanSettings::sensors`
+    sensors: Vec<SensorSection>,
+}
+
+/// Input and policy of a `[[fans.<id>.sensors]]' entry, the policy falling back to that of the fan
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SensorSection {
+    input: Input,
+    #[serde(default)]
+    policy: Policies,
+}
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "Cannot read config file {}: {}", "_0.display()", _1)]
+    Read(PathBuf, io::Error),
+    #[display(fmt = "Invalid config file {}: {}", "_0.display()", _1)]
+    Parse(PathBuf, toml::de::Error),
+    #[display(fmt = "Invalid config file {}: {} at line {}", "_0.display()", _1, _2)]
+    Invalid(PathBuf, String, usize),
+    #[display(fmt = "{}", _0)]
+    Options(String),
+}
+impl error::Error for Error {}
+
+impl File {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let source = fs::read_to_string(path).map_err(|err| Error::Read(path.to_owned(), err))?;
+        let file: File =
+            toml::from_str(&source).map_err(|err| Error::Parse(path.to_owned(), err))?;
+
+        Ok(File {
+            path: path.to_owned(),
+            source,
+            ..file
+        })
+    }
+
+    /// Names of the profiles, see `clevo-fan ctl profile'
+    pub fn profiles(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+
+    fn line(&self, offset: usize) -> usize {
+        self.source[..offset].matches('\n').count() + 1
+    }
+
+    /// Error about a value, located at the given position in the file, if there is one
+    fn error<T>(file: Option<&File>, value: &Located<T>, message: String) -> Error {
+        match (file, value.offset) {
+            (Some(file), Some(offset)) => {
+                Error::Invalid(file.path.clone(), message, file.line(offset))
+            }
+            _ => Error::Options(message),
+        }
+    }
+}
+
+/// A value, that knows where in the configuration file it came from, if it did at all
+#[derive(Debug, Clone, Default)]
+pub struct Located<T> {
+    pub value: T,
+    offset: Option<usize>,
+}
+
+impl<T: FromStr> FromStr for Located<T> {
+    type Err = T::Err;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Located {
+            value: T::from_str(s)?,
+            offset: None,
+        })
+    }
+}
+
+impl<T> From<T> for Located<T> {
+    fn from(value: T) -> Self {
+        Located {
+            value,
+            offset: None,
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Located<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let spanned = toml::Spanned::<T>::deserialize(deserializer)?;
+        Ok(Located {
+            offset: Some(spanned.start()),
+            value: spanned.into_inner(),
+        })
+    }
+}
+
+/// The value of an option, that has to be greater than 0, if it is given
+fn positive<T>(
+    file: Option<&File>,
+    value: &Option<Located<T>>,
+    name: &str,
+) -> Result<Option<T>, Error>
+where
+    T: Copy + Default + PartialOrd + fmt::Display,
+{
+    match value {
+        Some(located) if located.value <= T::default() => Err(File::error(
+            file,
+            located,
+            format!("`{}' must be greater than 0, got {}", name, located.value),
+        )),
+        value => Ok(value.as_ref().map(|located| located.value)),
+    }
+}
+
+/// The value of an option, that must not be negative, if it is given
+fn non_negative(
+    file: Option<&File>,
+    value: &Option<Located<f64>>,
+    name: &str,
+) -> Result<Option<f64>, Error> {
+    match value {
+        Some(located) if located.value < 0.0 || located.value.is_nan() => Err(File::error(
+            file,
+            located,
+            format!("`{}' must not be negative, got {}", name, located.value),
+        )),
+        value => Ok(value.as_ref().map(|located| located.value)),
+    }
+}
+
+/// Deserialize a value from its textual representation
+///
+/// Accepts strings as well as numbers, so e.g. both `on-exit = 40` and `on-exit = "auto"` work.
+pub fn deserialize_parsed<'de, D, T, E>(
+    deserializer: D,
+    parse: fn(&str) -> Result<T, E>,
+) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    E: fmt::Display,
+{
+    struct Visitor;
+    impl<'de> de::Visitor<'de> for Visitor {
+        type Value = String;
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "a string or a number")
+        }
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            Ok(value.to_owned())
+        }
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+            Ok(value.to_string())
+        }
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+            Ok(value.to_string())
+        }
+        fn visit_f64<E: de::Error>(self, value: f64) -> Result<Self::Value, E> {
+            Ok(value.to_string())
+        }
+    }
+
+    let text = deserializer.deserialize_any(Visitor)?;
+    parse(&text).map_err(de::Error::custom)
+}
+
+/// Settings of `auto', with command line, configuration file and defaults merged
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub profile: Option<String>,
+    /// The fans to control, ordered by id
+    pub fans: Vec<FanSettings>,
+    pub polling_interval: Duration,
+    /// Filters of each temperature, applied in order before it is fed to the policies
+    pub filters: Vec<Filter>,
+    pub min_fan_change: f64,
+    pub max_unchanged_cycles: usize,
+    pub hysteresis: Option<Hysteresis>,
+    pub monitor: bool,
+    /// Unit of temperatures printed by `--monitor'
+    pub units: utils::Unit,
+    pub tui: bool,
+    pub on_exit: fan::Handover,
+    pub socket: PathBuf,
+    pub stall: Stall,
+    /// Whether the policies determine the fan speed instead of the fan duty
+    pub rpm: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanSettings {
+    pub id: fan::Id,
+    pub input: Input,
+    pub policy: Policy,
+    /// Further inputs with policies of their own, the highest resulting duty is used
+    pub sensors: Vec<SensorSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorSettings {
+    pub input: Input,
+    pub policy: Policy,
+}
+
+/// Temperature, that the fan duty is based on
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    Single(Reading),
+    /// The highest one of several temperatures
+    Max(Vec<Reading>),
+    /// Weighted average of several temperatures
+    Average(Vec<(Reading, f64)>),
+}
+
+/// A single temperature, as read from the EC or the kernel
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reading {
+    Cpu,
+    Gpu,
+    Sensor(sensor::Source),
+}
+
+#[derive(Debug, Display)]
+#[display(
+    fmt = "Invalid input `{}', expected cpu, gpu, hwmon:<chip>[:<sensor>], thermal:<zone>, max(<input>,...) or avg(<input>[=<weight>],...)",
+    _0
+)]
+pub struct InvalidInput(String);
+impl error::Error for InvalidInput {}
+
+impl FromStr for Reading {
+    type Err = InvalidInput;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpu" => Ok(Reading::Cpu),
+            "gpu" => Ok(Reading::Gpu),
+            _ => sensor::Source::from_str(s)
+                .map(Reading::Sensor)
+                .map_err(|_| InvalidInput(s.to_owned())),
+        }
+    }
+}
+
+impl FromStr for Input {
+    type Err = InvalidInput;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidInput(s.to_owned());
+        if s == "max" {
+            return Ok(Input::Max(vec![Reading::Cpu, Reading::Gpu]));
+        }
+
+        let composite = |name: &str| {
+            s.strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix('('))
+                .and_then(|rest| rest.strip_suffix(')'))
+                .map(|inner| inner.split(',').map(str::trim).collect::<Vec<_>>())
+        };
+        if let Some(inputs) = composite("max") {
+            inputs
+                .into_iter()
+                .map(|input| Reading::from_str(input).map_err(|_| invalid()))
+                .collect::<Result<_, _>>()
+                .map(Input::Max)
+        } else if let Some(inputs) = composite("avg") {
+            inputs
+                .into_iter()
+                .map(|input| {
+                    let (input, weight) = match input.rsplit_once('=') {
+                        Some((input, weight)) => (
+                            input.trim(),
+                            f64::from_str(weight.trim())
+                                .ok()
+                                .filter(|weight| weight.is_finite() && *weight > 0.0)
+                                .ok_or_else(invalid)?,
+                        ),
+                        None => (input, 1.0),
+                    };
+                    Ok((Reading::from_str(input).map_err(|_| invalid())?, weight))
+                })
+                .collect::<Result<_, _>>()
+                .map(Input::Average)
+        } else {
+            Reading::from_str(s).map(Input::Single)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Input {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_parsed(deserializer, Self::from_str)
+    }
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reading::Cpu => write!(f, "cpu"),
+            Reading::Gpu => write!(f, "gpu"),
+            Reading::Sensor(source) => write!(f, "{}", source),
+        }
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Single(reading) => write!(f, "{}", reading),
+            Input::Max(readings) => {
+                write!(f, "max(")?;
+                for (i, reading) in readings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", reading)?;
+                }
+                write!(f, ")")
+            }
+            Input::Average(readings) => {
+                write!(f, "avg(")?;
+                for (i, (reading, weight)) in readings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}={}", reading, weight)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl Input {
+    pub fn readings(&self) -> Vec<&Reading> {
+        match self {
+            Input::Single(reading) => vec![reading],
+            Input::Max(readings) => readings.iter().collect(),
+            Input::Average(readings) => readings.iter().map(|(reading, _)| reading).collect(),
+        }
+    }
+
+    /// Whether any of the temperatures of this input could be read
+    pub fn is_readable(&self, temps: &BTreeMap<Reading, Option<utils::Temperature>>) -> bool {
+        self.readings()
+            .into_iter()
+            .any(|reading| temps.get(reading).copied().flatten().is_some())
+    }
+
+    /// Combine the temperatures read, skipping those that could not be read
+    ///
+    /// Only if none of them could be read, the temperature is assumed to be as high as possible.
+    pub fn combine(
+        &self,
+        temps: &BTreeMap<Reading, Option<utils::Temperature>>,
+    ) -> utils::Temperature {
+        let temp = |reading: &Reading| temps.get(reading).copied().flatten();
+        let combined = match self {
+            Input::Single(reading) => temp(reading),
+            Input::Max(readings) => {
+                readings
+                    .iter()
+                    .filter_map(temp)
+                    .fold(None, |max, temp| match max {
+                        Some(max) if max >= temp => Some(max),
+                        _ => Some(temp),
+                    })
+            }
+            Input::Average(readings) => {
+                let (sum, weights) = readings
+                    .iter()
+                    .filter_map(|(reading, weight)| Some((temp(reading)?, *weight)))
+                    .fold((0.0, 0.0), |(sum, weights), (temp, weight)| {
+                        (sum + temp.as_degrees_celsius() * weight, weights + weight)
+                    });
+                if weights > 0.0 {
+                    Some(utils::Temperature::from_degrees_celsius(sum / weights))
+                } else {
+                    None
+                }
+            }
+        };
+        combined.unwrap_or_else(utils::Temperature::max)
+    }
+}
+
+/// Settings of the detection of stalled fans
+#[derive(Debug, Clone)]
+pub struct Stall {
+    /// Duty above which a fan has to spin, see `Stall::min_duty'
+    pub min_duty: Option<fan::Duty>,
+    /// How long a fan has to be too slow to be considered stalled
+    pub time: Duration,
+    /// Speed of the fans at full duty, if known
+    pub max_rpm: Option<u32>,
+    pub action: StallAction,
+    /// Command to run on alerts
+    pub hook: Option<String>,
+}
+
+impl Stall {
+    /// Duty above which the given fan has to spin, if it spins at any
+    ///
+    /// Defaults to the spin-up duty of calibrated fans, else to 40%.
+    pub fn min_duty(
+        &self,
+        calibration: Option<&calibration::Calibration>,
+        id: fan::Id,
+    ) -> Option<fan::Duty> {
+        match (
+            self.min_duty,
+            calibration.and_then(|calibration| calibration.fans.get(&id)),
+        ) {
+            (Some(min_duty), _) => Some(min_duty),
+            (None, Some(fan)) => fan.spin_up_duty,
+            (None, None) => Some(fan::Duty::from_saturating_percentage(40.0)),
+        }
+    }
+}
+
+/// What to do with a stalled fan, until it recovers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StallAction {
+    /// Only alert, keep following the policy
+    None,
+    /// Force the fan to a fixed duty, e.g. to unstick it
+    Duty(fan::Duty),
+}
+
+impl FromStr for StallAction {
+    type Err = fan::ParsePercentageError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(StallAction::None),
+            _ => fan::Duty::from_percentage_str(s).map(StallAction::Duty),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for StallAction {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_parsed(deserializer, Self::from_str)
+    }
+}
+
+/// Filter of the temperature, see `--filter'
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    MovingAverage(usize),
+    MovingMedian(usize),
+    ExponentialMovingAverage(Duration),
+    /// Largest jump let through, in degrees Celsius
+    SpikeRejection(f64),
+    /// Largest change, in degrees Celsius per second
+    RateLimit(f64),
+}
+
+#[derive(Debug, Display)]
+#[display(
+    fmt = "Invalid filter `{}', expected average:<n>, median:<n>, ema:<time>, spike:<degrees> or rate:<degrees per second>",
+    _0
+)]
+pub struct InvalidFilter(String);
+impl error::Error for InvalidFilter {}
+
+impl Filter {
+    /// Whether the parameter of the filter is in its range, e.g. a window is not empty
+    fn is_valid(&self) -> bool {
+        match *self {
+            Filter::MovingAverage(window) | Filter::MovingMedian(window) => window > 0,
+            Filter::ExponentialMovingAverage(_) => true,
+            Filter::SpikeRejection(degrees) | Filter::RateLimit(degrees) => {
+                degrees.is_finite() && degrees > 0.0
+            }
+        }
+    }
+
+    /// The filter given by a shorthand option like `--moving-average', checked like `--filter'
+    fn from_option(
+        file: Option<&File>,
+        value: &Option<Located<usize>>,
+        name: &str,
+        filter: fn(usize) -> Filter,
+    ) -> Result<Option<Self>, Error> {
+        match value {
+            Some(located) if !filter(located.value).is_valid() => Err(File::error(
+                file,
+                located,
+                format!("`{}' must be greater than 0, got {}", name, located.value),
+            )),
+            value => Ok(value.as_ref().map(|located| filter(located.value))),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = InvalidFilter;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidFilter(s.to_owned());
+        let (kind, parameter) = s.split_once(':').ok_or_else(invalid)?;
+        let parameter = parameter.trim();
+        let window = || usize::from_str(parameter).map_err(|_| invalid());
+        let degrees = || f64::from_str(parameter).map_err(|_| invalid());
+        let filter = match kind.trim() {
+            "average" => window().map(Filter::MovingAverage),
+            "median" => window().map(Filter::MovingMedian),
+            "ema" => humantime::parse_duration(parameter)
+                .map(Filter::ExponentialMovingAverage)
+                .map_err(|_| invalid()),
+            "spike" => degrees().map(Filter::SpikeRejection),
+            "rate" => degrees().map(Filter::RateLimit),
+            _ => Err(invalid()),
+        }?;
+        if filter.is_valid() {
+            Ok(filter)
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Filter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_parsed(deserializer, Self::from_str)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::MovingAverage(window) => write!(f, "average:{}", window),
+            Filter::MovingMedian(window) => write!(f, "median:{}", window),
+            Filter::ExponentialMovingAverage(time_constant) => {
+                write!(f, "ema:{}", humantime::format_duration(*time_constant))
+            }
+            Filter::SpikeRejection(max_jump) => write!(f, "spike:{}", max_jump),
+            Filter::RateLimit(max_rate) => write!(f, "rate:{}", max_rate),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hysteresis {
+    pub rise: f64,
+    pub fall: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Policy {
+    Linear(fan::policy::Linear),
+    Exponential(fan::policy::Exponential),
+    Quadratic(fan::policy::Quadratic),
+    Curve(fan::policy::Curve),
+    Pid(fan::policy::Pid),
+}
+
+impl Settings {
+    /// Merge the given profile, the command line, the configuration file and defaults, in this
+    /// order of precedence
+    pub fn resolve(
+        options: &AutoOptions,
+        policies: &Policies,
+        file: Option<&File>,
+        profile: Option<&str>,
+    ) -> Result<Self, Error> {
+        let (mut options, mut policies) = match file {
+            Some(file) => (
+                options.clone().or(file.auto.clone()),
+                policies.clone().or(file.policy.clone()),
+            ),
+            None => (options.clone(), policies.clone()),
+        };
+
+        if let Some(name) = profile {
+            let profile = file
+                .and_then(|file| file.profiles.get(name))
+                .ok_or_else(|| Error::Options(format!("Unknown profile `{}'", name)))?;
+            options = profile.auto.clone().or(options);
+            policies = profile.policy.clone().or(policies);
+        }
+
+        // Fans given by `--fan' follow the general policy, unless they have a section of their
+        // own, as do all other fans with a section
+        let input = options.input.clone().unwrap_or(Input::Single(Reading::Cpu));
+        let sections = file.map(|file| &file.fans);
+        let mut ids = options.fan.clone().unwrap_or_else(|| vec![fan::Id::CPU]);
+        ids.extend(sections.into_iter().flat_map(|sections| sections.keys()));
+        ids.sort();
+        ids.dedup();
+        let fans = ids
+            .into_iter()
+            .map(|id| {
+                Ok(match sections.and_then(|sections| sections.get(&id)) {
+                    Some(section) => {
+                        let policies = section.policy.clone().or(policies.clone());
+                        FanSettings {
+                            id,
+                            input: section.input.clone().unwrap_or_else(|| input.clone()),
+                            policy: Policy::resolve(&policies, file)?,
+                            sensors: section
+                                .sensors
+                                .iter()
+                                .map(|sensor| {
+                                    Ok(SensorSettings {
+                                        input: sensor.input.clone(),
+                                        policy: Policy::resolve(
+                                            &sensor.policy.clone().or(policies.clone()),
+                                            file,
+                                        )?,
+                                    })
+                                })
+                                .collect::<Result<_, Error>>()?,
+                        }
+                    }
+                    None => FanSettings {
+                        id,
+                        input: input.clone(),
+                        policy: Policy::resolve(&policies, file)?,
+                        sensors: Vec::new(),
+                    },
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let polling_interval = positive(file, &options.polling_interval, "polling-interval")?;
+        let moving_average = Filter::from_option(
+            file,
+            &options.moving_average,
+            "moving-average",
+            Filter::MovingAverage,
+        )?;
+        let moving_median = Filter::from_option(
+            file,
+            &options.moving_median,
+            "moving-median",
+            Filter::MovingMedian,
+        )?;
+        if let (Some(_), Some(located)) = (&options.moving_average, &options.moving_median) {
+            return Err(File::error(
+                file,
+                located,
+                "`moving-average' and `moving-median' cannot be combined, use `filter' instead"
+                    .to_owned(),
+            ));
+        }
+        let min_fan_change = non_negative(file, &options.min_fan_change, "min-fan-change")?;
+        let hysteresis = non_negative(file, &options.hysteresis, "hysteresis")?;
+        let hysteresis_rise = non_negative(file, &options.hysteresis_rise, "hysteresis-rise")?;
+        let stall_time = positive(file, &options.stall_time, "stall-time")?;
+
+        Ok(Settings {
+            profile: profile.map(str::to_owned),
+            fans,
+            polling_interval: Duration::from_millis(polling_interval.unwrap_or(500)),
+            filters: moving_median
+                .or(moving_average)
+                .into_iter()
+                .chain(options.filter.iter().flatten().copied())
+                .collect(),
+            min_fan_change: min_fan_change.unwrap_or(0.0),
+            max_unchanged_cycles: options.max_unchanged_cycles.unwrap_or(10),
+            hysteresis: hysteresis.map(|fall| Hysteresis {
+                rise: hysteresis_rise.unwrap_or(0.0),
+                fall,
+            }),
+            monitor: options.monitor,
+            units: options.units.unwrap_or_default(),
+            tui: options.tui,
+            on_exit: options.on_exit.unwrap_or(fan::Handover::Firmware),
+            socket: options
+                .socket
+                .unwrap_or_else(|| PathBuf::from(ctl::DEFAULT_SOCKET_PATH)),
+            stall: Stall {
+                min_duty: options.stall_min_duty,
+                time: Duration::from_secs(stall_time.unwrap_or(10)),
+                max_rpm: options.fan_max_rpm,
+                action: options.on_stall.unwrap_or_else(|| {
+                    StallAction::Duty(fan::Duty::from_saturating_percentage(100.0))
+                }),
+                hook: options.alert_hook.clone(),
+            },
+            rpm: options.rpm,
+        })
+    }
+
+    pub fn fan_ids(&self) -> Vec<fan::Id> {
+        self.fans.iter().map(|fan| fan.id).collect()
+    }
+
+    /// Temperatures, that any fan is based on
+    pub fn readings(&self) -> Vec<Reading> {
+        let mut readings: Vec<_> = self
+            .fans
+            .iter()
+            .flat_map(|fan| {
+                fan.sensors
+                    .iter()
+                    .map(|sensor| &sensor.input)
+                    .chain(Some(&fan.input))
+            })
+            .flat_map(Input::readings)
+            .cloned()
+            .collect();
+        readings.sort();
+        readings.dedup();
+        readings
+    }
+
+    /// Sensors of the kernel, that any fan is based on
+    pub fn sensors(&self) -> Vec<sensor::Source> {
+        self.readings()
+            .into_iter()
+            .filter_map(|reading| match reading {
+                Reading::Sensor(source) => Some(source),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn build_policy(
+        &self,
+        policy: &Policy,
+    ) -> Box<dyn fan::Policy<Input = utils::Temperature>> {
+        let policy: Box<dyn fan::Policy<Input = utils::Temperature>> = match policy {
+            Policy::Linear(policy) => Box::new(policy.clone()),
+            Policy::Exponential(policy) => Box::new(policy.clone()),
+            Policy::Quadratic(policy) => Box::new(policy.clone()),
+            Policy::Curve(policy) => Box::new(policy.clone()),
+            Policy::Pid(policy) => Box::new(policy.clone()),
+        };
+
+        match self.hysteresis {
+            Some(Hysteresis { rise, fall }) => {
+                Box::new(fan::policy::Hysteresis::new(policy, rise, fall))
+            }
+            None => policy,
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Policy::Linear(policy) => write!(
+                f,
+                "linear (slope {}, offset {})",
+                policy.slope, policy.offset
+            ),
+            Policy::Exponential(policy) => write!(
+                f,
+                "exponential (base {}, factor {})",
+                policy.base, policy.factor
+            ),
+            Policy::Quadratic(policy) => write!(f, "square (factor {})", policy.factor),
+            Policy::Curve(policy) => write!(f, "curve {}", policy),
+            Policy::Pid(policy) => write!(
+                f,
+                "PID (setpoint {}, kp {}, ki {}, kd {}, min duty {})",
+                policy.setpoint, policy.kp, policy.ki, policy.kd, policy.min_duty
+            ),
+        }
+    }
+}
+
+impl Policy {
+    fn resolve(policies: &Policies, file: Option<&File>) -> Result<Self, Error> {
+        let location = |name, offset| Located {
+            value: name,
+            offset,
+        };
+        let mut selected: Vec<_> = [
+            policies
+                .linear
+                .value
+                .then(|| location("linear", policies.linear.offset)),
+            policies
+                .exp
+                .value
+                .then(|| location("exp", policies.exp.offset)),
+            policies
+                .square
+                .value
+                .then(|| location("square", policies.square.offset)),
+            policies
+                .curve
+                .as_ref()
+                .map(|curve| location("curve", curve.offset)),
+            policies.pid.as_ref().map(|pid| location("pid", pid.offset)),
+        ]
+        .iter()
+        .flatten()
+        .cloned()
+        .collect();
+        // The policy selected last in the configuration file is the one in conflict
+        selected.sort_by_key(|selected| selected.offset);
+        match selected.as_slice() {
+            [] => {
+                return Err(Error::Options(
+                    "No policy selected, use one of `--linear', `--exp', `--square', `--curve' or `--pid'".to_owned(),
+                ))
+            }
+            [_] => (),
+            [.., previous, conflicting] => {
+                return Err(File::error(
+                    file,
+                    conflicting,
+                    format!(
+                        "Only one policy can be selected, but `{}' is selected besides `{}'",
+                        conflicting.value, previous.value
+                    ),
+                ))
+            }
+        }
+
+        Ok(if policies.linear.value {
+            Policy::Linear(fan::policy::Linear {
+                slope: policies.linear_slope.unwrap_or(1.0),
+                offset: policies.linear_offset.unwrap_or(0.0),
+            })
+        } else if policies.exp.value {
+            Policy::Exponential(fan::policy::Exponential {
+                base: policies
+                    .exp_base
+                    .unwrap_or(fan::policy::ExponentialBase::Euler),
+                factor: policies.exp_factor.unwrap_or(1.0),
+            })
+        } else if policies.square.value {
+            Policy::Quadratic(fan::policy::Quadratic {
+                factor: policies.square_factor.unwrap_or(0.01),
+            })
+        } else if let Some(curve) = &policies.curve {
+            let named = file.and_then(|file| file.curves.get(&curve.value));
+            Policy::Curve(match named {
+                Some(named) => named.clone(),
+                None => fan::policy::Curve::from_str(&curve.value).map_err(|err| {
+                    if curve.value.contains(':') {
+                        File::error(file, curve, err.to_string())
+                    } else {
+                        File::error(file, curve, format!("Unknown curve `{}'", curve.value))
+                    }
+                })?,
+            })
+        } else if let Some(setpoint) = &policies.pid {
+            Policy::Pid(fan::policy::Pid::new(
+                setpoint.value,
+                non_negative(file, &policies.pid_kp, "pid-kp")?.unwrap_or(4.0),
+                non_negative(file, &policies.pid_ki, "pid-ki")?.unwrap_or(0.2),
+                non_negative(file, &policies.pid_kd, "pid-kd")?.unwrap_or(0.0),
+                policies.pid_min_duty.unwrap_or_else(fan::Duty::min),
+            ))
+        } else {
+            unreachable!("Exactly one policy is selected")
+        })
+    }
+}
+
+impl Options {
+    /// Fill in options not given on the command line from the configuration file
+    pub fn or(&self, file: &File) -> Options {
+        Options {
+            ec_path: self.ec_path.clone().or_else(|| file.ec_path.clone()),
+            simulate: self.simulate || file.simulate,
+            lock_path: self.lock_path.clone().or_else(|| file.lock_path.clone()),
+            force: self.force,
+            model: self.model.clone().or_else(|| file.model.clone()),
+            model_file: self.model_file.clone().or_else(|| file.model_file.clone()),
+            calibration: self
+                .calibration
+                .clone()
+                .or_else(|| file.calibration.clone()),
+            sysfs_root: self.sysfs_root.clone().or_else(|| file.sysfs_root.clone()),
+        }
+    }
+}
+
+impl AutoOptions {
+    fn or(self, other: AutoOptions) -> AutoOptions {
+        // Filter options replace each other as a whole, so that e.g. `--moving-average' on the
+        // command line is not shadowed by `moving-median' or `filter' from the configuration file
+        let (moving_average, moving_median, filter) = if self.moving_average.is_some()
+            || self.moving_median.is_some()
+            || self.filter.is_some()
+        {
+            (self.moving_average, self.moving_median, self.filter)
+        } else {
+            (other.moving_average, other.moving_median, other.filter)
+        };
+
+        AutoOptions {
+            config: self.config.or(other.config),
+            polling_interval: self.polling_interval.or(other.polling_interval),
+            moving_average,
+            moving_median,
+            filter,
+            min_fan_change: self.min_fan_change.or(other.min_fan_change),
+            max_unchanged_cycles: self.max_unchanged_cycles.or(other.max_unchanged_cycles),
+            hysteresis: self.hysteresis.or(other.hysteresis),
+            hysteresis_rise: self.hysteresis_rise.or(other.hysteresis_rise),
+            monitor: self.monitor || other.monitor,
+            units: self.units.or(other.units),
+            tui: self.tui || other.tui,
+            on_exit: self.on_exit.or(other.on_exit),
+            socket: self.socket.or(other.socket),
+            fan: self.fan.or(other.fan),
+            input: self.input.or(other.input),
+            profile: self.profile.or(other.profile),
+            record: self.record.or(other.record),
+            stall_min_duty: self.stall_min_duty.or(other.stall_min_duty),
+            stall_time: self.stall_time.or(other.stall_time),
+            fan_max_rpm: self.fan_max_rpm.or(other.fan_max_rpm),
+            rpm: self.rpm || other.rpm,
+            on_stall: self.on_stall.or(other.on_stall),
+            alert_hook: self.alert_hook.or(other.alert_hook),
+        }
+    }
+}
+
+impl Policies {
+    fn selects_policy(&self) -> bool {
+        self.linear.value
+            || self.exp.value
+            || self.square.value
+            || self.curve.is_some()
+            || self.pid.is_some()
+    }
+
+    fn or(self, other: Policies) -> Policies {
+        // A policy selected on the command line replaces the one from the configuration file,
+        // while the parameters of the policies are merged individually
+        let other = if self.selects_policy() {
+            Policies {
+                linear: Located::default(),
+                exp: Located::default(),
+                square: Located::default(),
+                curve: None,
+                pid: None,
+                ..other
+            }
+        } else {
+            other
+        };
+
+        Policies {
+            linear: if self.linear.value {
+                self.linear
+            } else {
+                other.linear
+            },
+            linear_slope: self.linear_slope.or(other.linear_slope),
+            linear_offset: self.linear_offset.or(other.linear_offset),
+            exp: if self.exp.value { self.exp } else { other.exp },
+            exp_base: self.exp_base.or(other.exp_base),
+            exp_factor: self.exp_factor.or(other.exp_factor),
+            square: if self.square.value {
+                self.square
+            } else {
+                other.square
+            },
+            square_factor: self.square_factor.or(other.square_factor),
+            curve: self.curve.or(other.curve),
+            pid: self.pid.or(other.pid),
+            pid_kp: self.pid_kp.or(other.pid_kp),
+            pid_ki: self.pid_ki.or(other.pid_ki),
+            pid_kd: self.pid_kd.or(other.pid_kd),
+            pid_min_duty: self.pid_min_duty.or(other.pid_min_duty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use structopt::StructOpt;
+
+    /// Options of `auto' on the command line
+    #[derive(StructOpt)]
+    struct Args {
+        #[structopt(flatten)]
+        options: AutoOptions,
+        #[structopt(flatten)]
+        policies: Policies,
+    }
+
+    fn load(config: &str) -> File {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(config.as_bytes()).unwrap();
+        File::load(file.path()).unwrap()
+    }
+
+    fn resolve(
+        args: &[&str],
+        file: Option<&File>,
+        profile: Option<&str>,
+    ) -> Result<Settings, Error> {
+        let args = Args::from_iter_safe(Some("auto").iter().chain(args)).unwrap();
+        Settings::resolve(&args.options, &args.policies, file, profile)
+    }
+
+    /// Message and line of an error about the configuration file
+    fn invalid(result: Result<Settings, Error>) -> (String, usize) {
+        match result {
+            Err(Error::Invalid(_, message, line)) => (message, line),
+            result => panic!("Expected an invalid file, got {:?}", result),
+        }
+    }
+
+    fn sensor(source: &str) -> Reading {
+        Reading::Sensor(sensor::Source::from_str(source).unwrap())
+    }
+
+    #[test]
+    fn parse_input() {
+        let parse = |s| Input::from_str(s).unwrap();
+        assert_eq!(parse("cpu"), Input::Single(Reading::Cpu));
+        assert_eq!(parse("gpu"), Input::Single(Reading::Gpu));
+        assert_eq!(
+            parse("hwmon:coretemp:Package id 0"),
+            Input::Single(sensor("hwmon:coretemp:Package id 0"))
+        );
+        assert_eq!(parse("max"), Input::Max(vec![Reading::Cpu, Reading::Gpu]));
+        assert_eq!(
+            parse("max(cpu, thermal:x86_pkg_temp)"),
+            Input::Max(vec![Reading::Cpu, sensor("thermal:x86_pkg_temp")])
+        );
+        assert_eq!(
+            parse("avg(cpu=3, gpu)"),
+            Input::Average(vec![(Reading::Cpu, 3.0), (Reading::Gpu, 1.0)])
+        );
+        // Sensors may contain `=' themselves, only the last one separates the weight
+        assert_eq!(
+            parse("avg(hwmon:chip:a=b=0.5)"),
+            Input::Average(vec![(sensor("hwmon:chip:a=b"), 0.5)])
+        );
+    }
+
+    #[test]
+    fn input_round_trip() {
+        for input in &[
+            "cpu",
+            "hwmon:k10temp:Tctl",
+            "max(cpu,gpu)",
+            "avg(cpu=2,gpu=1)",
+        ] {
+            assert_eq!(Input::from_str(input).unwrap().to_string(), *input);
+        }
+    }
+
+    #[test]
+    fn parse_invalid_input() {
+        for input in &[
+            "",
+            "fan",
+            "min(cpu,gpu)",
+            "max()",
+            "max(cpu,)",
+            "max(cpu",
+            "avg(cpu=0)",
+            "avg(cpu=-1)",
+            "avg(cpu=inf)",
+            "avg(cpu=heavy)",
+            "hwmon:",
+        ] {
+            assert!(Input::from_str(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn parse_filter() {
+        let parse = |s| Filter::from_str(s).unwrap();
+        assert_eq!(parse("average:5"), Filter::MovingAverage(5));
+        assert_eq!(parse("median: 3"), Filter::MovingMedian(3));
+        assert_eq!(
+            parse("ema:10s"),
+            Filter::ExponentialMovingAverage(Duration::from_secs(10))
+        );
+        assert_eq!(parse("spike:8"), Filter::SpikeRejection(8.0));
+        assert_eq!(parse("rate:0.5"), Filter::RateLimit(0.5));
+    }
+
+    #[test]
+    fn parse_invalid_filter() {
+        for filter in &[
+            "average",
+            "average:0",
+            "median:-1",
+            "median:2.5",
+            "ema:soon",
+            "spike:0",
+            "spike:-3",
+            "rate:nan",
+            "rate:inf",
+            "mean:5",
+        ] {
+            assert!(Filter::from_str(filter).is_err(), "{}", filter);
+        }
+    }
+
+    fn temps(readings: &[(Reading, Option<f64>)]) -> BTreeMap<Reading, Option<utils::Temperature>> {
+        readings
+            .iter()
+            .map(|(reading, temp)| {
+                (
+                    reading.clone(),
+                    temp.map(utils::Temperature::from_degrees_celsius),
+                )
+            })
+            .collect()
+    }
+
+    fn combine(input: &str, temps: &BTreeMap<Reading, Option<utils::Temperature>>) -> f64 {
+        Input::from_str(input)
+            .unwrap()
+            .combine(temps)
+            .as_degrees_celsius()
+    }
+
+    #[test]
+    fn combine_inputs() {
+        let temps = temps(&[
+            (Reading::Cpu, Some(70.0)),
+            (Reading::Gpu, Some(50.0)),
+            (sensor("thermal:0"), Some(40.0)),
+        ]);
+        assert_eq!(combine("gpu", &temps), 50.0);
+        assert_eq!(combine("max(gpu,cpu,thermal:0)", &temps), 70.0);
+        assert_eq!(combine("avg(cpu=3,gpu)", &temps), 65.0);
+    }
+
+    #[test]
+    fn combine_skips_missing_readings() {
+        let temps = temps(&[
+            (Reading::Cpu, Some(70.0)),
+            (Reading::Gpu, None),
+            (sensor("thermal:0"), Some(40.0)),
+        ]);
+        assert_eq!(combine("max(gpu,thermal:0)", &temps), 40.0);
+        assert_eq!(combine("avg(cpu=1,gpu=8,thermal:0=2)", &temps), 50.0);
+        // Readings not read at all are missing, too
+        assert_eq!(combine("max(cpu,thermal:1)", &temps), 70.0);
+        assert!(Input::from_str("max(gpu,thermal:0)")
+            .unwrap()
+            .is_readable(&temps));
+    }
+
+    #[test]
+    fn combine_without_readings() {
+        let temps = temps(&[(Reading::Cpu, None), (Reading::Gpu, None)]);
+        let max = utils::Temperature::max().as_degrees_celsius();
+        for input in &["cpu", "max(cpu,gpu)", "avg(cpu=2,gpu)", "thermal:0"] {
+            assert_eq!(combine(input, &temps), max, "{}", input);
+            assert!(!Input::from_str(input).unwrap().is_readable(&temps));
+        }
+    }
+
+    const CONFIG: &str = r#"
+[auto]
+polling-interval = 1000
+hysteresis = 3
+
+[policy]
+curve = "quiet"
+pid-kp = 2
+
+[curves]
+quiet = "40:0,80:100"
+
+[profiles.silent.auto]
+polling-interval = 2000
+
+[profiles.silent.policy]
+curve = "60:0,90:100"
+"#;
+
+    #[test]
+    fn load_file() {
+        let file = load(CONFIG);
+        assert_eq!(file.profiles().collect::<Vec<_>>(), vec!["silent"]);
+        let settings = resolve(&[], Some(&file), None).unwrap();
+        assert_eq!(settings.polling_interval, Duration::from_secs(1));
+        assert_eq!(settings.hysteresis.unwrap().fall, 3.0);
+        assert_eq!(settings.fan_ids(), vec![fan::Id::CPU]);
+        assert_eq!(
+            settings.fans[0].policy.to_string(),
+            "curve 40:0.00,80:100.00"
+        );
+    }
+
+    #[test]
+    fn command_line_precedence() {
+        let file = load(CONFIG);
+        let settings = resolve(
+            &["--polling-interval", "200", "--pid", "60"],
+            Some(&file),
+            None,
+        )
+        .unwrap();
+        assert_eq!(settings.polling_interval, Duration::from_millis(200));
+        // Options not given on the command line still come from the file
+        assert_eq!(settings.hysteresis.unwrap().fall, 3.0);
+        // The policy on the command line replaces that of the file, but not its parameters
+        assert_eq!(
+            settings.fans[0].policy.to_string(),
+            "PID (setpoint 60, kp 2, ki 0.2, kd 0, min duty 0.00%)"
+        );
+    }
+
+    #[test]
+    fn profile() {
+        let file = load(CONFIG);
+        let settings =
+            resolve(&["--polling-interval", "200"], Some(&file), Some("silent")).unwrap();
+        assert_eq!(settings.profile.as_deref(), Some("silent"));
+        // The profile takes precedence over the command line, the rest is merged
+        assert_eq!(settings.polling_interval, Duration::from_secs(2));
+        assert_eq!(settings.hysteresis.unwrap().fall, 3.0);
+        assert_eq!(
+            settings.fans[0].policy.to_string(),
+            "curve 60:0.00,90:100.00"
+        );
+
+        assert!(matches!(
+            resolve(&[], Some(&file), Some("loud")),
+            Err(Error::Options(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_values() {
+        let file = load(
+            "[auto]\nhysteresis = 2\nhysteresis-rise = -1\n\n[policy]\ncurve = \"50:0,80:100\"\n",
+        );
+        assert_eq!(
+            invalid(resolve(&[], Some(&file), None)),
+            (
+                "`hysteresis-rise' must not be negative, got -1".to_owned(),
+                3
+            )
+        );
+
+        let file = load("[policy]\npid = 60\npid-ki = -0.5\n");
+        assert_eq!(
+            invalid(resolve(&[], Some(&file), None)),
+            ("`pid-ki' must not be negative, got -0.5".to_owned(), 3)
+        );
+
+        let file = load("[auto]\nstall-time = 0\n\n[policy]\npid = 60\n");
+        assert_eq!(invalid(resolve(&[], Some(&file), None)).1, 2);
+
+        assert!(matches!(
+            resolve(
+                &["--curve", "50:0,80:100", "--min-fan-change=-5"],
+                None,
+                None
+            ),
+            Err(Error::Options(_))
+        ));
+    }
+
+    #[test]
+    fn policy_conflict() {
+        let file = load("[policy]\ncurve = \"50:0,80:100\"\nlinear = true\npid = 60\n");
+        assert_eq!(
+            invalid(resolve(&[], Some(&file), None)),
+            (
+                "Only one policy can be selected, but `pid' is selected besides `linear'"
+                    .to_owned(),
+                4
+            )
+        );
+        // A policy on the command line replaces the conflicting ones of the file
+        assert!(resolve(&["--square"], Some(&file), None).is_ok());
+    }
+
+    #[test]
+    fn moving_average_and_median() {
+        let file = load("[auto]\nmoving-average = 3\nmoving-median = 5\n\n[policy]\npid = 60\n");
+        assert_eq!(invalid(resolve(&[], Some(&file), None)).1, 3);
+        // Filters on the command line replace those of the file as a whole
+        let settings = resolve(&["--moving-average", "4"], Some(&file), None).unwrap();
+        assert_eq!(settings.filters, vec![Filter::MovingAverage(4)]);
+    }
+}
@@ -0,0 +1,362 @@
+use crate::fan;
+use derive_more::Display;
+use std::{
+    cell::RefCell,
+    error, fmt, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_SOCKET_PATH: &str = "/run/clevo-fan.sock";
+
+/// Permissions of the socket, only the user running `clevo-fan auto' may control it
+const SOCKET_MODE: u32 = 0o600;
+
+/// How long a client may take to send its request, before it is disconnected
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// How long writing a response may block the fan control loop
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+/// Most clients connected at once, further ones are turned away
+const MAX_CONNECTIONS: usize = 8;
+/// Most requests answered by a single `Server::serve`, so that the fan control loop keeps going
+const MAX_REQUESTS_PER_POLL: usize = 4;
+/// Longest request, longer ones are answered with an error
+const MAX_REQUEST_LENGTH: usize = 256;
+
+/// Request to a running `clevo-fan auto'
+///
+/// On the socket, each request is a single line, answered with either `OK' followed by the
+/// response or `ERR <message>'.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Status,
+    /// Keep the fan at a fixed duty instead of following the policy, optionally only for a while.
+    /// Without duty, the override is lifted.
+    Override(Option<fan::Duty>, Option<Duration>),
+    /// Switch to a profile from the configuration file, or back to the plain configuration
+    Profile(Option<String>),
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Request::Status => write!(f, "status"),
+            Request::Override(None, _) => write!(f, "override off"),
+            Request::Override(Some(duty), None) => write!(f, "override {:#}", duty),
+            Request::Override(Some(duty), Some(duration)) => {
+                write!(f, "override {:#} {}", duty, duration.as_millis())
+            }
+            Request::Profile(None) => write!(f, "profile"),
+            Request::Profile(Some(name)) => write!(f, "profile {}", name),
+        }
+    }
+}
+
+#[derive(Debug, Display)]
+#[display(fmt = "Invalid request `{}'", _0)]
+pub struct InvalidRequest(String);
+impl error::Error for InvalidRequest {}
+
+impl FromStr for Request {
+    type Err = InvalidRequest;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidRequest(s.to_owned());
+        let words: Vec<_> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["status"] => Ok(Request::Status),
+            ["override", "off"] => Ok(Request::Override(None, None)),
+            ["override", duty] => fan::Duty::from_percentage_str(duty)
+                .map(|duty| Request::Override(Some(duty), None))
+                .map_err(|_| invalid()),
+            ["override", duty, millis] => {
+                let duty = fan::Duty::from_percentage_str(duty).map_err(|_| invalid())?;
+                let millis = u64::from_str(millis).map_err(|_| invalid())?;
+                Ok(Request::Override(
+                    Some(duty),
+                    Some(Duration::from_millis(millis)),
+                ))
+            }
+            ["profile"] => Ok(Request::Profile(None)),
+            ["profile", name] => Ok(Request::Profile(Some((*name).to_owned()))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "Cannot connect to {}: {}", "_0.display()", _1)]
+    Connect(PathBuf, io::Error),
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+    #[display(fmt = "{}", _0)]
+    Daemon(String),
+}
+impl error::Error for Error {}
+
+/// Send a request to the `clevo-fan auto' listening at `path' and return its response
+pub fn request(path: &Path, request: &Request) -> Result<String, Error> {
+    let mut stream =
+        UnixStream::connect(path).map_err(|err| Error::Connect(path.to_owned(), err))?;
+    writeln!(stream, "{}", request).map_err(Error::Io)?;
+
+    let mut lines = BufReader::new(stream).lines();
+    let status = lines
+        .next()
+        .unwrap_or(Ok(String::new()))
+        .map_err(Error::Io)?;
+    if status == "OK" {
+        lines
+            .map(|line| line.map(|line| line + "\n"))
+            .collect::<Result<_, _>>()
+            .map_err(Error::Io)
+    } else {
+        Err(Error::Daemon(status.trim_start_matches("ERR ").to_owned()))
+    }
+}
+
+/// Listening end of the control socket
+///
+/// The socket and its connections are non-blocking, so pending requests can be polled from the fan
+/// control loop, without a slow client holding it up. It is removed again when dropped.
+pub struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+    /// Connected clients, whose request is not complete yet
+    connections: RefCell<Vec<Connection>>,
+}
+
+struct Connection {
+    stream: UnixStream,
+    /// The request received so far
+    request: Vec<u8>,
+    connected: Instant,
+}
+
+impl Server {
+    /// Listen at `path', replacing a socket left over from an instance that was killed
+    ///
+    /// Anything else at `path' is left alone, as is a socket another instance listens at.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if !metadata.file_type().is_socket() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "exists and is not a socket",
+                ))
+            }
+            Ok(_) => match UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        "in use by another instance",
+                    ))
+                }
+                Err(_) => fs::remove_file(path)?,
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+
+        // The socket is created in a private directory and only linked to `path' once its mode is
+        // set, so that it is never accessible with the permissions given by the umask. Linking
+        // fails, should anything have appeared at `path' in the meantime.
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("socket");
+        let dir = path.with_file_name(format!(".{}.{}", name, process::id()));
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let private = dir.join("socket");
+        let listener = UnixListener::bind(&private).and_then(|listener| {
+            fs::set_permissions(&private, fs::Permissions::from_mode(SOCKET_MODE))?;
+            fs::hard_link(&private, path)?;
+            Ok(listener)
+        });
+        fs::remove_dir_all(&dir).ok();
+        let listener = listener?;
+        listener.set_nonblocking(true)?;
+        Ok(Server {
+            listener,
+            path: path.to_owned(),
+            connections: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// Answer the pending requests, that are complete, using `handle'
+    ///
+    /// At most `MAX_REQUESTS_PER_POLL` requests are answered, the rest are left for the next call.
+    /// Errors on individual connections are returned after all requests were handled.
+    pub fn serve<F>(&self, mut handle: F) -> io::Result<()>
+    where
+        F: FnMut(Result<Request, InvalidRequest>) -> Result<String, String>,
+    {
+        let mut result = Ok(());
+        let mut connections = self.connections.borrow_mut();
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) if connections.len() < MAX_CONNECTIONS => {
+                    if let Err(err) = stream.set_nonblocking(true) {
+                        result = Err(err);
+                        continue;
+                    }
+                    connections.push(Connection {
+                        stream,
+                        request: Vec::new(),
+                        connected: Instant::now(),
+                    });
+                }
+                Ok((mut stream, _)) => {
+                    writeln!(stream, "ERR Too many clients").ok();
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+
+        let mut answered = 0;
+        let mut index = 0;
+        while index < connections.len() && answered < MAX_REQUESTS_PER_POLL {
+            let connection = &mut connections[index];
+            let response = match connection.receive() {
+                Ok(Received::Request(line)) => handle(Request::from_str(line.trim())),
+                Ok(Received::TooLong) => Err("Request too long".to_owned()),
+                Ok(Received::Pending) if connection.connected.elapsed() < TIMEOUT => {
+                    index += 1;
+                    continue;
+                }
+                Ok(Received::Pending) | Ok(Received::Nothing) => {
+                    connections.swap_remove(index);
+                    continue;
+                }
+                Err(err) => {
+                    result = Err(err);
+                    connections.swap_remove(index);
+                    continue;
+                }
+            };
+            if let Err(err) = connection.respond(response) {
+                result = Err(err);
+            }
+            answered += 1;
+            connections.swap_remove(index);
+        }
+
+        result
+    }
+}
+
+/// What a client sent so far
+enum Received {
+    /// The request is not complete yet
+    Pending,
+    Request(String),
+    /// The request is longer than `MAX_REQUEST_LENGTH`
+    TooLong,
+    /// The client disconnected without sending anything
+    Nothing,
+}
+
+impl Connection {
+    /// Read what the client sent so far
+    ///
+    /// A request is complete at the end of its line or when the client stops sending.
+    fn receive(&mut self) -> io::Result<Received> {
+        let mut buf = [0; MAX_REQUEST_LENGTH];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(length) => {
+                    self.request.extend_from_slice(&buf[..length]);
+                    if self.request.contains(&b'\n') {
+                        break;
+                    }
+                    if self.request.len() > MAX_REQUEST_LENGTH {
+                        return Ok(Received::TooLong);
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Received::Pending)
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        if self.request.is_empty() {
+            return Ok(Received::Nothing);
+        }
+        let line = self
+            .request
+            .split(|byte| *byte == b'\n')
+            .next()
+            .unwrap_or(&[]);
+        Ok(Received::Request(
+            String::from_utf8_lossy(line).into_owned(),
+        ))
+    }
+
+    fn respond(&mut self, response: Result<String, String>) -> io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        self.stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        match response {
+            Ok(response) => write!(self.stream, "OK\n{}", response),
+            Err(message) => writeln!(self.stream, "ERR {}", message),
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bind() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clevo-fan.sock");
+        let server = Server::bind(&path).unwrap();
+        let mode = fs::symlink_metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, SOCKET_MODE);
+        // Only the socket itself is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let err = Server::bind(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn bind_replaces_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clevo-fan.sock");
+        drop(UnixListener::bind(&path).unwrap());
+        Server::bind(&path).unwrap();
+    }
+
+    #[test]
+    fn bind_keeps_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clevo-fan.sock");
+        fs::write(&path, "keep").unwrap();
+        let err = Server::bind(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep");
+    }
+}
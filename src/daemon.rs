@@ -0,0 +1,996 @@
+use crate::{
+    alert, calibration, config, ctl, ec, fan, sensor, shutdown, speed, stall, trace, tui,
+    utils::{self, ResultExt},
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Filter of the temperature curve, together with its history
+enum Filter {
+    MovingAverage(utils::MovingAverage<utils::Temperature>),
+    MovingMedian(utils::MovingMedian<utils::Temperature>),
+    ExponentialMovingAverage(utils::ExponentialMovingAverage),
+    SpikeRejection(utils::SpikeRejection),
+    RateLimit(utils::RateLimit),
+}
+
+impl Filter {
+    fn new(settings: config::Filter) -> Self {
+        match settings {
+            config::Filter::MovingAverage(window) => {
+                Filter::MovingAverage(utils::MovingAverage::new(window))
+            }
+            config::Filter::MovingMedian(window) => {
+                Filter::MovingMedian(utils::MovingMedian::new(window))
+            }
+            config::Filter::ExponentialMovingAverage(time_constant) => {
+                Filter::ExponentialMovingAverage(utils::ExponentialMovingAverage::new(
+                    time_constant,
+                ))
+            }
+            config::Filter::SpikeRejection(max_jump) => {
+                Filter::SpikeRejection(utils::SpikeRejection::new(max_jump))
+            }
+            config::Filter::RateLimit(max_rate) => {
+                Filter::RateLimit(utils::RateLimit::new(max_rate))
+            }
+        }
+    }
+
+    /// Adapt the filter to new settings, keeping the history if the kind of filter is unchanged
+    fn reconfigure(self, settings: config::Filter) -> Self {
+        match (self, settings) {
+            (Filter::MovingAverage(mut average), config::Filter::MovingAverage(window)) => {
+                average.resize(window);
+                Filter::MovingAverage(average)
+            }
+            (Filter::MovingMedian(mut median), config::Filter::MovingMedian(window)) => {
+                median.resize(window);
+                Filter::MovingMedian(median)
+            }
+            (
+                Filter::ExponentialMovingAverage(mut average),
+                config::Filter::ExponentialMovingAverage(time_constant),
+            ) => {
+                average.set_time_constant(time_constant);
+                Filter::ExponentialMovingAverage(average)
+            }
+            (Filter::SpikeRejection(mut spikes), config::Filter::SpikeRejection(max_jump)) => {
+                spikes.set_max_jump(max_jump);
+                Filter::SpikeRejection(spikes)
+            }
+            (Filter::RateLimit(mut limit), config::Filter::RateLimit(max_rate)) => {
+                limit.set_max_rate(max_rate);
+                Filter::RateLimit(limit)
+            }
+            (_, settings) => Filter::new(settings),
+        }
+    }
+
+    fn as_filter(&mut self) -> &mut dyn utils::Filter<utils::Temperature> {
+        match self {
+            Filter::MovingAverage(average) => average,
+            Filter::MovingMedian(median) => median,
+            Filter::ExponentialMovingAverage(average) => average,
+            Filter::SpikeRejection(spikes) => spikes,
+            Filter::RateLimit(limit) => limit,
+        }
+    }
+}
+
+/// Filters of one temperature, each one filtering the output of the previous one
+struct FilterChain(Vec<Filter>);
+
+impl FilterChain {
+    fn new(settings: &[config::Filter]) -> Self {
+        FilterChain(settings.iter().copied().map(Filter::new).collect())
+    }
+
+    /// Adapt the chain to new settings, keeping the history of each filter, if the filter at its
+    /// place is of the same kind
+    fn reconfigure(self, settings: &[config::Filter]) -> Self {
+        let mut filters = self.0.into_iter();
+        FilterChain(
+            settings
+                .iter()
+                .map(|settings| match filters.next() {
+                    Some(filter) => filter.reconfigure(*settings),
+                    None => Filter::new(*settings),
+                })
+                .collect(),
+        )
+    }
+
+    fn push(&mut self, temp: utils::Temperature, elapsed: Duration) -> utils::Temperature {
+        self.0
+            .iter_mut()
+            .fold(temp, |temp, filter| filter.as_filter().push(temp, elapsed))
+    }
+}
+
+/// How long to wait between polling for requests on the control socket
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Values of one cycle, as reported by `clevo-fan ctl status' and shown by `--tui'
+///
+/// Values that could not be read from the EC are missing.
+#[derive(Clone)]
+pub struct Status {
+    pub cpu_temp: Option<utils::Temperature>,
+    pub gpu_temp: Option<utils::Temperature>,
+    pub smoothed_cpu_temp: Option<utils::Temperature>,
+    pub smoothed_gpu_temp: Option<utils::Temperature>,
+    pub fans: Vec<FanStatus>,
+}
+
+#[derive(Clone)]
+pub struct FanStatus {
+    pub id: fan::Id,
+    pub target_fan_duty: fan::Duty,
+    pub fan_duty: fan::Duty,
+    /// Speed the fan is adjusted to, with `--rpm'
+    pub target_rpm: Option<u32>,
+    pub fan_speed: Option<fan::Speed>,
+    pub stalled: bool,
+}
+
+/// Control loop state of a single fan
+struct FanLoop {
+    settings: config::FanSettings,
+    policy: Box<dyn fan::Policy<Input = utils::Temperature>>,
+    /// Policies of the further sensors of the fan, in the order of its settings
+    sensor_policies: Vec<Box<dyn fan::Policy<Input = utils::Temperature>>>,
+    current_fan_duty: fan::Duty,
+    last_target_fan_duty: fan::Duty,
+    unchanged_cycles: usize,
+}
+
+impl FanLoop {
+    fn new(settings: &config::Settings, fan: &config::FanSettings) -> Self {
+        FanLoop {
+            settings: fan.clone(),
+            policy: settings.build_policy(&fan.policy),
+            sensor_policies: FanLoop::build_sensor_policies(settings, fan),
+            current_fan_duty: fan::Duty::min(),
+            last_target_fan_duty: fan::Duty::min(),
+            unchanged_cycles: 0,
+        }
+    }
+
+    fn build_sensor_policies(
+        settings: &config::Settings,
+        fan: &config::FanSettings,
+    ) -> Vec<Box<dyn fan::Policy<Input = utils::Temperature>>> {
+        fan.sensors
+            .iter()
+            .map(|sensor| settings.build_policy(&sensor.policy))
+            .collect()
+    }
+
+    /// Suppress fan duty changes smaller than `min_fan_change', unless requested for too long
+    fn limit_fan_change(&mut self, duty: fan::Duty, settings: &config::Settings) -> fan::Duty {
+        let change_requested =
+            (duty.as_percentage() - self.current_fan_duty.as_percentage()).abs() > 1.0;
+        let changed =
+            (duty.as_percentage() - self.last_target_fan_duty.as_percentage()).abs() <= 1.0;
+        if change_requested && changed {
+            self.unchanged_cycles += 1;
+        } else {
+            self.last_target_fan_duty = duty;
+            self.unchanged_cycles = 0;
+        }
+
+        if (duty.as_percentage() - self.current_fan_duty.as_percentage()).abs()
+            > settings.min_fan_change
+            || self.unchanged_cycles > settings.max_unchanged_cycles
+        {
+            self.current_fan_duty = duty;
+        }
+        self.current_fan_duty
+    }
+}
+
+/// Fan duties of one cycle of the `Pipeline`
+pub struct Step {
+    pub smoothed_cpu_temp: Option<utils::Temperature>,
+    pub smoothed_gpu_temp: Option<utils::Temperature>,
+    pub fans: Vec<FanStep>,
+}
+
+pub struct FanStep {
+    pub id: fan::Id,
+    /// Fan duty requested by the policy
+    pub policy_fan_duty: fan::Duty,
+    /// Fan duty after suppressing small changes, see `--min-fan-change'
+    pub target_fan_duty: fan::Duty,
+}
+
+/// Filters and policies of all fans, turning temperatures into fan duties
+///
+/// This is all of the fan control loop that does not touch the EC, so that recorded temperatures
+/// can be fed through it the same way, see `clevo-fan replay'.
+pub struct Pipeline {
+    fans: Vec<FanLoop>,
+    /// Filters of each temperature read, once it has been read
+    filters: BTreeMap<config::Reading, FilterChain>,
+}
+
+impl Pipeline {
+    pub fn new(settings: &config::Settings) -> Self {
+        Pipeline {
+            fans: settings
+                .fans
+                .iter()
+                .map(|fan| FanLoop::new(settings, fan))
+                .collect(),
+            filters: BTreeMap::new(),
+        }
+    }
+
+    /// Switch from the `current' to `new' settings and return the fans no longer controlled
+    ///
+    /// The state of each fan is kept, unless its settings changed. The history of each filter is
+    /// kept, unless the kind of filter at its place in the chain changed.
+    fn reconfigure(&mut self, current: &config::Settings, new: &config::Settings) -> Vec<fan::Id> {
+        let removed = self
+            .fans
+            .iter()
+            .map(|fan| fan.settings.id)
+            .filter(|id| new.fans.iter().all(|fan| fan.id != *id))
+            .collect();
+
+        let mut fans = Vec::new();
+        for fan in &new.fans {
+            let existing = self
+                .fans
+                .iter()
+                .position(|existing| existing.settings.id == fan.id)
+                .map(|position| self.fans.remove(position));
+            fans.push(match existing {
+                Some(mut existing) => {
+                    if existing.settings != *fan || new.hysteresis != current.hysteresis {
+                        existing.policy = new.build_policy(&fan.policy);
+                        existing.sensor_policies = FanLoop::build_sensor_policies(new, fan);
+                        existing.settings = fan.clone();
+                    }
+                    existing
+                }
+                None => FanLoop::new(new, fan),
+            });
+        }
+        self.fans = fans;
+
+        let readings = new.readings();
+        self.filters = std::mem::take(&mut self.filters)
+            .into_iter()
+            .filter(|(reading, _)| {
+                matches!(reading, config::Reading::Cpu | config::Reading::Gpu)
+                    || readings.contains(reading)
+            })
+            .map(|(reading, filters)| (reading, filters.reconfigure(&new.filters)))
+            .collect();
+
+        removed
+    }
+
+    /// Determine the fan duties for the given temperatures, `elapsed' after the previous step
+    ///
+    /// `sensors' holds the temperatures of the sensors of the kernel, that fans are based on.
+    /// Temperatures that could not be read are skipped, see `config::Input::combine`. An input of
+    /// a fan, none of whose temperatures could be read, is skipped as well, as long as another
+    /// input of the fan can be read. Only otherwise the fan is driven as if the temperature were
+    /// as high as possible.
+    pub fn step(
+        &mut self,
+        settings: &config::Settings,
+        cpu_temp: Option<utils::Temperature>,
+        gpu_temp: Option<utils::Temperature>,
+        sensors: &sensor::Readings,
+        elapsed: Duration,
+    ) -> Step {
+        let mut readings = vec![
+            (config::Reading::Cpu, cpu_temp),
+            (config::Reading::Gpu, gpu_temp),
+        ];
+        readings.extend(settings.sensors().into_iter().map(|source| {
+            let temp = sensors.get(&source).copied().flatten();
+            (config::Reading::Sensor(source), temp)
+        }));
+
+        // Temperatures that could not be read are left out of the filters, so that they do not
+        // linger in their history
+        let mut temps = BTreeMap::new();
+        for (reading, temp) in readings {
+            let temp = match temp {
+                Some(temp) if !settings.filters.is_empty() => Some(
+                    self.filters
+                        .entry(reading.clone())
+                        .or_insert_with(|| FilterChain::new(&settings.filters))
+                        .push(temp, elapsed),
+                ),
+                temp => temp,
+            };
+            temps.insert(reading, temp);
+        }
+        let smoothed = |reading: &config::Reading| {
+            if settings.filters.is_empty() {
+                None
+            } else {
+                temps[reading]
+            }
+        };
+
+        let fans = self
+            .fans
+            .iter_mut()
+            .map(|fan| {
+                // Inputs that could not be read are skipped, instead of running the fan at full
+                // duty, as long as another input of the fan can still drive it. Only if none can,
+                // the fan is driven by its own input, assuming the highest temperature.
+                let sensor_readable = fan
+                    .settings
+                    .sensors
+                    .iter()
+                    .any(|sensor| sensor.input.is_readable(&temps));
+                let mut policy_fan_duty =
+                    if sensor_readable && !fan.settings.input.is_readable(&temps) {
+                        fan::Duty::min()
+                    } else {
+                        let temp = fan.settings.input.combine(&temps);
+                        fan.policy.next_fan_duty(temp, elapsed)
+                    };
+
+                for (sensor, policy) in fan.settings.sensors.iter().zip(&mut fan.sensor_policies) {
+                    if sensor.input.is_readable(&temps) {
+                        let duty = policy.next_fan_duty(sensor.input.combine(&temps), elapsed);
+                        if duty > policy_fan_duty {
+                            policy_fan_duty = duty;
+                        }
+                    }
+                }
+
+                FanStep {
+                    id: fan.settings.id,
+                    policy_fan_duty,
+                    target_fan_duty: fan.limit_fan_change(policy_fan_duty, settings),
+                }
+            })
+            .collect();
+
+        Step {
+            smoothed_cpu_temp: smoothed(&config::Reading::Cpu),
+            smoothed_gpu_temp: smoothed(&config::Reading::Gpu),
+            fans,
+        }
+    }
+}
+
+/// Print the column labels of `print_monitor_line'
+pub fn print_monitor_header(settings: &config::Settings) {
+    write!(io::stdout(), "{:46} ", "CPU Temperature").ignore();
+    match settings.filters.as_slice() {
+        [] => (),
+        [config::Filter::MovingAverage(_)] => {
+            write!(io::stdout(), "{:46} ", "Running Average").ignore()
+        }
+        [config::Filter::MovingMedian(_)] => {
+            write!(io::stdout(), "{:46} ", "Running Median").ignore()
+        }
+        _ => write!(io::stdout(), "{:46} ", "Filtered").ignore(),
+    }
+    for fan in &settings.fans {
+        let label = format!("Fan {} Duty", fan.id);
+        write!(io::stdout(), "{:56} ", label).ignore();
+        if settings.min_fan_change > 0.0 {
+            write!(io::stdout(), "{:37} ", label).ignore();
+        }
+    }
+    writeln!(io::stdout()).ignore();
+}
+
+/// Print the temperature and fan duties of a step as bars, as done by `--monitor'
+pub fn print_monitor_line(
+    settings: &config::Settings,
+    cpu_temp: Option<utils::Temperature>,
+    step: &Step,
+    fan_override: Option<fan::Duty>,
+) {
+    let temp = cpu_temp.unwrap_or_else(utils::Temperature::max);
+    visualize(
+        &temp.in_unit(settings.units),
+        temp.as_degrees_celsius() as usize,
+        50,
+        90,
+    );
+    if let Some(temp) = step.smoothed_cpu_temp {
+        visualize(
+            &temp.in_unit(settings.units),
+            temp.as_degrees_celsius() as usize,
+            50,
+            90,
+        );
+    }
+    for fan in &step.fans {
+        let duty = fan.policy_fan_duty;
+        visualize(&duty, duty.as_percentage() as usize, 30, 80);
+        if settings.min_fan_change > 0.0 {
+            let duty = fan.target_fan_duty;
+            visualize(&duty, duty.as_percentage() as usize, 30, 30);
+        }
+        if let Some(duty) = fan_override {
+            write!(io::stdout(), "override {} ", duty).ignore();
+        }
+    }
+    writeln!(io::stdout()).ignore();
+}
+
+fn visualize(value: &dyn fmt::Display, raw: usize, min: usize, max: usize) {
+    write!(io::stdout(), "{:6} ", value).ignore();
+    let mut bar = String::new();
+    for _ in min..raw {
+        bar.push('#');
+    }
+    write!(io::stdout(), "{:width$}", bar, width = max - min).ignore();
+}
+
+/// The fan control loop of `clevo-fan auto'
+///
+/// Once running, this never fails. Every error is reported to stderr (or the dashboard) and
+/// handled, so that the fans never get unattended.
+pub struct Daemon {
+    // Dropped first, so that errors when handing over the fans are visible on the normal screen
+    dashboard: Option<tui::Dashboard>,
+    ec: Box<dyn ec::Backend>,
+    sensors: sensor::Sensors,
+    fan: shutdown::Guard,
+    settings: config::Settings,
+    pipeline: Pipeline,
+    recorder: Option<trace::Recorder>,
+    /// Duties the fans do not start at, which policies are kept from
+    calibration: Option<calibration::Calibration>,
+    speed: BTreeMap<fan::Id, speed::Adjuster>,
+    stall: BTreeMap<fan::Id, stall::Detector>,
+    /// Duty last set for each fan, taken as 0 before it was set
+    applied: BTreeMap<fan::Id, fan::Duty>,
+
+    last_sample: Option<Instant>,
+    /// Fixed fan duty requested via the control socket, until the given time, if any
+    fan_override: Option<(fan::Duty, Option<Instant>)>,
+    status: Option<Status>,
+}
+
+impl Daemon {
+    pub fn new(
+        ec: Box<dyn ec::Backend>,
+        sensors: sensor::Sensors,
+        fan: fan::Control,
+        settings: config::Settings,
+        dashboard: Option<tui::Dashboard>,
+        recorder: Option<trace::Recorder>,
+        calibration: Option<calibration::Calibration>,
+    ) -> Self {
+        Daemon {
+            dashboard,
+            ec,
+            sensors,
+            fan: shutdown::Guard::new(fan, settings.on_exit, settings.fan_ids()),
+            pipeline: Pipeline::new(&settings),
+            recorder,
+            calibration,
+            speed: BTreeMap::new(),
+            stall: BTreeMap::new(),
+            applied: BTreeMap::new(),
+            settings,
+            last_sample: None,
+            fan_override: None,
+            status: None,
+        }
+    }
+
+    /// Run the fan control loop until termination is requested
+    ///
+    /// When a reload is requested, new settings are obtained from `reload`, given the active
+    /// profile. The policy is only replaced if its settings changed, the history of the filters
+    /// is kept if possible.
+    ///
+    /// Between cycles, requests on the control socket are answered. Requests that change what the
+    /// fan should do start the next cycle right away.
+    pub fn run<F>(&mut self, signals: &shutdown::Signals, server: &ctl::Server, mut reload: F)
+    where
+        F: FnMut(Option<&str>) -> Result<config::Settings, config::Error>,
+    {
+        self.print_header();
+
+        let mut quit = false;
+        while !quit && !signals.terminate_requested() {
+            if signals.reload_requested() {
+                match reload(self.settings.profile.as_deref()) {
+                    Ok(settings) => self.reconfigure(settings),
+                    Err(err) => self.report(format!(
+                        "Error: Cannot reload configuration: {}, keeping the current one",
+                        err
+                    )),
+                }
+            }
+
+            self.cycle();
+
+            let next_cycle = Instant::now() + self.settings.polling_interval;
+            let mut changed = false;
+            while !changed && !quit && !signals.terminate_requested() {
+                let result = server.serve(|request| {
+                    let request = request.map_err(|err| err.to_string())?;
+                    changed |= !matches!(request, ctl::Request::Status);
+                    self.handle(request, &mut reload)
+                });
+                if let Err(err) = result {
+                    self.report(format!("Error: Cannot answer control request: {}", err));
+                }
+
+                if let Some(dashboard) = &mut self.dashboard {
+                    quit = dashboard.quit_requested().unwrap_or_else(|err| {
+                        dashboard.log(format!("Error: Cannot read from terminal: {}", err));
+                        false
+                    });
+                }
+
+                let now = Instant::now();
+                if now >= next_cycle {
+                    break;
+                }
+                thread::sleep(POLL_INTERVAL.min(next_cycle - now));
+            }
+        }
+    }
+
+    fn handle<F>(&mut self, request: ctl::Request, reload: &mut F) -> Result<String, String>
+    where
+        F: FnMut(Option<&str>) -> Result<config::Settings, config::Error>,
+    {
+        match request {
+            ctl::Request::Status => Ok(self.status()),
+            ctl::Request::Override(Some(duty), duration) => {
+                self.fan_override =
+                    Some((duty, duration.map(|duration| Instant::now() + duration)));
+                Ok(String::new())
+            }
+            ctl::Request::Override(None, _) => {
+                self.fan_override = None;
+                Ok(String::new())
+            }
+            ctl::Request::Profile(profile) => {
+                let settings = reload(profile.as_deref()).map_err(|err| err.to_string())?;
+                self.reconfigure(settings);
+                Ok(String::new())
+            }
+        }
+    }
+
+    fn status(&self) -> String {
+        let mut status = String::new();
+        let mut line = |label: &str, value: &dyn fmt::Display| {
+            status.push_str(&format!("{}: {}\n", label, value))
+        };
+        let unknown = |value: Option<utils::Temperature>| -> Box<dyn fmt::Display> {
+            match value {
+                Some(value) => Box::new(value),
+                None => Box::new("unknown"),
+            }
+        };
+
+        line("Model", self.ec.model());
+        line(
+            "Profile",
+            &self.settings.profile.as_deref().unwrap_or("none"),
+        );
+        if !self.settings.filters.is_empty() {
+            let filters: Vec<_> = self
+                .settings
+                .filters
+                .iter()
+                .map(|f| f.to_string())
+                .collect();
+            line("Filters", &filters.join(" -> "));
+        }
+        for fan in &self.settings.fans {
+            line(
+                &format!("Fan {} Policy", fan.id),
+                &format!("{} of {} temperature", fan.policy, fan.input),
+            );
+            for sensor in &fan.sensors {
+                line(
+                    &format!("Fan {} Sensor Policy", fan.id),
+                    &format!("{} of {} temperature", sensor.policy, sensor.input),
+                );
+            }
+        }
+        if let Some(last) = &self.status {
+            line("CPU Temp", &unknown(last.cpu_temp));
+            line("GPU Temp", &unknown(last.gpu_temp));
+            if !self.settings.filters.is_empty() {
+                line("Smoothed CPU Temp", &unknown(last.smoothed_cpu_temp));
+                line("Smoothed GPU Temp", &unknown(last.smoothed_gpu_temp));
+            }
+            for fan in &last.fans {
+                line(&format!("Fan {} Target Duty", fan.id), &fan.target_fan_duty);
+                line(&format!("Fan {} Duty", fan.id), &fan.fan_duty);
+                if let Some(rpm) = fan.target_rpm {
+                    line(
+                        &format!("Fan {} Target Speed", fan.id),
+                        &format!("{} RPM", rpm),
+                    );
+                }
+                match &fan.fan_speed {
+                    Some(speed) => line(&format!("Fan {} Speed", fan.id), speed),
+                    None => line(&format!("Fan {} Speed", fan.id), &"unknown"),
+                }
+                if fan.stalled {
+                    line(&format!("Fan {} Stalled", fan.id), &"yes");
+                }
+            }
+        }
+        match self.fan_override {
+            Some((duty, None)) => line("Override", &duty),
+            Some((duty, Some(until))) => line(
+                "Override",
+                &format!(
+                    "{} for {}s",
+                    duty,
+                    until.saturating_duration_since(Instant::now()).as_secs()
+                ),
+            ),
+            None => line("Override", &"none"),
+        }
+
+        status
+    }
+
+    /// Report an error to stderr, or to the log of the dashboard, if shown
+    fn report(&mut self, message: String) {
+        match &mut self.dashboard {
+            Some(dashboard) => dashboard.log(message),
+            None => writeln!(io::stderr(), "{}", message).ignore(),
+        }
+    }
+
+    fn reconfigure(&mut self, settings: config::Settings) {
+        // Fans no longer controlled are handed over right away, instead of staying at their last
+        // duty until exiting
+        let removed = self.pipeline.reconfigure(&self.settings, &settings);
+        if !removed.is_empty() {
+            if let Err(err) = self.fan.hand_over(settings.on_exit, &removed) {
+                self.report(format!("Error: Cannot hand over fan control: {}", err));
+            }
+        }
+
+        self.stall
+            .retain(|id, _| settings.fans.iter().any(|fan| fan.id == *id));
+        if !settings.rpm {
+            self.speed.clear();
+        }
+        self.fan.set_handover(settings.on_exit, settings.fan_ids());
+        self.settings = settings;
+
+        for id in self.settings.fan_ids() {
+            if self.settings.rpm && self.speed_range(id).is_none() {
+                self.report(format!(
+                    "Error: {}, controlling its duty instead",
+                    speed::Error::UnknownRange(id)
+                ));
+            }
+        }
+
+        self.print_header();
+    }
+
+    fn cycle(&mut self) {
+        let registers = self.ec.registers().map_err(|err| {
+            self.report(format!(
+                "Error: Cannot read temperature: {}, assuming the worst unless other temperatures can be read",
+                err
+            ))
+        });
+        let cpu_temp = registers.as_ref().ok().map(|ec| ec.cpu_temp);
+        let gpu_temp = registers.as_ref().ok().map(|ec| ec.gpu_temp);
+        let mut sensors = sensor::Readings::new();
+        for source in self.settings.sensors() {
+            let temp = self.sensors.read(&source).map_err(|err| {
+                self.report(format!(
+                    "Error: Cannot read temperature: {}, assuming the worst unless other temperatures can be read",
+                    err
+                ))
+            });
+            sensors.insert(source, temp.ok());
+        }
+
+        let now = Instant::now();
+        let elapsed = self
+            .last_sample
+            .map_or(Duration::from_secs(0), |last| now - last);
+        self.last_sample = Some(now);
+
+        let step = self
+            .pipeline
+            .step(&self.settings, cpu_temp, gpu_temp, &sensors, elapsed);
+
+        if let Some((_, Some(until))) = self.fan_override {
+            if now >= until {
+                self.fan_override = None;
+            }
+        }
+        let fan_override = self.fan_override.map(|(duty, _)| duty);
+        if self.monitoring() {
+            print_monitor_line(&self.settings, cpu_temp, &step, fan_override);
+        }
+
+        let mut fans = Vec::new();
+        for fan in &step.fans {
+            let fan_speed = registers
+                .as_ref()
+                .ok()
+                .and_then(|ec| ec.fan(fan.id))
+                .map(|fan| fan.speed);
+            let stalled = self.check_stall(fan.id, fan_speed, now);
+
+            let range = self.speed_range(fan.id);
+            let target_rpm = range
+                .as_ref()
+                .map(|range| range.target(fan.target_fan_duty));
+            let duty = match (stalled, self.settings.stall.action) {
+                (true, config::StallAction::Duty(duty)) => duty,
+                _ => match (fan_override, &range, target_rpm) {
+                    (Some(duty), _, _) => duty,
+                    (None, Some(range), Some(target_rpm)) => self
+                        .speed
+                        .entry(fan.id)
+                        .or_default()
+                        .next_duty(range, target_rpm, fan_speed),
+                    (None, _, _) => match &self.calibration {
+                        Some(calibration) => calibration.clamp(
+                            fan.id,
+                            fan.target_fan_duty,
+                            self.applied
+                                .get(&fan.id)
+                                .copied()
+                                .unwrap_or_else(fan::Duty::min),
+                        ),
+                        None => fan.target_fan_duty,
+                    },
+                },
+            };
+            match self.fan.set_duty(fan.id, duty) {
+                Ok(()) => {
+                    self.applied.insert(fan.id, duty);
+                    self.stall.entry(fan.id).or_default().applied(duty)
+                }
+                Err(err) => {
+                    self.report(format!("Error: Cannot set duty of fan {}: {}", fan.id, err))
+                }
+            }
+
+            fans.push(FanStatus {
+                id: fan.id,
+                target_fan_duty: fan.target_fan_duty,
+                fan_duty: duty,
+                target_rpm,
+                fan_speed,
+                stalled,
+            });
+        }
+
+        let status = Status {
+            cpu_temp,
+            gpu_temp,
+            smoothed_cpu_temp: step.smoothed_cpu_temp,
+            smoothed_gpu_temp: step.smoothed_gpu_temp,
+            fans,
+        };
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record(registers.as_ref().ok(), &sensors, &status) {
+                self.report(format!("Error: Cannot record trace: {}", err));
+            }
+        }
+        self.status = Some(status);
+
+        self.draw();
+    }
+
+    /// Speed range of a fan, if it is controlled by speed, see `--rpm'
+    fn speed_range(&self, id: fan::Id) -> Option<speed::Range> {
+        if !self.settings.rpm {
+            return None;
+        }
+        speed::Range::of(self.calibration.as_ref(), self.settings.stall.max_rpm, id)
+    }
+
+    /// Check whether the fan spins as fast as it should, alerting if it stalls or recovers
+    fn check_stall(&mut self, id: fan::Id, speed: Option<fan::Speed>, now: Instant) -> bool {
+        let range = speed::Range::of(self.calibration.as_ref(), self.settings.stall.max_rpm, id);
+        let min_duty = self.settings.stall.min_duty(self.calibration.as_ref(), id);
+        let detector = self.stall.entry(id).or_default();
+        let event = match speed {
+            Some(speed) => {
+                detector.check(&self.settings.stall, min_duty, range.as_ref(), speed, now)
+            }
+            None => None,
+        };
+        let stalled = detector.stalled();
+
+        let (kind, message) = match event {
+            Some(stall::Event::Stalled(problem)) => (
+                "stall",
+                match self.settings.stall.action {
+                    config::StallAction::Duty(duty) => {
+                        format!("Fan {} stalled: {}, forcing {}", id, problem, duty)
+                    }
+                    config::StallAction::None => format!("Fan {} stalled: {}", id, problem),
+                },
+            ),
+            Some(stall::Event::Recovered) => ("recover", format!("Fan {} recovered", id)),
+            None => return stalled,
+        };
+
+        self.report(format!("Alert: {}", message));
+        if let Err(err) = alert::syslog(&message) {
+            self.report(format!("Error: Cannot send alert to syslog: {}", err));
+        }
+        if let Some(hook) = &self.settings.stall.hook {
+            if let Err(err) = alert::run_hook(hook, kind, id, &message) {
+                self.report(format!("Error: Cannot run alert hook: {}", err));
+            }
+        }
+
+        stalled
+    }
+
+    fn draw(&mut self) {
+        if self.dashboard.is_none() {
+            return;
+        }
+
+        let info = self.status();
+        if let Some(dashboard) = &mut self.dashboard {
+            if let Some(status) = &self.status {
+                dashboard.push(status.clone());
+            }
+            if let Err(err) = dashboard.draw(&info) {
+                dashboard.log(format!("Error: Cannot draw dashboard: {}", err));
+            }
+        }
+    }
+
+    /// Whether to print the values of each cycle, which the dashboard replaces
+    fn monitoring(&self) -> bool {
+        self.settings.monitor && self.dashboard.is_none()
+    }
+
+    fn print_header(&self) {
+        if self.monitoring() {
+            print_monitor_header(&self.settings);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, str::FromStr};
+
+    fn filters(filters: &[&str]) -> Vec<config::Filter> {
+        filters
+            .iter()
+            .map(|filter| config::Filter::from_str(filter).unwrap())
+            .collect()
+    }
+
+    fn push(chain: &mut FilterChain, temps: &[f64]) -> Vec<f64> {
+        temps
+            .iter()
+            .map(|temp| {
+                chain
+                    .push(
+                        utils::Temperature::from_degrees_celsius(*temp),
+                        Duration::from_secs(1),
+                    )
+                    .as_degrees_celsius()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn filter_chain_order() {
+        // The rate limit first only lets 51°C into the average
+        let mut chain = FilterChain::new(&filters(&["rate:1", "average:2"]));
+        assert_eq!(push(&mut chain, &[50.0, 60.0]), vec![50.0, 50.5]);
+        // The average first jumps to 55°C, which the rate limit slows down
+        let mut chain = FilterChain::new(&filters(&["average:2", "rate:1"]));
+        assert_eq!(push(&mut chain, &[50.0, 60.0]), vec![50.0, 51.0]);
+    }
+
+    #[test]
+    fn empty_filter_chain() {
+        let mut chain = FilterChain::new(&[]);
+        assert_eq!(push(&mut chain, &[50.0, 60.0]), vec![50.0, 60.0]);
+    }
+
+    #[test]
+    fn filter_chain_reconfigure() {
+        let mut chain = FilterChain::new(&filters(&["average:2"]));
+        push(&mut chain, &[50.0, 60.0]);
+        // The history of the average is kept, the rate limit starts from scratch
+        let mut chain = chain.reconfigure(&filters(&["average:3", "rate:1"]));
+        assert_eq!(push(&mut chain, &[70.0]), vec![60.0]);
+        let mut chain = chain.reconfigure(&filters(&["median:3"]));
+        assert_eq!(push(&mut chain, &[10.0]), vec![10.0]);
+    }
+
+    fn settings(config: &str) -> config::Settings {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(config.as_bytes()).unwrap();
+        let file = config::File::load(file.path()).unwrap();
+        config::Settings::resolve(
+            &crate::AutoOptions::default(),
+            &crate::Policies::default(),
+            Some(&file),
+            None,
+        )
+        .unwrap()
+    }
+
+    fn policy_fan_duties(
+        settings: &config::Settings,
+        cpu_temp: Option<f64>,
+        sensor_temp: Option<f64>,
+    ) -> Vec<f64> {
+        let sensors = settings
+            .sensors()
+            .into_iter()
+            .map(|source| {
+                (
+                    source,
+                    sensor_temp.map(utils::Temperature::from_degrees_celsius),
+                )
+            })
+            .collect();
+        Pipeline::new(settings)
+            .step(
+                settings,
+                cpu_temp.map(utils::Temperature::from_degrees_celsius),
+                None,
+                &sensors,
+                Duration::from_secs(1),
+            )
+            .fans
+            .iter()
+            .map(|fan| fan.policy_fan_duty.as_percentage())
+            .collect()
+    }
+
+    #[test]
+    fn unreadable_input() {
+        let settings = settings(
+            r#"
+            [fans.cpu]
+            input = "thermal:0"
+            policy = { curve = "40:0,80:100" }
+
+            [[fans.cpu.sensors]]
+            input = "cpu"
+            policy = { curve = "40:0,80:50" }
+            "#,
+        );
+        // The readable sensor drives the fan on its own, instead of the main input at full duty
+        assert_eq!(policy_fan_duties(&settings, Some(60.0), None), vec![25.0]);
+        assert_eq!(
+            policy_fan_duties(&settings, Some(60.0), Some(70.0)),
+            vec![75.0]
+        );
+        // Without any readable input, the fan runs at full duty
+        assert_eq!(policy_fan_duties(&settings, None, None), vec![100.0]);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{ec, fan, model};
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    time::Duration,
+};
+
+const ROW_LENGTH: usize = 16;
+
+/// Names of the registers the model knows about, by offset
+///
+/// Names are like the keys of `clevo-fan show --format json', e.g. `fan1_duty'. Should a model
+/// use a register for multiple values, all of their names are given.
+fn fields(model: &model::Model) -> BTreeMap<usize, Vec<String>> {
+    let mut fields = BTreeMap::new();
+    let mut add = |offset: u8, name: String| {
+        fields
+            .entry(offset as usize)
+            .or_insert_with(Vec::new)
+            .push(name)
+    };
+
+    add(model.cpu_temp, "cpu_temp".to_owned());
+    add(model.gpu_temp, "gpu_temp".to_owned());
+    for (id, fan) in fan::Id::all().zip(&model.fans) {
+        add(fan.duty, format!("fan{}_duty", id));
+        add(fan.rpm_hi, format!("fan{}_speed_hi", id));
+        add(fan.rpm_lo, format!("fan{}_speed_lo", id));
+    }
+
+    fields
+}
+
+/// Print the register block as a hexdump, followed by the known registers of each row
+pub fn print_dump(buf: &[u8; ec::EC_REG_SIZE], model: &model::Model) -> io::Result<()> {
+    let fields = fields(model);
+    let mut stdout = io::stdout();
+
+    write!(stdout, "   ")?;
+    for column in 0..ROW_LENGTH {
+        write!(stdout, " {:2x}", column)?;
+    }
+    writeln!(stdout)?;
+
+    for (row, bytes) in buf.chunks(ROW_LENGTH).enumerate() {
+        let start = row * ROW_LENGTH;
+        write!(stdout, "{:02x}:", start)?;
+        for byte in bytes {
+            write!(stdout, " {:02x}", byte)?;
+        }
+
+        let known: Vec<_> = fields
+            .range(start..start + ROW_LENGTH)
+            .map(|(offset, names)| format!("{:02x} {}={}", offset, names.join("/"), buf[*offset]))
+            .collect();
+        if !known.is_empty() {
+            write!(stdout, "  {}", known.join(", "))?;
+        }
+        writeln!(stdout)?;
+    }
+
+    Ok(())
+}
+
+/// Print the registers that changed between two samples, one per line
+///
+/// Each line gives the offset, the old and the new value in hex and decimal and the name of the
+/// register, if it is known. Nothing is printed when nothing changed.
+pub fn print_diff(
+    elapsed: Duration,
+    previous: &[u8; ec::EC_REG_SIZE],
+    current: &[u8; ec::EC_REG_SIZE],
+    model: &model::Model,
+) -> io::Result<()> {
+    let fields = fields(model);
+    let mut stdout = io::stdout();
+
+    let changed = previous
+        .iter()
+        .zip(current.iter())
+        .enumerate()
+        .filter(|(_, (old, new))| old != new);
+    for (offset, (old, new)) in changed {
+        write!(
+            stdout,
+            "[{:8.1}s] {:02x}: {:02x} -> {:02x} ({:3} -> {:3})",
+            elapsed.as_secs_f64(),
+            offset,
+            old,
+            new,
+            old,
+            new
+        )?;
+        if let Some(names) = fields.get(&offset) {
+            write!(stdout, "  {}", names.join("/"))?;
+        }
+        writeln!(stdout)?;
+    }
+
+    stdout.flush()
+}
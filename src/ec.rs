@@ -1,53 +1,248 @@
-use crate::{fan, utils};
-use derive_more::Display;
-use std::{convert::TryFrom, error::Error, fmt, io, iter, thread, time::Duration};
+use crate::{fan, model, utils};
+use derive_more::{Display, From};
+use std::{
+    error, fmt, fs,
+    io::{self, Read, Seek, Write},
+    iter,
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+pub const EC_REG_SIZE: usize = 0x100;
+
+/// Maximum number of fans of a model, see `fan::Id`
+pub const FAN_COUNT: usize = 2;
 
 #[derive(Debug)]
 pub struct Registers {
     pub cpu_temp: utils::Temperature,
     pub gpu_temp: utils::Temperature,
-    pub fan_duty: fan::Duty,
-    pub fan_speed: fan::Speed,
+    /// Values of each fan of the model, ordered by `fan::Id`
+    pub fans: Vec<FanRegisters>,
+    pub raw: RawRegisters,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FanRegisters {
+    pub duty: fan::Duty,
+    pub speed: fan::Speed,
 }
 
-impl From<&[u8]> for Registers {
-    fn from(buf: &[u8]) -> Self {
-        const EC_REG_CPU_TEMP: usize = 0x07;
-        const EC_REG_GPU_TEMP: usize = 0xCD;
-        const EC_REG_FAN_DUTY: usize = 0xCE;
-        const EC_REG_FAN_RPMS_HI: usize = 0xD0;
-        const EC_REG_FAN_RPMS_LO: usize = 0xD1;
+/// The register bytes, the values in `Registers` are decoded from
+#[derive(Debug, Clone)]
+pub struct RawRegisters {
+    pub cpu_temp: u8,
+    pub gpu_temp: u8,
+    pub fans: Vec<RawFanRegisters>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RawFanRegisters {
+    pub duty: u8,
+    pub rpms_hi: u8,
+    pub rpms_lo: u8,
+}
+
+impl Registers {
+    /// Decode the register block, as laid out by the given model
+    pub fn decode(buf: &[u8], model: &model::Model) -> Self {
+        let raw = RawRegisters {
+            cpu_temp: buf[model.cpu_temp as usize],
+            gpu_temp: buf[model.gpu_temp as usize],
+            fans: model
+                .fans
+                .iter()
+                .map(|fan| RawFanRegisters {
+                    duty: buf[fan.duty as usize],
+                    rpms_hi: buf[fan.rpm_hi as usize],
+                    rpms_lo: buf[fan.rpm_lo as usize],
+                })
+                .collect(),
+        };
 
         Registers {
-            cpu_temp: utils::Temperature::from_degrees_celsius(buf[EC_REG_CPU_TEMP]),
-            gpu_temp: utils::Temperature::from_degrees_celsius(buf[EC_REG_GPU_TEMP]),
-            fan_duty: fan::Duty::from_point_in_range(buf[EC_REG_FAN_DUTY], 0..=255),
-            fan_speed: fan::Speed::from_raw_ec_bytes(
-                buf[EC_REG_FAN_RPMS_LO],
-                buf[EC_REG_FAN_RPMS_HI],
-            ),
+            cpu_temp: utils::Temperature::from_degrees_celsius(raw.cpu_temp as f64),
+            gpu_temp: utils::Temperature::from_degrees_celsius(raw.gpu_temp as f64),
+            fans: raw
+                .fans
+                .iter()
+                .map(|fan| FanRegisters {
+                    duty: fan::Duty::from_point_in_range(fan.duty, 0..=255),
+                    speed: fan::Speed::from_raw_ec_bytes(fan.rpms_lo, fan.rpms_hi, model.rpm_magic),
+                })
+                .collect(),
+            raw,
+        }
+    }
+
+    /// Values of the given fan, if the model has that fan
+    pub fn fan(&self, id: fan::Id) -> Option<&FanRegisters> {
+        self.fans.get(id.index())
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "CPU Temp: {}", self.cpu_temp)?;
+        write!(f, "GPU Temp: {}", self.gpu_temp)?;
+        for (id, fan) in fan::Id::all().zip(&self.fans) {
+            // A single fan is not numbered, as before there was support for multiple fans
+            let label = match self.fans.len() {
+                1 => "Fan".to_owned(),
+                _ => format!("Fan {}", id),
+            };
+            writeln!(f)?;
+            writeln!(f, "{} Duty: {}", label, fan.duty)?;
+            write!(f, "{} Speed: {}", label, fan.speed)?;
         }
+        Ok(())
     }
 }
 
-impl TryFrom<&mut dyn io::Read> for Registers {
-    type Error = io::Error;
-    fn try_from(file: &mut dyn io::Read) -> io::Result<Self> {
-        const EC_REG_SIZE: usize = 0x100;
+#[derive(Debug, Display, From)]
+pub enum Error {
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+    #[display(fmt = "{}", _0)]
+    PortIO(PortIOError),
+    #[display(fmt = "{}", _0)]
+    Syscall(utils::SyscallError),
+    #[display(fmt = "Unsupported EC command {:#04x} (port {:#04x})", _0, _1)]
+    #[from(ignore)]
+    UnsupportedCommand(u8, u8),
+    #[display(fmt = "There is no fan {} on this model, see `--model'", _0)]
+    NoSuchFan(fan::Id),
+}
+impl error::Error for Error {}
+
+/// Means of accessing the EC
+///
+/// The register block is only ever read as a whole, commands are only ever sent as a triple of
+/// command, port and value (e.g. `0x99` to control the fan). Which registers and commands mean
+/// what is given by the model of the EC.
+pub trait Backend {
+    fn read(&mut self) -> Result<[u8; EC_REG_SIZE], Error>;
+    fn write(&mut self, cmd: u8, port: u8, value: u8) -> Result<(), Error>;
+    fn model(&self) -> &model::Model;
 
+    fn registers(&mut self) -> Result<Registers, Error> {
+        self.read().map(|buf| Registers::decode(&buf, self.model()))
+    }
+}
+
+const EC_SC_PORT_NUM: u16 = 0x66;
+const EC_DATA_PORT_NUM: u16 = 0x62;
+const IBF: u32 = 1;
+
+/// The actual EC of the running machine
+///
+/// Reads go through the kernels sysfs representation of the EC registers, writes use the EC I/O
+/// ports directly. The I/O ports are only requested on the first write, so reading works without
+/// the privileges to do port I/O.
+pub struct Hardware {
+    file: fs::File,
+    ports: Option<(ECPort, ECPort)>,
+    model: model::Model,
+}
+
+impl Hardware {
+    pub fn open(path: &Path, model: model::Model) -> io::Result<Self> {
+        Ok(Hardware {
+            file: fs::OpenOptions::new().read(true).open(path)?,
+            ports: None,
+            model,
+        })
+    }
+
+    fn ports(&mut self) -> Result<&mut (ECPort, ECPort), utils::SyscallError> {
+        if self.ports.is_none() {
+            unsafe {
+                self.ports = Some((ECPort::new(EC_SC_PORT_NUM)?, ECPort::new(EC_DATA_PORT_NUM)?));
+            }
+        }
+
+        Ok(self.ports.as_mut().unwrap())
+    }
+}
+
+impl Backend for Hardware {
+    fn read(&mut self) -> Result<[u8; EC_REG_SIZE], Error> {
         let mut buf = [0; EC_REG_SIZE];
-        file.read_exact(&mut buf)?;
+        self.file.seek(io::SeekFrom::Start(0))?;
+        self.file.read_exact(&mut buf)?;
 
-        Ok(Registers::from(&buf as &[u8]))
+        Ok(buf)
+    }
+
+    fn write(&mut self, cmd: u8, port: u8, value: u8) -> Result<(), Error> {
+        let (sc_port, data_port) = self.ports()?;
+
+        sc_port.wait(IBF, 0)?;
+        sc_port.write(cmd)?;
+
+        sc_port.wait(IBF, 0)?;
+        data_port.write(port)?;
+
+        sc_port.wait(IBF, 0)?;
+        data_port.write(value)?;
+
+        sc_port.wait(IBF, 0)?;
+        Ok(())
+    }
+
+    fn model(&self) -> &model::Model {
+        &self.model
     }
 }
 
-impl fmt::Display for Registers {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "CPU Temp: {}", self.cpu_temp)?;
-        writeln!(f, "GPU Temp: {}", self.gpu_temp)?;
-        writeln!(f, "Fan Duty: {}", self.fan_duty)?;
-        write!(f, "Fan Speed: {}", self.fan_speed)
+/// A fake EC, backed by a plain file holding the register block
+///
+/// Commands are not sent anywhere, instead their effect is emulated by updating the file: Setting
+/// the fan duty writes the corresponding register, handing the fan back to the firmware is accepted
+/// but has no effect. Any other command is rejected. The file can be modified externally at any
+/// time to emulate changing temperatures, fan speeds and so on.
+pub struct Simulated {
+    file: fs::File,
+    model: model::Model,
+}
+
+impl Simulated {
+    pub fn open(path: &Path, model: model::Model) -> io::Result<Self> {
+        Ok(Simulated {
+            file: fs::OpenOptions::new().read(true).write(true).open(path)?,
+            model,
+        })
+    }
+}
+
+impl Backend for Simulated {
+    fn read(&mut self) -> Result<[u8; EC_REG_SIZE], Error> {
+        let mut buf = [0; EC_REG_SIZE];
+        self.file.seek(io::SeekFrom::Start(0))?;
+        self.file.read_exact(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    fn write(&mut self, cmd: u8, port: u8, value: u8) -> Result<(), Error> {
+        if cmd != self.model.fan_control_cmd {
+            return Err(Error::UnsupportedCommand(cmd, port));
+        }
+        let register = match self.model.fans.iter().find(|fan| fan.port == port) {
+            Some(fan) => fan.duty,
+            // There is no firmware to take over, so just leave the fan as it is
+            None if port == self.model.fan_auto_port => return Ok(()),
+            None => return Err(Error::UnsupportedCommand(cmd, port)),
+        };
+
+        self.file.seek(io::SeekFrom::Start(register as u64))?;
+        self.file.write_all(&[value])?;
+        Ok(())
+    }
+
+    fn model(&self) -> &model::Model {
+        &self.model
     }
 }
 
@@ -56,7 +251,7 @@ pub struct ECPort(cpuio::Port<u8>);
 #[derive(Debug, Display)]
 #[display(fmt = "Error doing Port I/O")]
 pub struct PortIOError;
-impl Error for PortIOError {}
+impl error::Error for PortIOError {}
 
 impl ECPort {
     pub unsafe fn new(port: u16) -> Result<Self, utils::SyscallError> {
@@ -89,3 +284,127 @@ impl ECPort {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A simulated EC at 70°C CPU and 50°C GPU, with only the CPU fan spinning
+    fn simulated(model: &str) -> (tempfile::NamedTempFile, model::Model) {
+        let model = model::Model::select(Some(model), None, false).unwrap();
+        let mut registers = [0; EC_REG_SIZE];
+        registers[model.cpu_temp as usize] = 70;
+        registers[model.gpu_temp as usize] = 50;
+        registers[model.fans[0].duty as usize] = 0x80;
+        registers[model.fans[0].rpm_hi as usize] = 0x03;
+        registers[model.fans[0].rpm_lo as usize] = 0x52;
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&registers).unwrap();
+        (file, model)
+    }
+
+    fn open(file: &tempfile::NamedTempFile, model: &model::Model) -> Simulated {
+        Simulated::open(file.path(), model.clone()).unwrap()
+    }
+
+    #[test]
+    fn decode_registers() {
+        let (file, model) = simulated("clevo-dual");
+        let registers = open(&file, &model).registers().unwrap();
+
+        assert_eq!(registers.cpu_temp.as_degrees_celsius(), 70.0);
+        assert_eq!(registers.gpu_temp.as_degrees_celsius(), 50.0);
+        assert_eq!(registers.fans.len(), 2);
+        let cpu_fan = registers.fan(fan::Id::CPU).unwrap();
+        assert_eq!(registers.raw.fans[0].duty, 0x80);
+        assert!((cpu_fan.duty.as_percentage() - 50.2).abs() < 0.1);
+        assert_eq!(cpu_fan.speed.as_rpm(), 2536);
+        assert_eq!(registers.fan(fan::Id::GPU).unwrap().speed.as_rpm(), 0);
+    }
+
+    #[test]
+    fn single_fan() {
+        let (file, model) = simulated("clevo");
+        let registers = open(&file, &model).registers().unwrap();
+        assert_eq!(registers.fans.len(), 1);
+        assert!(registers.fan(fan::Id::GPU).is_none());
+
+        let mut control = fan::Control::new(Box::new(open(&file, &model)));
+        assert!(matches!(
+            control.set_duty(fan::Id::GPU, fan::Duty::min()),
+            Err(Error::NoSuchFan(_))
+        ));
+    }
+
+    #[test]
+    fn set_duty() {
+        let (file, model) = simulated("clevo-dual");
+        let mut control = fan::Control::new(Box::new(open(&file, &model)));
+        control
+            .set_duty(fan::Id::GPU, fan::Duty::from_percentage(40.0).unwrap())
+            .unwrap();
+        control
+            .set_duty(fan::Id::CPU, fan::Duty::from_percentage(100.0).unwrap())
+            .unwrap();
+
+        let registers = open(&file, &model).registers().unwrap();
+        assert_eq!(registers.raw.fans[0].duty, 255);
+        assert_eq!(registers.raw.fans[1].duty, 102);
+        // Nothing else is touched
+        assert_eq!(registers.raw.cpu_temp, 70);
+        assert_eq!(registers.fan(fan::Id::CPU).unwrap().speed.as_rpm(), 2536);
+    }
+
+    #[test]
+    fn hand_over() {
+        let (file, model) = simulated("clevo-dual");
+        let mut control = fan::Control::new(Box::new(open(&file, &model)));
+        control.hand_over(fan::Handover::Firmware, &[]).unwrap();
+        assert_eq!(
+            open(&file, &model).registers().unwrap().raw.fans[0].duty,
+            0x80
+        );
+
+        let duty = fan::Duty::from_percentage(100.0).unwrap();
+        control
+            .hand_over(fan::Handover::Duty(duty), &[fan::Id::CPU, fan::Id::GPU])
+            .unwrap();
+        let registers = open(&file, &model).registers().unwrap();
+        assert_eq!(registers.raw.fans[0].duty, 255);
+        assert_eq!(registers.raw.fans[1].duty, 255);
+    }
+
+    #[test]
+    fn unsupported_command() {
+        let (file, model) = simulated("clevo-dual");
+        let mut ec = open(&file, &model);
+        assert!(matches!(
+            ec.write(0x98, model.fans[0].port, 0),
+            Err(Error::UnsupportedCommand(0x98, _))
+        ));
+        assert!(matches!(
+            ec.write(model.fan_control_cmd, 0x42, 0),
+            Err(Error::UnsupportedCommand(_, 0x42))
+        ));
+    }
+
+    #[test]
+    fn external_changes() {
+        let (mut file, model) = simulated("clevo-dual");
+        let mut ec = open(&file, &model);
+        assert_eq!(ec.registers().unwrap().raw.cpu_temp, 70);
+
+        file.seek(io::SeekFrom::Start(model.cpu_temp as u64))
+            .unwrap();
+        file.write_all(&[85]).unwrap();
+        assert_eq!(ec.registers().unwrap().cpu_temp.as_degrees_celsius(), 85.0);
+    }
+
+    #[test]
+    fn truncated_file() {
+        let (file, model) = simulated("clevo-dual");
+        file.as_file().set_len(0x10).unwrap();
+        assert!(matches!(open(&file, &model).registers(), Err(Error::Io(_))));
+    }
+}
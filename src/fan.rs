@@ -1,11 +1,9 @@
 pub mod policy;
 pub use policy::FanPolicy as Policy;
 
-use crate::{
-    ec::{self, ECPort},
-    utils,
-};
+use crate::{config, ec};
 use derive_more::Display;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{error::Error, fmt, num, ops::RangeInclusive, str::FromStr};
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
@@ -74,7 +72,9 @@ impl Duty {
         })
     }
 
+    /// Parse a percentage, with or without trailing `%'
     pub fn from_percentage_str(percentage: &str) -> Result<Self, ParsePercentageError> {
+        let percentage = percentage.strip_suffix('%').unwrap_or(percentage);
         f64::from_str(percentage)
             .map_err(ParsePercentageError::ParseFloat)
             .and_then(Duty::from_percentage)
@@ -85,7 +85,19 @@ impl Duty {
     }
 }
 
-#[derive(Debug)]
+impl<'de> Deserialize<'de> for Duty {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        config::deserialize_parsed(deserializer, Self::from_percentage_str)
+    }
+}
+
+impl Serialize for Duty {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct Speed {
     rpm: u32,
 }
@@ -102,13 +114,16 @@ impl fmt::Display for Speed {
 }
 
 impl Speed {
-    pub fn from_raw_ec_bytes(lo: u8, hi: u8) -> Self {
-        // See https://github.com/SkyLandTW/clevo-indicator/blob/master/src/clevo-indicator.c#L562
-        const MAGIC: u32 = 2156220;
+    pub fn as_rpm(&self) -> u32 {
+        self.rpm
+    }
+
+    /// Decode the raw fan speed, which is inversely proportional to the RPM by `magic'
+    pub fn from_raw_ec_bytes(lo: u8, hi: u8, magic: u32) -> Self {
         let raw = ((hi as u16) << 8) + lo as u16;
         Speed {
             rpm: if raw > 0 {
-                MAGIC / raw as u32
+                magic / raw as u32
             } else {
                 raw as u32
             },
@@ -116,45 +131,125 @@ impl Speed {
     }
 }
 
-const EC_SC_PORT_NUM: u16 = 0x66;
-const EC_DATA_PORT_NUM: u16 = 0x62;
-const EC_FAN_CONTROL_CMD: u8 = 0x99;
-const EC_FAN_CONTROL_PORT: u8 = 0x1;
-const IBF: u32 = 1;
+/// Number of a fan: 1 is the CPU fan, 2 the GPU fan on models having one
+///
+/// How the EC addresses the fan is given by the model, see `model::FanMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Id(u8);
 
-pub struct Control {
-    sc_port: ECPort,
-    data_port: ECPort,
+#[derive(Debug, Display)]
+#[display(
+    fmt = "Invalid fan `{}', expected \"cpu\", \"gpu\" or a number from 1 to {}",
+    _0,
+    ec::FAN_COUNT
+)]
+pub struct InvalidId(String);
+impl Error for InvalidId {}
+
+impl Id {
+    pub const CPU: Id = Id(1);
+    pub const GPU: Id = Id(2);
+
+    /// All fans a model can have, whether the actual model has them or not
+    pub fn all() -> impl Iterator<Item = Id> {
+        (1..=ec::FAN_COUNT as u8).map(Id)
+    }
+
+    /// Position among `Id::all()`
+    pub fn index(self) -> usize {
+        self.0 as usize - 1
+    }
 }
 
-impl Control {
-    pub fn new() -> Result<Self, utils::SyscallError> {
-        unsafe {
-            Ok(Control {
-                sc_port: ECPort::new(EC_SC_PORT_NUM)?,
-                data_port: ECPort::new(EC_DATA_PORT_NUM)?,
-            })
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Id {
+    type Err = InvalidId;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpu" => Ok(Id::CPU),
+            "gpu" => Ok(Id::GPU),
+            _ => u8::from_str(s)
+                .ok()
+                .map(Id)
+                .filter(|id| Id::all().any(|valid| valid == *id))
+                .ok_or_else(|| InvalidId(s.to_owned())),
         }
     }
+}
 
-    fn write(&mut self, cmd: u8, port: u8, value: u8) -> Result<(), ec::PortIOError> {
-        self.sc_port.wait(IBF, 0)?;
-        self.sc_port.write(cmd)?;
+impl<'de> Deserialize<'de> for Id {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        config::deserialize_parsed(deserializer, Self::from_str)
+    }
+}
+
+impl Serialize for Id {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// What to leave the fan at when giving up control over it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Handover {
+    /// Let the EC firmware manage the fan again
+    Firmware,
+    /// Keep the fan at a fixed duty
+    Duty(Duty),
+}
+
+impl FromStr for Handover {
+    type Err = ParsePercentageError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" | "firmware" => Ok(Handover::Firmware),
+            _ => Duty::from_percentage_str(s).map(Handover::Duty),
+        }
+    }
+}
 
-        self.sc_port.wait(IBF, 0)?;
-        self.data_port.write(port)?;
+pub struct Control {
+    ec: Box<dyn ec::Backend>,
+}
+
+impl<'de> Deserialize<'de> for Handover {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        config::deserialize_parsed(deserializer, Self::from_str)
+    }
+}
 
-        self.sc_port.wait(IBF, 0)?;
-        self.data_port.write(value)?;
+impl Control {
+    pub fn new(ec: Box<dyn ec::Backend>) -> Self {
+        Control { ec }
+    }
 
-        self.sc_port.wait(IBF, 0)
+    /// EC the fans are controlled by, e.g. to read back their speed
+    pub fn ec(&mut self) -> &mut dyn ec::Backend {
+        &mut *self.ec
     }
 
-    pub fn set_duty(&mut self, duty: Duty) -> Result<(), ec::PortIOError> {
-        self.write(
-            EC_FAN_CONTROL_CMD,
-            EC_FAN_CONTROL_PORT,
-            duty.to_point_in_range(0..=255),
-        )
+    pub fn set_duty(&mut self, fan: Id, duty: Duty) -> Result<(), ec::Error> {
+        let model = self.ec.model();
+        let (cmd, port) = (model.fan_control_cmd, model.fan(fan)?.port);
+        self.ec.write(cmd, port, duty.to_point_in_range(0..=255))
+    }
+
+    /// Give up control over the given fans
+    ///
+    /// The firmware always takes over all fans at once.
+    pub fn hand_over(&mut self, handover: Handover, fans: &[Id]) -> Result<(), ec::Error> {
+        match handover {
+            Handover::Firmware => {
+                let model = self.ec.model();
+                let (cmd, port) = (model.fan_control_cmd, model.fan_auto_port);
+                self.ec.write(cmd, port, 0)
+            }
+            Handover::Duty(duty) => fans.iter().try_for_each(|fan| self.set_duty(*fan, duty)),
+        }
     }
 }
@@ -1,12 +1,24 @@
-use crate::utils;
+use crate::{config, utils};
 use derive_more::Display;
-use std::{error::Error, str::FromStr};
+use serde::{Deserialize, Deserializer};
+use std::{error::Error, fmt, num::ParseFloatError, str::FromStr, time::Duration};
 
 pub trait FanPolicy {
     type Input;
-    fn next_fan_duty(&self, input: Self::Input) -> super::Duty;
+    /// Determine the fan duty for the next cycle
+    ///
+    /// `elapsed` is the time since the previous input, zero for the very first one.
+    fn next_fan_duty(&mut self, input: Self::Input, elapsed: Duration) -> super::Duty;
 }
 
+impl<P: FanPolicy + ?Sized> FanPolicy for Box<P> {
+    type Input = P::Input;
+    fn next_fan_duty(&mut self, input: Self::Input, elapsed: Duration) -> super::Duty {
+        (**self).next_fan_duty(input, elapsed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Linear {
     pub slope: f64,
     pub offset: f64,
@@ -14,9 +26,9 @@ pub struct Linear {
 
 impl FanPolicy for Linear {
     type Input = utils::Temperature;
-    fn next_fan_duty(&self, temp: Self::Input) -> super::Duty {
+    fn next_fan_duty(&mut self, temp: Self::Input, _elapsed: Duration) -> super::Duty {
         super::Duty::from_saturating_percentage(
-            self.offset + temp.as_degrees_celsius() as f64 * self.slope,
+            self.offset + temp.as_degrees_celsius() * self.slope,
         )
     }
 }
@@ -30,12 +42,13 @@ impl Default for Linear {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Exponential {
     pub base: ExponentialBase,
     pub factor: f64,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ExponentialBase {
     Euler,
     Binary,
@@ -57,6 +70,21 @@ impl FromStr for ExponentialBase {
     }
 }
 
+impl<'de> Deserialize<'de> for ExponentialBase {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        config::deserialize_parsed(deserializer, Self::from_str)
+    }
+}
+
+impl fmt::Display for ExponentialBase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExponentialBase::Euler => write!(f, "e"),
+            ExponentialBase::Binary => write!(f, "2"),
+        }
+    }
+}
+
 impl ExponentialBase {
     fn exp(self, exponent: f64) -> f64 {
         use self::ExponentialBase::*;
@@ -69,22 +97,346 @@ impl ExponentialBase {
 
 impl FanPolicy for Exponential {
     type Input = utils::Temperature;
-    fn next_fan_duty(&self, temp: Self::Input) -> super::Duty {
+    fn next_fan_duty(&mut self, temp: Self::Input, _elapsed: Duration) -> super::Duty {
         super::Duty::from_saturating_percentage(
-            self.factor * self.base.exp(temp.as_degrees_celsius() as f64),
+            self.factor * self.base.exp(temp.as_degrees_celsius()),
         )
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Quadratic {
     pub factor: f64,
 }
 
 impl FanPolicy for Quadratic {
     type Input = utils::Temperature;
-    fn next_fan_duty(&self, temp: Self::Input) -> super::Duty {
+    fn next_fan_duty(&mut self, temp: Self::Input, _elapsed: Duration) -> super::Duty {
+        super::Duty::from_saturating_percentage(self.factor * temp.as_degrees_celsius().powi(2))
+    }
+}
+
+/// Fan curve, defined by a set of points, that is interpolated linearly
+///
+/// Below the first and above the last point, the duty of that point is used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve {
+    /// Points of the curve, as degrees Celsius and fan duty, ordered by temperature
+    points: Vec<(f64, super::Duty)>,
+}
+
+#[derive(Debug, Display)]
+pub enum InvalidCurve {
+    #[display(fmt = "Curve needs at least one point")]
+    Empty,
+    #[display(fmt = "Invalid curve point `{}', expected <temperature>:<duty>", _0)]
+    Point(String),
+    #[display(fmt = "Invalid temperature in curve point `{}': {}", _0, _1)]
+    Temperature(String, ParseFloatError),
+    #[display(fmt = "Invalid duty in curve point `{}': {}", _0, _1)]
+    Duty(String, super::ParsePercentageError),
+    #[display(fmt = "Curve point `{}' must be finite", _0)]
+    NotFinite(String),
+    #[display(
+        fmt = "Curve temperatures must be strictly increasing, `{}' is not",
+        _0
+    )]
+    Order(String),
+}
+impl Error for InvalidCurve {}
+
+impl FromStr for Curve {
+    type Err = InvalidCurve;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut points: Vec<(f64, super::Duty)> = Vec::new();
+        for point in s
+            .split(',')
+            .map(str::trim)
+            .filter(|point| !point.is_empty())
+        {
+            let mut parts = point.splitn(2, ':');
+            let (temp, duty) = match (parts.next(), parts.next()) {
+                (Some(temp), Some(duty)) => (temp.trim(), duty.trim()),
+                _ => return Err(InvalidCurve::Point(point.to_owned())),
+            };
+            let temp = f64::from_str(temp)
+                .map_err(|err| InvalidCurve::Temperature(point.to_owned(), err))?;
+            let duty = super::Duty::from_percentage_str(duty)
+                .map_err(|err| InvalidCurve::Duty(point.to_owned(), err))?;
+            // "nan" and "inf" parse as floats, but cannot be interpolated
+            if !temp.is_finite() || !duty.as_percentage().is_finite() {
+                return Err(InvalidCurve::NotFinite(point.to_owned()));
+            }
+
+            if points.last().is_some_and(|&(last, _)| last >= temp) {
+                return Err(InvalidCurve::Order(point.to_owned()));
+            }
+            points.push((temp, duty));
+        }
+
+        if points.is_empty() {
+            Err(InvalidCurve::Empty)
+        } else {
+            Ok(Curve { points })
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Curve {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        config::deserialize_parsed(deserializer, Self::from_str)
+    }
+}
+
+impl fmt::Display for Curve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (temp, duty)) in self.points.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}:{:#}", temp, duty)?;
+        }
+        Ok(())
+    }
+}
+
+impl FanPolicy for Curve {
+    type Input = utils::Temperature;
+    fn next_fan_duty(&mut self, temp: Self::Input, _elapsed: Duration) -> super::Duty {
+        let temp = temp.as_degrees_celsius();
+
+        let (first_temp, first_duty) = self.points[0];
+        if temp <= first_temp {
+            return first_duty;
+        }
+
+        self.points
+            .windows(2)
+            .find(|segment| temp <= segment[1].0)
+            .map(|segment| {
+                let (start_temp, start_duty) = segment[0];
+                let (end_temp, end_duty) = segment[1];
+                let position = (temp - start_temp) / (end_temp - start_temp);
+                super::Duty::from_saturating_percentage(
+                    start_duty.as_percentage()
+                        + position * (end_duty.as_percentage() - start_duty.as_percentage()),
+                )
+            })
+            .unwrap_or_else(|| self.points[self.points.len() - 1].1)
+    }
+}
+
+/// Temperature hysteresis around another policy
+///
+/// The fan duty only goes up once the temperature has risen by `rise` degrees since the last
+/// change and only goes down once it has fallen by `fall` degrees since then. In between, the last
+/// fan duty is kept, no matter what the inner policy says.
+pub struct Hysteresis<P> {
+    pub inner: P,
+    pub rise: f64,
+    pub fall: f64,
+    /// Temperature at the last change, together with the fan duty set back then
+    last_change: Option<(f64, super::Duty)>,
+}
+
+impl<P> Hysteresis<P> {
+    pub fn new(inner: P, rise: f64, fall: f64) -> Self {
+        Hysteresis {
+            inner,
+            rise,
+            fall,
+            last_change: None,
+        }
+    }
+}
+
+impl<P: FanPolicy<Input = utils::Temperature>> FanPolicy for Hysteresis<P> {
+    type Input = utils::Temperature;
+    fn next_fan_duty(&mut self, temp: Self::Input, elapsed: Duration) -> super::Duty {
+        let target = self.inner.next_fan_duty(temp, elapsed);
+        let temp = temp.as_degrees_celsius();
+
+        match self.last_change {
+            Some((last_temp, duty))
+                if (target <= duty || temp < last_temp + self.rise)
+                    && (target >= duty || temp > last_temp - self.fall) =>
+            {
+                duty
+            }
+            _ => {
+                self.last_change = Some((temp, target));
+                target
+            }
+        }
+    }
+}
+
+/// PID controller, trying to keep the temperature at a setpoint
+///
+/// The error is the distance of the temperature above the setpoint, in degrees Celsius, the output
+/// is the fan duty in percent. The integral term is only accumulated while the output is not
+/// saturated in the direction of the error (anti-windup).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pid {
+    /// Target temperature, in degrees Celsius
+    pub setpoint: f64,
+    /// Proportional gain, in percent per degree Celsius
+    pub kp: f64,
+    /// Integral gain, in percent per degree Celsius and second
+    pub ki: f64,
+    /// Derivative gain, in percent per degree Celsius per second
+    pub kd: f64,
+    /// Lower bound of the output
+    pub min_duty: super::Duty,
+    integral: f64,
+    last_error: Option<f64>,
+}
+
+impl Pid {
+    pub fn new(setpoint: f64, kp: f64, ki: f64, kd: f64, min_duty: super::Duty) -> Self {
+        Pid {
+            setpoint,
+            kp,
+            ki,
+            kd,
+            min_duty,
+            integral: 0.0,
+            last_error: None,
+        }
+    }
+}
+
+impl FanPolicy for Pid {
+    type Input = utils::Temperature;
+    fn next_fan_duty(&mut self, temp: Self::Input, elapsed: Duration) -> super::Duty {
+        let elapsed = elapsed.as_secs_f64();
+        let error = temp.as_degrees_celsius() - self.setpoint;
+        let derivative = match self.last_error {
+            Some(last_error) if elapsed > 0.0 => (error - last_error) / elapsed,
+            _ => 0.0,
+        };
+        self.last_error = Some(error);
+
+        let min = self.min_duty.as_percentage();
+        let max = 100.0;
+        let proportional_derivative = self.kp * error + self.kd * derivative;
+
+        let integral = self.integral + error * elapsed;
+        let output = proportional_derivative + self.ki * integral;
+        if (output < max || error < 0.0) && (output > min || error > 0.0) {
+            self.integral = integral;
+        }
+
         super::Duty::from_saturating_percentage(
-            self.factor * (temp.as_degrees_celsius() as f64).powi(2),
+            (proportional_derivative + self.ki * self.integral).max(min),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /// Duty in percent, that the policy determines for the temperature
+    fn duty<P: FanPolicy<Input = utils::Temperature>>(
+        policy: &mut P,
+        temp: f64,
+        elapsed: Duration,
+    ) -> f64 {
+        policy
+            .next_fan_duty(utils::Temperature::from_degrees_celsius(temp), elapsed)
+            .as_percentage()
+    }
+
+    #[test]
+    fn parse_curve() {
+        let curve = Curve::from_str(" 50:0, 60:40% ,75:70,85:100,").unwrap();
+        assert_eq!(curve.to_string(), "50:0.00,60:40.00,75:70.00,85:100.00");
+        assert_eq!(Curve::from_str(&curve.to_string()).unwrap(), curve);
+        assert_eq!(
+            Curve::from_str("-10.5:20").unwrap().to_string(),
+            "-10.5:20.00"
+        );
+    }
+
+    #[test]
+    fn parse_invalid_curve() {
+        use self::InvalidCurve::*;
+        let parse = |s| Curve::from_str(s).unwrap_err();
+        assert!(matches!(parse(""), Empty));
+        assert!(matches!(parse(" , "), Empty));
+        assert!(matches!(parse("50"), Point(_)));
+        assert!(matches!(parse("hot:50"), Temperature(..)));
+        assert!(matches!(parse("50:fast"), Duty(..)));
+        assert!(matches!(parse("50:101"), Duty(..)));
+        assert!(matches!(parse("50:-1"), Duty(..)));
+        assert!(matches!(parse("nan:50"), NotFinite(_)));
+        assert!(matches!(parse("50:0,inf:100"), NotFinite(_)));
+        assert!(matches!(parse("50:nan"), NotFinite(_)));
+        assert!(matches!(parse("50:0,50:100"), Order(_)));
+        assert!(matches!(parse("60:0,50:100"), Order(_)));
+    }
+
+    #[test]
+    fn curve() {
+        let mut curve = Curve::from_str("50:0,60:40,80:100").unwrap();
+        assert_eq!(duty(&mut curve, 20.0, SECOND), 0.0);
+        assert_eq!(duty(&mut curve, 50.0, SECOND), 0.0);
+        assert_eq!(duty(&mut curve, 55.0, SECOND), 20.0);
+        assert_eq!(duty(&mut curve, 60.0, SECOND), 40.0);
+        assert_eq!(duty(&mut curve, 70.0, SECOND), 70.0);
+        assert_eq!(duty(&mut curve, 80.0, SECOND), 100.0);
+        assert_eq!(duty(&mut curve, 255.0, SECOND), 100.0);
+    }
+
+    #[test]
+    fn single_point_curve() {
+        let mut curve = Curve::from_str("60:30").unwrap();
+        assert_eq!(duty(&mut curve, 40.0, SECOND), 30.0);
+        assert_eq!(duty(&mut curve, 80.0, SECOND), 30.0);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut hysteresis = Hysteresis::new(Linear::default(), 2.0, 3.0);
+        assert_eq!(duty(&mut hysteresis, 50.0, SECOND), 50.0);
+        // Rising by less than `rise'
+        assert_eq!(duty(&mut hysteresis, 51.0, SECOND), 50.0);
+        assert_eq!(duty(&mut hysteresis, 52.0, SECOND), 52.0);
+        // Falling by less than `fall'
+        assert_eq!(duty(&mut hysteresis, 50.0, SECOND), 52.0);
+        assert_eq!(duty(&mut hysteresis, 49.5, SECOND), 52.0);
+        assert_eq!(duty(&mut hysteresis, 49.0, SECOND), 49.0);
+    }
+
+    #[test]
+    fn pid() {
+        let mut pid = Pid::new(60.0, 2.0, 0.5, 1.0, super::super::Duty::min());
+        // No integral and derivative for the first input
+        assert_eq!(duty(&mut pid, 70.0, Duration::from_secs(0)), 20.0);
+        // The integral grows by 10 degree seconds
+        assert_eq!(duty(&mut pid, 70.0, SECOND), 25.0);
+        // Falling by 2 degrees per second
+        assert_eq!(duty(&mut pid, 68.0, SECOND), 16.0 + 0.5 * 18.0 - 2.0);
+    }
+
+    #[test]
+    fn pid_min_duty() {
+        let min_duty = super::super::Duty::from_percentage(20.0).unwrap();
+        let mut pid = Pid::new(60.0, 2.0, 0.0, 0.0, min_duty);
+        assert_eq!(duty(&mut pid, 40.0, SECOND), 20.0);
+    }
+
+    #[test]
+    fn pid_anti_windup() {
+        let mut pid = Pid::new(60.0, 10.0, 1.0, 0.0, super::super::Duty::min());
+        // Saturated at full duty for a long time
+        for _ in 0..100 {
+            assert_eq!(duty(&mut pid, 80.0, SECOND), 100.0);
+        }
+        // Without anti-windup, the integral would keep the fan at full duty
+        assert_eq!(duty(&mut pid, 60.0, SECOND), 0.0);
+    }
+}
@@ -0,0 +1,5 @@
+//! Building blocks of `clevo-fan', that do not depend on the EC or the command line
+//!
+//! They are a library of their own, so that the benchmarks can use them.
+
+pub mod utils;
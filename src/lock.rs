@@ -0,0 +1,106 @@
+use crate::utils;
+use derive_more::Display;
+use std::{
+    error, fs,
+    io::{self, Read, Seek, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
+
+pub const DEFAULT_LOCK_PATH: &str = "/run/clevo-fan.lock";
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "Cannot open lock file {}: {}", "_0.display()", _1)]
+    Open(PathBuf, io::Error),
+    #[display(fmt = "Cannot lock {}: {}", "_0.display()", _1)]
+    Lock(PathBuf, utils::SyscallError),
+    #[display(
+        fmt = "The fan is already controlled by {} (see {})",
+        "_1.map_or(\"another process\".to_owned(), |pid| format!(\"process {}\", pid))",
+        "_0.display()"
+    )]
+    Held(PathBuf, Option<u32>),
+}
+impl error::Error for Error {}
+
+/// Advisory lock, that has to be held when writing to the EC
+///
+/// Every command sending commands to the EC holds this while doing so, `clevo-fan auto' for its
+/// whole lifetime. This keeps concurrent command sequences from getting interleaved. The lock is
+/// an flock(2) on the lock file, which contains the PID of the holder. It is released when
+/// dropped or when the holder dies.
+pub struct WriterLock {
+    file: fs::File,
+}
+
+impl WriterLock {
+    /// Take the lock, or fail naming the process holding it
+    pub fn acquire(path: &Path) -> Result<Self, Error> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // The PID of the holder must survive until we have the lock
+            .truncate(false)
+            .open(path)
+            .map_err(|err| Error::Open(path.to_owned(), err))?;
+
+        match nc::flock(file.as_raw_fd(), nc::LOCK_EX | nc::LOCK_NB) {
+            Ok(()) => (),
+            Err(nc::EWOULDBLOCK) => {
+                let mut holder = String::new();
+                file.read_to_string(&mut holder).ok();
+                return Err(Error::Held(
+                    path.to_owned(),
+                    u32::from_str(holder.trim()).ok(),
+                ));
+            }
+            Err(errno) => return Err(Error::Lock(path.to_owned(), errno.into())),
+        }
+
+        // Not being able to name ourselves as the holder is no reason to fail
+        file.set_len(0)
+            .and_then(|_| file.seek(io::SeekFrom::Start(0)))
+            .and_then(|_| writeln!(file, "{}", process::id()))
+            .ok();
+
+        Ok(WriterLock { file })
+    }
+}
+
+impl Drop for WriterLock {
+    fn drop(&mut self) {
+        self.file.set_len(0).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writer_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clevo-fan.lock");
+
+        let lock = WriterLock::acquire(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+        match WriterLock::acquire(&path) {
+            Err(Error::Held(held, pid)) => {
+                assert_eq!(held, path);
+                assert_eq!(pid, Some(process::id()));
+            }
+            result => panic!("Expected the lock to be held, got {:?}", result.err()),
+        }
+
+        drop(lock);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        let _lock = WriterLock::acquire(&path).unwrap();
+    }
+}
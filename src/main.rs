@@ -1,19 +1,31 @@
+mod alert;
+mod calibration;
+mod config;
+mod ctl;
+mod daemon;
+mod dump;
 mod ec;
 mod fan;
-mod utils;
+mod lock;
+mod model;
+mod plant;
+mod sensor;
+mod show;
+mod shutdown;
+mod speed;
+mod stall;
+mod trace;
+mod tui;
 
-use io::Seek;
+use clevo_fan::utils::{self, ResultExt};
+use serde::Deserialize;
 use std::{
-    convert::TryFrom,
-    fmt, fs,
     io::{self, Write},
-    iter,
-    path::PathBuf,
-    thread,
-    time::Duration,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
 };
 use structopt::StructOpt;
-use utils::{MovingAverageIteratorExt, MovingMedianIteratorExt, ResultExt};
 
 type MainResult = utils::FlexibleResult<()>;
 
@@ -28,14 +40,148 @@ struct App {
     command: Command,
 }
 
-#[derive(Debug, StructOpt)]
+const DEFAULT_EC_PATH: &str = "/sys/kernel/debug/ec/ec0/io";
+
+#[derive(Debug, Clone, StructOpt)]
 struct Options {
     /// SysFS path to the EC interface
-    #[structopt(long, default_value = "/sys/kernel/debug/ec/ec0/io")]
-    ec_path: PathBuf,
+    ///
+    /// Defaults to /sys/kernel/debug/ec/ec0/io.
+    #[structopt(long)]
+    ec_path: Option<PathBuf>,
+    /// Use a simulated EC instead of the real one
+    ///
+    /// The file at <ec-path> is used as the EC register block, no I/O ports are accessed. Setting
+    /// the fan duty updates the corresponding register in that file, every other value can be
+    /// changed by modifying the file externally. This allows running all commands without root
+    /// privileges or Clevo hardware, e.g. for testing.
+    #[structopt(long)]
+    simulate: bool,
+
+    /// Lock file, that is held while writing to the EC
+    ///
+    /// Only one process at a time may write to the EC, `clevo-fan auto' for as long as it runs.
+    /// Defaults to /run/clevo-fan.lock, or to <ec-path>.lock with `--simulate'.
+    #[structopt(long)]
+    lock_path: Option<PathBuf>,
+    /// Write to the EC even if another process holds the lock
+    ///
+    /// Only meant for emergencies, e.g. when a hung `clevo-fan auto' keeps the fan at a bad duty.
+    #[structopt(long)]
+    force: bool,
+
+    /// Register map of the EC to use
+    ///
+    /// Clevo models differ in where the EC keeps the temperatures and fan values and in how the
+    /// fans are controlled. By default, the model is chosen by the board name in
+    /// /sys/class/dmi/id/board_name, falling back to "clevo", the register map most models use,
+    /// with only the CPU fan. Use "clevo-dual" for unknown boards with a separate GPU fan. With
+    /// `--simulate', the board name is not looked at.
+    #[structopt(long)]
+    model: Option<String>,
+    /// Read additional register maps from a TOML file
+    ///
+    /// The file contains a table per model, e.g. `[my-model]'. It has the keys `boards' (list of
+    /// board names to use this model for), `cpu-temp' and `gpu-temp' (register offsets),
+    /// `fan-control-cmd' (EC command to set the duty of a fan), `fan-auto-port' (port of that
+    /// command, that hands the fans back to the firmware) and `rpm-magic' (the fan speed in RPM
+    /// is `rpm-magic' divided by the raw speed). Each fan is an entry of the `[[my-model.fans]]'
+    /// array with the keys `duty', `rpm-hi', `rpm-lo' (register offsets) and `port' (port to set
+    /// its duty at). Offsets can be given as hexadecimal numbers, e.g. `cpu-temp = 0x07'.
+    ///
+    /// These register maps take precedence over the built-in ones.
+    #[structopt(long)]
+    model_file: Option<PathBuf>,
+
+    /// File with the measured behaviour of the fans, as written by `clevo-fan calibrate'
+    ///
+    /// If it exists, `clevo-fan set' warns about duties too low to start the fan and `clevo-fan
+    /// auto' avoids them. Defaults to /var/lib/clevo-fan/calibration.toml, or to
+    /// <ec-path>.calibration.toml with `--simulate'.
+    #[structopt(long)]
+    calibration: Option<PathBuf>,
+
+    /// Where sysfs is mounted, to read the sensors of the kernel from
+    ///
+    /// See `--input' of `clevo-fan auto'. Can point to a copy of the relevant parts of /sys, e.g.
+    /// for testing. Defaults to /sys.
+    #[structopt(long)]
+    sysfs_root: Option<PathBuf>,
+}
+
+impl Options {
+    fn open_ec(&self) -> utils::FlexibleResult<Box<dyn ec::Backend>> {
+        let path = self
+            .ec_path
+            .as_deref()
+            .unwrap_or_else(|| Path::new(DEFAULT_EC_PATH));
+        let model = model::Model::select(
+            self.model.as_deref(),
+            self.model_file.as_deref(),
+            !self.simulate,
+        )?;
+        Ok(if self.simulate {
+            Box::new(ec::Simulated::open(path, model)?)
+        } else {
+            Box::new(ec::Hardware::open(path, model)?)
+        })
+    }
+
+    /// Path of a file belonging to the EC, next to the simulated EC with `--simulate'
+    fn ec_file(&self, path: &Option<PathBuf>, suffix: &str, default: &str) -> PathBuf {
+        match (path, &self.ec_path) {
+            (Some(path), _) => path.clone(),
+            (None, Some(ec_path)) if self.simulate => {
+                let mut path = ec_path.clone().into_os_string();
+                path.push(suffix);
+                PathBuf::from(path)
+            }
+            (None, _) => PathBuf::from(default),
+        }
+    }
+
+    fn calibration_path(&self) -> PathBuf {
+        self.ec_file(
+            &self.calibration,
+            ".calibration.toml",
+            calibration::DEFAULT_CALIBRATION_PATH,
+        )
+    }
+
+    fn sensors(&self) -> sensor::Sensors {
+        sensor::Sensors::new(
+            self.sysfs_root
+                .as_deref()
+                .unwrap_or_else(|| Path::new(sensor::DEFAULT_SYSFS_ROOT)),
+        )
+    }
+
+    fn load_calibration(&self) -> Result<Option<calibration::Calibration>, calibration::Error> {
+        calibration::Calibration::load(&self.calibration_path())
+    }
+
+    /// Take the lock needed to write to the EC
+    ///
+    /// With `--force', failing to do so is only a warning.
+    fn lock_writer(&self) -> utils::FlexibleResult<Option<lock::WriterLock>> {
+        let path = self.ec_file(&self.lock_path, ".lock", lock::DEFAULT_LOCK_PATH);
+
+        match lock::WriterLock::acquire(&path) {
+            Ok(lock) => Ok(Some(lock)),
+            Err(err) if self.force => {
+                writeln!(io::stderr(), "Warning: {}, writing anyway", err)?;
+                Ok(None)
+            }
+            Err(err @ lock::Error::Held(..)) => {
+                Err(format!("{}, use `--force' to write anyway", err).into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
 }
 
 #[derive(Debug, StructOpt)]
+#[allow(clippy::large_enum_variant)]
 enum Command {
     /// Query values from EC interface
     ///
@@ -53,11 +199,44 @@ enum Command {
     ///
     /// Manually set the fan duty to a specificied value.
     ///
-    /// Warning: This should not be used while a `clevo-fan auto' is already running.
+    /// This fails while a `clevo-fan auto' is running, use `clevo-fan ctl override' instead.
     Set {
         /// Desired fan duty, in percent
-        #[structopt(parse(try_from_str = fan::Duty::from_percentage_str))]
-        value: fan::Duty,
+        #[structopt(
+            parse(try_from_str = fan::Duty::from_percentage_str),
+            required_unless = "rpm"
+        )]
+        value: Option<fan::Duty>,
+        /// Desired fan speed, in RPM, instead of a fan duty
+        ///
+        /// The duty is adjusted, until the speed read back from the EC matches. This needs the
+        /// speed range of the fan from `clevo-fan calibrate' and fails if the speed is outside of
+        /// it or cannot be reached within 30 seconds.
+        #[structopt(long, conflicts_with = "value")]
+        rpm: Option<u32>,
+        /// Fan to set, either "cpu", "gpu" or its number
+        ///
+        /// Can be given multiple times. Defaults to the CPU fan.
+        #[structopt(long = "fan", number_of_values = 1)]
+        fans: Vec<fan::Id>,
+    },
+
+    /// Hand fan control back to the EC firmware
+    ///
+    /// Does the same as `clevo-fan auto' when exiting. Useful to recover from a crashed or killed
+    /// `clevo-fan auto' or after manually setting the fan duty.
+    Reset {
+        /// What to leave the fan at
+        ///
+        /// Either "auto", to let the EC firmware manage the fan, or a fixed fan duty, in percent.
+        #[structopt(long, default_value = "auto")]
+        to: fan::Handover,
+        /// Fan to leave at the fixed duty, either "cpu", "gpu" or its number
+        ///
+        /// Can be given multiple times. Defaults to the CPU fan. The firmware always takes over all
+        /// fans.
+        #[structopt(long = "fan", number_of_values = 1)]
+        fans: Vec<fan::Id>,
     },
 
     /// Automatically manage fan duty
@@ -74,65 +253,401 @@ enum Command {
     /// that the fan never gets unattended: When failing to read the temperature, an infinitely
     /// high temperature is assumed to stay on the safe side. When the fan duty cannot be set, the
     /// cycle is skipped and setting it is tried again using the next queried temperature.  All
-    /// these error conditions are reported to stderr. Any errors writing to stderr (or to stdout)
-    /// are ignored.
+    /// these error conditions are reported to stderr (or the dashboard, see `--tui'). Any errors
+    /// writing to stderr (or to stdout) are ignored.
+    ///
+    /// On SIGINT or SIGTERM and on internal errors, the fan is handed over as configured by
+    /// `--on-exit' before exiting.
+    ///
+    /// All options can also be given in a configuration file, see `--config'. On SIGHUP, the
+    /// configuration file is read again and the new settings are applied without restarting.
+    /// Each filter keeps its history, if the same kind of filter stays at its place in the chain.
+    /// The global options (e.g. `--ec-path', `--simulate', `--model') are only read at startup.
+    ///
+    /// While running, this can be queried and controlled with `clevo-fan ctl', see `--socket'.
+    ///
+    /// If the fans have been calibrated (see `clevo-fan calibrate'), duties too low to keep a fan
+    /// spinning are lowered to 0. When starting a fan, duties too low to start it are raised to
+    /// its spin-up duty. Overrides set via `clevo-fan ctl' are applied as they are.
     Auto {
         #[structopt(flatten)]
         policies: Policies,
+        #[structopt(flatten)]
+        options: AutoOptions,
+    },
 
-        /// Update interval, in milliseconds
-        ///
-        /// Specifies the interval length in which to poll the temperature and update the fan duty.
-        #[structopt(long, short = "i", default_value = "500")]
-        polling_interval: u64,
+    /// Query or control a running `clevo-fan auto'
+    ///
+    /// This talks to `clevo-fan auto' via its control socket, so that the fan is only ever written
+    /// by that one process.
+    Ctl {
+        /// Path of the control socket of `clevo-fan auto'
+        #[structopt(long, default_value = ctl::DEFAULT_SOCKET_PATH)]
+        socket: PathBuf,
+        #[structopt(subcommand)]
+        command: CtlCommand,
+    },
 
-        /// Apply moving average to temperature curve
-        ///
-        /// Usees a moving moving average of the <moving-average> most recent temperature probes as
-        /// basis to the fan duty calculation.
-        ///
-        /// In contrast to the moving median option, the moving average is a bit more sensitive to
-        /// short temperature spikes, but can react faster to sudden, strong temperature changes.
-        #[structopt(long, short = "a")]
-        moving_average: Option<usize>,
-        /// Apply moving median to temperature curve
-        ///
-        /// Uses a moving moving median of the <moving-median> most recent temperature probes as
-        /// basis to the fan duty calculation.
-        ///
-        /// In contrast to the moving average option, the moving median is better at hiding
-        /// temperature spikes, but also more sluggish in reacting to real, longer-lasting
-        /// temperature surges (since they are indistinguishable from short spikes at first).
-        #[structopt(long, short = "m")]
-        moving_median: Option<usize>,
+    /// Replay a trace recorded by `clevo-fan auto --record'
+    ///
+    /// The recorded temperatures are fed through the filters and the policy given by the
+    /// options, exactly like `clevo-fan auto' would, without touching the EC. This allows tuning
+    /// e.g. `--filter', `--min-fan-change' or the policy offline. The resulting fan duties
+    /// are printed as CSV, next to the recorded ones, or plotted with `--monitor'. Options not
+    /// concerning the fan duty, like `--socket' or `--tui', are ignored.
+    Replay {
+        /// The recorded trace
+        trace: PathBuf,
+        #[structopt(flatten)]
+        policies: Policies,
+        #[structopt(flatten)]
+        options: AutoOptions,
+    },
 
-        /// Only apply fan duty changes smaller than this value
-        ///
-        /// If a calculated new fan duty is within this distance to the current fan change will not
-        /// be applied, unless a requested change is requested for too long. See
-        /// `--max-unchanged-cycles'.
-        #[structopt(long, default_value = "0.0")]
-        min_fan_change: f64,
-        /// Maximum number of consequtive fan duty changes to ignore
+    /// Test policies against a simulated machine
+    ///
+    /// Unlike `clevo-fan replay', this closes the loop: The fan duties determined by the policy
+    /// cool down a simulated machine, which in turn determines the next temperature. The machine
+    /// is a single thermal mass, heated by a load profile and cooled towards the ambient
+    /// temperature, passively and by each fan in proportion to its duty. The CPU and GPU
+    /// temperature and the sensors of the kernel are all that of the machine. The simulation runs
+    /// as fast as possible, not in real time.
+    ///
+    /// Reports the peak and mean temperature, the variance of the fan duty and the number of fan
+    /// duty changes. Options not concerning the fan duty, like `--socket' or `--tui', are ignored.
+    Simulate {
+        #[structopt(flatten)]
+        plant: PlantOptions,
+        #[structopt(flatten)]
+        policies: Policies,
+        #[structopt(flatten)]
+        options: AutoOptions,
+    },
+
+    /// Inspect the raw EC register block
+    ///
+    /// Meant for finding the registers of a new model, see `--model-file'. The registers known
+    /// from the current model are annotated.
+    Ec {
+        #[structopt(subcommand)]
+        command: EcCommand,
+    },
+
+    /// Measure the speed of the fans over their whole duty range
+    ///
+    /// Each fan is stopped, then its duty is raised from 0 to 100% and lowered again in steps,
+    /// waiting for the speed to settle at each step. This finds the speed at each duty, the lowest
+    /// duty that starts the stopped fan and the lowest duty that keeps the running fan spinning.
+    /// The results are written to the file given by `--calibration', from where `clevo-fan set'
+    /// and `clevo-fan auto' pick them up.
+    ///
+    /// This takes a few minutes per fan and fails while a `clevo-fan auto' is running. The fans
+    /// are handed back to the firmware when done or interrupted.
+    Calibrate {
+        /// Fan to calibrate, either "cpu", "gpu" or its number
         ///
-        /// When the target fan duty has remained unchanged for the last <max-unchanged-cycles>
-        /// cycles and a change has been continually requested in that period, it is applied even if
-        /// it falls below the <min-fan-change>.
+        /// Can be given multiple times. Defaults to all fans of the model, that are currently
+        /// spinning, as a fan may not exist even though the model has registers for it. Fans not
+        /// calibrated keep their previous calibration.
+        #[structopt(long = "fan", number_of_values = 1)]
+        fans: Vec<fan::Id>,
+        /// Duty between measurements, in percent
+        #[structopt(long, default_value = "5")]
+        step: f64,
+        /// Longest time to wait for the fan speed to settle at each duty, e.g. "10s"
         ///
-        /// Only effective with `min-fan-change > 0'.
-        #[structopt(long, default_value = "10")]
-        max_unchanged_cycles: usize,
+        /// At duties where the fan stands still, all of this time is waited, in case the fan is
+        /// just slow to start.
+        #[structopt(long, default_value = "10s")]
+        settle: humantime::Duration,
+    },
+}
 
-        /// Monitor temperature and fan duty curves
-        ///
-        /// Prints the current temperature, the preprocessed temperature (if any preprocessing
-        /// option was selected) and the resulting fan duty to stdout in each cycle. The curve of
-        /// each of these values is visualized using ASCII-plotting, using the '#'-character.
-        #[structopt(long)]
-        monitor: bool,
+#[derive(Debug, StructOpt)]
+enum CtlCommand {
+    /// Print the current temperature, fan duty, policy and profile
+    Status,
+
+    /// Keep the fan at a fixed duty, regardless of the policy
+    ///
+    /// The policy keeps running in the background and takes over again, once the override is
+    /// lifted or expires.
+    Override {
+        /// Desired fan duty, in percent, or "off" to lift the override
+        duty: String,
+        /// Lift the override after this time, e.g. "30s" or "5m"
+        #[structopt(long = "for")]
+        duration: Option<humantime::Duration>,
+    },
+
+    /// Switch to a profile from the configuration file of `clevo-fan auto'
+    ///
+    /// Options of the profile take precedence over the command line and the rest of the
+    /// configuration file. Without <name>, the profile is left again. The profile is kept when
+    /// reloading the configuration.
+    Profile { name: Option<String> },
+}
+
+#[derive(Debug, StructOpt)]
+enum EcCommand {
+    /// Print all registers as a hexdump
+    Dump,
+
+    /// Print the registers that change, until interrupted
+    ///
+    /// Each changed register is printed on its own line, with the time since starting, its old
+    /// and new value and its name, if known. This helps to correlate registers with e.g. load or
+    /// fan changes.
+    Diff {
+        /// Time between samples, e.g. "500ms" or "2s"
+        #[structopt(long, short = "i", default_value = "1s")]
+        interval: humantime::Duration,
     },
 }
 
+#[derive(Debug, StructOpt)]
+struct PlantOptions {
+    /// Heat put into the machine, as phases of <duration>:<watts>
+    ///
+    /// The simulation ends with the last phase.
+    #[structopt(long, default_value = "1m:5,3m:45,2m:5")]
+    load: plant::LoadProfile,
+    /// Ambient temperature, in degrees Celsius
+    #[structopt(long, default_value = "25")]
+    ambient: f64,
+    /// Energy needed to heat the machine by one degree, in J/K
+    #[structopt(long, default_value = "30")]
+    heat_capacity: f64,
+    /// Heat dissipated without fans, per degree above ambient, in W/K
+    #[structopt(long, default_value = "0.25")]
+    passive_cooling: f64,
+    /// Heat dissipated by each fan at full duty, per degree above ambient, in W/K
+    #[structopt(long, default_value = "0.65")]
+    fan_cooling: f64,
+    /// Also simulate each profile of the configuration file, for comparison
+    #[structopt(long)]
+    compare_profiles: bool,
+}
+
+#[derive(Debug, Clone, Default, StructOpt, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct AutoOptions {
+    /// Read settings from a TOML configuration file
+    ///
+    /// The file can contain the global options (e.g. `ec-path', `simulate', `model') as top-level
+    /// keys, the other options of this command in an `[auto]' table and the policy options in a
+    /// `[policy]' table. Keys are named like the long options, e.g. `polling-interval = 500'
+    /// or `curve = "50:0,60:40,75:70,85:100"'. Named curves can be defined in a `[curves]'
+    /// table and then be used as `curve = "<name>"', both in the file and on the command line.
+    /// Each fan can get its own `input' and policy in a `[fans.<id>]' and `[fans.<id>.policy]'
+    /// table, e.g. `[fans.gpu]' with `input = "gpu"'. Further inputs with policies of their own
+    /// can be added to a fan as `[[fans.<id>.sensors]]' entries with an `input' and an optional
+    /// `[fans.<id>.sensors.policy]' table, falling back to the policy of the fan. The fan then
+    /// runs at the highest duty of all its policies, skipping inputs that cannot be read, its own
+    /// `input' included. Only if none can be read, it runs at full duty.
+    ///
+    /// Options given on the command line override those in the configuration file. The file
+    /// is fully validated before the fan control loop starts.
+    #[structopt(long)]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+
+    /// Update interval, in milliseconds
+    ///
+    /// Specifies the interval length in which to poll the temperature and update the fan duty.
+    /// Defaults to 500.
+    #[structopt(long, short = "i")]
+    polling_interval: Option<config::Located<u64>>,
+
+    /// Apply moving average to temperature curve
+    ///
+    /// Usees a moving moving average of the <moving-average> most recent temperature probes as
+    /// basis to the fan duty calculation.
+    ///
+    /// In contrast to the moving median option, the moving average is a bit more sensitive to
+    /// short temperature spikes, but can react faster to sudden, strong temperature changes.
+    #[structopt(long, short = "a")]
+    moving_average: Option<config::Located<usize>>,
+    /// Apply moving median to temperature curve
+    ///
+    /// Uses a moving moving median of the <moving-median> most recent temperature probes as
+    /// basis to the fan duty calculation.
+    ///
+    /// In contrast to the moving average option, the moving median is better at hiding
+    /// temperature spikes, but also more sluggish in reacting to real, longer-lasting
+    /// temperature surges (since they are indistinguishable from short spikes at first).
+    #[structopt(long, short = "m")]
+    moving_median: Option<config::Located<usize>>,
+    /// Filter the temperature curve
+    ///
+    /// Can be given multiple times, the filters are applied in the given order, e.g.
+    /// `--filter median:5 --filter ema:3s'. Each filter is one of:
+    ///
+    /// "average:<n>" and "median:<n>", the moving average or median of the <n> most recent
+    /// temperatures, like `--moving-average' and `--moving-median'. These two options are
+    /// applied before the filters given here.
+    ///
+    /// "ema:<time>", an exponential moving average, following a change of the temperature by
+    /// 63% within <time>, e.g. "3s".
+    ///
+    /// "spike:<degrees>", dropping temperatures jumping more than <degrees> Celsius away from
+    /// the previous one, unless they stay there for 3 readings.
+    ///
+    /// "rate:<degrees>", following the temperature by at most <degrees> Celsius per second.
+    ///
+    /// In the configuration file, use e.g. `filter = ["median:5", "ema:3s"]'.
+    #[structopt(long, number_of_values = 1)]
+    filter: Option<Vec<config::Filter>>,
+
+    /// Only apply fan duty changes smaller than this value
+    ///
+    /// If a calculated new fan duty is within this distance to the current fan change will not
+    /// be applied, unless a requested change is requested for too long. See
+    /// `--max-unchanged-cycles'. Defaults to 0.
+    #[structopt(long)]
+    min_fan_change: Option<config::Located<f64>>,
+    /// Maximum number of consequtive fan duty changes to ignore
+    ///
+    /// When the target fan duty has remained unchanged for the last <max-unchanged-cycles>
+    /// cycles and a change has been continually requested in that period, it is applied even if
+    /// it falls below the <min-fan-change>.
+    ///
+    /// Only effective with `min-fan-change > 0'. Defaults to 10.
+    #[structopt(long)]
+    max_unchanged_cycles: Option<usize>,
+
+    /// Apply temperature hysteresis to the fan curve
+    ///
+    /// The fan duty is only lowered once the temperature has fallen <hysteresis> degrees
+    /// Celsius below the temperature at which it was last changed. This keeps the fan from
+    /// pumping up and down when the temperature hovers around a point where the policy changes
+    /// the fan duty.
+    #[structopt(long)]
+    hysteresis: Option<config::Located<f64>>,
+    /// Temperature rise needed to increase the fan duty, in degrees Celsius
+    ///
+    /// The fan duty is only raised once the temperature has risen this far above the
+    /// temperature at which it was last changed.
+    ///
+    /// Only effective when using `--hysteresis'. Defaults to 0.
+    #[structopt(long)]
+    hysteresis_rise: Option<config::Located<f64>>,
+
+    /// Monitor temperature and fan duty curves
+    ///
+    /// Prints the current temperature, the preprocessed temperature (if any preprocessing
+    /// option was selected) and the resulting fan duty to stdout in each cycle. The curve of
+    /// each of these values is visualized using ASCII-plotting, using the '#'-character.
+    #[structopt(long)]
+    monitor: bool,
+    /// Unit of temperatures printed by `--monitor', one of "celsius", "fahrenheit" or "kelvin"
+    ///
+    /// Defaults to "celsius". Temperatures in options and the configuration file are always in
+    /// degrees Celsius.
+    #[structopt(long)]
+    units: Option<utils::Unit>,
+    /// Show a full-screen dashboard
+    ///
+    /// The dashboard shows charts of the raw and smoothed temperature, the target and applied fan
+    /// duty and the fan speed, along with the active policy and the errors, that would otherwise
+    /// be reported to stderr. Press `q' to quit. Only read at startup.
+    #[structopt(long, conflicts_with = "monitor")]
+    tui: bool,
+
+    /// What to leave the fan at when exiting
+    ///
+    /// Either "auto", to let the EC firmware manage the fan again, or a fixed, safe fan duty,
+    /// in percent. Defaults to "auto".
+    #[structopt(long)]
+    on_exit: Option<fan::Handover>,
+
+    /// Listen for `clevo-fan ctl' on this Unix socket
+    ///
+    /// Only the user running `clevo-fan auto' can connect to it. Only read at startup. Defaults
+    /// to /run/clevo-fan.sock.
+    #[structopt(long)]
+    socket: Option<PathBuf>,
+    /// Start with a profile from the configuration file
+    ///
+    /// See `clevo-fan ctl profile'.
+    #[structopt(long)]
+    #[serde(skip)]
+    profile: Option<String>,
+    /// Write each cycle to a CSV file, to be used with `clevo-fan replay'
+    ///
+    /// Each row holds the time, the raw registers, the sensors of the kernel the fans are based on
+    /// at startup, the smoothed temperatures and the target and applied duty of each fan. An
+    /// existing file is overwritten.
+    #[structopt(long)]
+    #[serde(skip)]
+    record: Option<PathBuf>,
+
+    /// Control this fan, either "cpu", "gpu" or its number
+    ///
+    /// Can be given multiple times, all fans follow the same policy. Fans with a `[fans.<id>]'
+    /// table in the configuration file are controlled as well, using the policy and input given
+    /// there. Defaults to the CPU fan.
+    #[structopt(long, number_of_values = 1)]
+    fan: Option<Vec<fan::Id>>,
+    /// Temperature to base the fan duty on, one of "cpu", "gpu", "max" or a sensor of the kernel
+    ///
+    /// "max" uses the higher one of the CPU and the GPU temperature as read from the EC. Sensors
+    /// of the kernel are read from sysfs, see `--sysfs-root', and are usually more precise and
+    /// more up to date than the EC. They are given as "hwmon:<chip>[:<sensor>]", e.g.
+    /// "hwmon:coretemp" or "hwmon:k10temp:Tctl", with the sensor given by its label or as
+    /// "temp<n>" and defaulting to the first one, or as "thermal:<zone>", with the thermal zone
+    /// given by its type (e.g. "x86_pkg_temp") or number.
+    ///
+    /// Several temperatures can be combined, as "max(<input>,...)", e.g. "max(cpu,gpu)", or as a
+    /// weighted average "avg(<input>[=<weight>],...)", e.g. "avg(cpu=2,hwmon:coretemp)", with the
+    /// weight defaulting to 1. Temperatures that cannot be read are left out, only if none of
+    /// them can be read, the worst is assumed. Defaults to "cpu".
+    #[structopt(long)]
+    input: Option<config::Input>,
+
+    /// Duty above which a fan has to spin, in percent
+    ///
+    /// A fan at least at this duty, that does not spin for `--stall-time', is considered stalled.
+    /// It is then reported to stderr, syslog and `--alert-hook' and `--on-stall' kicks in.
+    /// Defaults to the spin-up duty measured by `clevo-fan calibrate', else to 40.
+    #[structopt(long, parse(try_from_str = fan::Duty::from_percentage_str))]
+    stall_min_duty: Option<fan::Duty>,
+    /// How long a fan has to be too slow to be considered stalled, in seconds
+    ///
+    /// Defaults to 10.
+    #[structopt(long)]
+    stall_time: Option<config::Located<u64>>,
+    /// Speed of the fans at full duty, in RPM
+    ///
+    /// If given, a fan is also considered stalled, when it is at less than half the speed
+    /// expected from its duty, assuming the speed is proportional to the duty. Fans calibrated by
+    /// `clevo-fan calibrate' are always checked that way, against their measured speed.
+    #[structopt(long)]
+    fan_max_rpm: Option<u32>,
+    /// Control the fan speed instead of the fan duty
+    ///
+    /// The output of the policy is taken as a target speed, in percent of the maximum speed of
+    /// the fan, e.g. 50% of a fan reaching 5000 RPM is 2500 RPM. The duty is then adjusted each
+    /// cycle, until the speed read back from the EC matches. Target speeds below the lowest speed
+    /// of the fan, other than 0, are raised to it. The speed range of each fan is taken from
+    /// `clevo-fan calibrate', or else from `--fan-max-rpm'. Overrides and `--on-stall' still set
+    /// a fixed duty.
+    #[structopt(long)]
+    rpm: bool,
+    /// What to do with a stalled fan, until it recovers
+    ///
+    /// Either "none", to keep following the policy, or a fixed fan duty, in percent, e.g. to
+    /// unstick the fan. This takes precedence over `clevo-fan ctl override'. Defaults to 100.
+    #[structopt(long)]
+    on_stall: Option<config::StallAction>,
+    /// Run this shell command when a fan stalls or recovers
+    ///
+    /// The environment variables CLEVO_FAN_EVENT (`stall' or `recover'), CLEVO_FAN_ID and
+    /// CLEVO_FAN_MESSAGE describe the event. The command runs in the background.
+    #[structopt(long)]
+    alert_hook: Option<String>,
+}
+
 #[derive(Debug, StructOpt)]
 struct ShowValues {
     /// Print all available values, except gpu_temp
@@ -147,11 +662,22 @@ struct ShowValues {
     #[structopt(long, short = "g")]
     gpu_temp: bool,
     #[structopt(long, short = "f")]
-    /// Print level of the fan, in percent
+    /// Print level of the fans, in percent
     fan_duty: bool,
     #[structopt(long, short = "r")]
-    /// Print speed of the fan, in rounds per minute (RPM)
+    /// Print speed of the fans, in rounds per minute (RPM)
     fan_speed: bool,
+    /// Only print values of this fan, either "cpu", "gpu" or its number
+    ///
+    /// Can be given multiple times. Defaults to all fans.
+    #[structopt(long = "fan", number_of_values = 1)]
+    fans: Vec<fan::Id>,
+    /// Also print the temperature of this sensor of the kernel, in degrees Celsius
+    ///
+    /// Given like `--input' of `clevo-fan auto', e.g. "hwmon:coretemp" or "thermal:x86_pkg_temp".
+    /// Can be given multiple times.
+    #[structopt(long = "sensor", number_of_values = 1)]
+    sensors: Vec<sensor::Source>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -162,9 +688,43 @@ struct ShowOptions {
     /// Hide value units
     #[structopt(long, short = "u")]
     hide_units: bool,
+    /// Unit of temperatures, one of "celsius", "fahrenheit" or "kelvin"
+    #[structopt(long, default_value = "celsius")]
+    units: utils::Unit,
+    /// Output format, one of "text", "json" or "csv"
+    ///
+    /// JSON and CSV always contain every value, with the unit as part of the key and along with
+    /// the raw register bytes it was decoded from, e.g. `fan_duty_percent' and `fan_duty_raw'.
+    /// On models with multiple fans, the values of the fans are numbered, e.g. `fan2_duty_raw'.
+    /// JSON output is one object per line, CSV output starts with a header row.
+    #[structopt(long, default_value = "text")]
+    format: show::Format,
+
+    /// Keep printing the values, one line per sample, until interrupted
+    ///
+    /// The EC interface is kept open and read again every <interval>. A failed read is reported
+    /// to stderr and skips the sample.
+    #[structopt(long, short = "w")]
+    watch: bool,
+    /// Time between two samples, e.g. "250ms" or "2s"
+    ///
+    /// Only effective with `--watch'.
+    #[structopt(long, default_value = "1s")]
+    interval: humantime::Duration,
+    /// Stop after this many samples
+    ///
+    /// Implies `--watch'.
+    #[structopt(long, short = "n")]
+    count: Option<usize>,
+    /// Overwrite the previous sample instead of printing a new line
+    ///
+    /// Only effective with `--watch' and the text format.
+    #[structopt(long)]
+    in_place: bool,
 }
 
-#[derive(Debug, StructOpt)]
+#[derive(Debug, Clone, Default, StructOpt, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
 struct Policies {
     /// Determine fan duty as a linear function of the core temperature
     ///
@@ -172,59 +732,104 @@ struct Policies {
     /// controlled via the `--linear-*' options.
     ///
     /// This is more intended as a proof-of-concept, as it is not actually a very smart policy.
-    #[structopt(long,
-                required_unless_one(&["exp", "square"]),
-                conflicts_with_all(&["exp", "square"]))]
-    linear: bool,
+    #[structopt(
+        long,
+        parse(from_flag = config::Located::from),
+        conflicts_with_all(&["exp", "square", "curve", "pid"])
+    )]
+    linear: config::Located<bool>,
     /// Set slope of the fan duty function
     ///
-    /// Only effective when using the linear policy.
-    #[structopt(long, default_value = "1.0")]
-    linear_slope: f64,
+    /// Only effective when using the linear policy. Defaults to 1.
+    #[structopt(long)]
+    linear_slope: Option<f64>,
     /// Set y-axis offset of the fan duty function
     ///
-    /// Only effective when using the linear policy.
-    #[structopt(long, default_value = "0.0")]
-    linear_offset: f64,
+    /// Only effective when using the linear policy. Defaults to 0.
+    #[structopt(long)]
+    linear_offset: Option<f64>,
 
     /// Determine fan duty as an exponential function of the core temperature
     ///
     /// The function looks like `duty(temp) = factor * base^temp. The base and factor can be
     /// controlled via the `--exp-*' options. For the `base^temp` part, the builtin exponential
     /// functions are used, not actual exponentiation, see `--exp-base' for details.
-    #[structopt(long,
-                required_unless_one(&["linear", "square"]),
-                conflicts_with_all(&["linear", "square"]))]
-    exp: bool,
+    #[structopt(
+        long,
+        parse(from_flag = config::Located::from),
+        conflicts_with_all(&["linear", "square", "curve", "pid"])
+    )]
+    exp: config::Located<bool>,
     /// Set base of the fan duty function
     ///
     /// "e" designates the natural (using `std::f64::exp') and "2" the binary exponential function
     /// (using `std::f64::exp2').
     ///
-    /// Only effective when using the exponential policy.
-    #[structopt(long, default_value = "e",
-                possible_values(&["2", "e"]))]
-    exp_base: fan::policy::ExponentialBase,
+    /// Only effective when using the exponential policy. Defaults to "e".
+    #[structopt(long, possible_values(&["2", "e"]))]
+    exp_base: Option<fan::policy::ExponentialBase>,
     /// Set fan duty factor for exponential function
     ///
-    /// Only effective when using the exponential policy.
-    #[structopt(long, default_value = "1")]
-    exp_factor: f64,
+    /// Only effective when using the exponential policy. Defaults to 1.
+    #[structopt(long)]
+    exp_factor: Option<f64>,
 
     /// Determine fan duty as a quadratic function of the core temperature
     ///
     /// The function looks like this `duty(temp) = factor * temp^2'. The factor can be controlled
     /// via the `--factor' option.
-    #[structopt(long,
-                required_unless_one(&["linear", "exp"]),
-                conflicts_with_all(&["linear", "exp"]))]
-    square: bool,
+    #[structopt(
+        long,
+        parse(from_flag = config::Located::from),
+        conflicts_with_all(&["linear", "exp", "curve", "pid"])
+    )]
+    square: config::Located<bool>,
 
     /// Set fan duty factor for square function
     ///
-    /// Only effective when using the square policy.
-    #[structopt(long, default_value = "0.01")]
-    square_factor: f64,
+    /// Only effective when using the square policy. Defaults to 0.01.
+    #[structopt(long)]
+    square_factor: Option<f64>,
+
+    /// Determine fan duty by interpolating between points of a fan curve
+    ///
+    /// The curve is given as a comma-separated list of `<temperature>:<duty>' points, with the
+    /// temperature in degrees Celsius and the duty in percent, e.g. `50:0,60:40,75:70,85:100'.
+    /// Between two points, the fan duty is interpolated linearly. Below the first point, the fan
+    /// duty of the first point is used, above the last point that of the last point.
+    ///
+    /// Instead of the points, the name of a curve defined in the configuration file can be given.
+    #[structopt(long, conflicts_with_all(&["linear", "exp", "square", "pid"]))]
+    curve: Option<config::Located<String>>,
+
+    /// Determine fan duty with a PID controller, keeping the core temperature at <pid> degrees
+    ///
+    /// In contrast to the other policies, the fan duty does not only depend on the current
+    /// temperature, but also on its history: The longer and the further the temperature stays
+    /// above the setpoint, the higher the fan duty gets, and vice versa. The gains can be
+    /// controlled via the `--pid-*' options.
+    #[structopt(long, conflicts_with_all(&["linear", "exp", "square", "curve"]))]
+    pid: Option<config::Located<f64>>,
+    /// Set proportional gain of the PID controller, in percent per degree
+    ///
+    /// Only effective when using the PID policy. Defaults to 4.
+    #[structopt(long)]
+    pid_kp: Option<config::Located<f64>>,
+    /// Set integral gain of the PID controller, in percent per degree and second
+    ///
+    /// Only effective when using the PID policy. Defaults to 0.2.
+    #[structopt(long)]
+    pid_ki: Option<config::Located<f64>>,
+    /// Set derivative gain of the PID controller, in percent per degree per second
+    ///
+    /// Only effective when using the PID policy. Defaults to 0.
+    #[structopt(long)]
+    pid_kd: Option<config::Located<f64>>,
+    /// Set minimum fan duty of the PID controller, in percent
+    ///
+    /// Only effective when using the PID policy. Defaults to 0.
+    #[structopt(long, parse(try_from_str = fan::Duty::from_percentage_str))]
+    pid_min_duty: Option<fan::Duty>,
 }
 
 impl App {
@@ -240,6 +845,8 @@ impl App {
                     ShowValues {
                         _all: all,
                         gpu_temp,
+                        fans,
+                        sensors,
                         ..
                     },
                 options,
@@ -251,6 +858,8 @@ impl App {
                         fan_duty: true,
                         fan_speed: true,
                         gpu_temp,
+                        fans,
+                        sensors,
                     },
                     options,
                 }
@@ -266,195 +875,326 @@ impl Command {
     fn run(self, general_options: &Options) -> MainResult {
         match self {
             Command::Show { values, options } => {
-                let mut ec = fs::OpenOptions::new()
-                    .read(true)
-                    .open(&general_options.ec_path)?;
-                let ec = ec::Registers::try_from(&mut ec as &mut dyn io::Read)?;
-
-                let values: [(_, &dyn fmt::Display, _); 4] = [
-                    (values.cpu_temp, &ec.cpu_temp, "CPU Temp"),
-                    (values.gpu_temp, &ec.gpu_temp, "GPU Temp"),
-                    (values.fan_duty, &ec.fan_duty, "Fan Duty"),
-                    (values.fan_speed, &ec.fan_speed, "Fan Speed"),
-                ];
-                for (should_print, value, label) in values.iter() {
-                    if *should_print {
-                        if !options.hide_labels {
-                            write!(io::stdout(), "{}: ", label)?;
-                        }
-                        if options.hide_units {
-                            writeln!(io::stdout(), "{:#}", value)?;
-                        } else {
-                            writeln!(io::stdout(), "{}", value)?;
+                let mut ec = general_options.open_ec()?;
+                for id in &values.fans {
+                    ec.model().fan(*id)?;
+                }
+                let mut sensors = general_options.sensors();
+                let sources = values.sensors.clone();
+                let mut read_sensors = || -> Result<Vec<_>, sensor::Error> {
+                    sources
+                        .iter()
+                        .map(|source| Ok((source.clone(), sensors.read(source)?)))
+                        .collect()
+                };
+                if !options.watch && options.count.is_none() {
+                    return show::Printer::new(values, options)?
+                        .print(&ec.registers()?, &read_sensors()?);
+                }
+
+                let interval: Duration = options.interval.into();
+                let count = options.count;
+                let mut printer = show::Printer::new(values, options)?;
+                let signals = shutdown::Signals::register()?;
+                let mut samples = 0;
+                while !signals.terminate_requested() && count.map_or(true, |count| samples < count)
+                {
+                    let started = Instant::now();
+                    // A failed read only loses this sample, the next one may well succeed
+                    let mut sample =
+                        || -> utils::FlexibleResult<_> { Ok((ec.registers()?, read_sensors()?)) };
+                    match sample() {
+                        Ok((registers, readings)) => printer.print(&registers, &readings)?,
+                        Err(err) => writeln!(io::stderr(), "Error: {}", err).ignore(),
+                    }
+                    samples += 1;
+                    thread::sleep(interval.saturating_sub(started.elapsed()));
+                }
+                printer.finish()?;
+            }
+            Command::Set {
+                value: None,
+                rpm: Some(rpm),
+                fans,
+            } => {
+                let calibration = general_options.load_calibration()?;
+                let _lock = general_options.lock_writer()?;
+                let mut fan = fan::Control::new(general_options.open_ec()?);
+                for id in fans_or_cpu(fans) {
+                    fan.ec().model().fan(id)?;
+                    let range = speed::Range::of(calibration.as_ref(), None, id)
+                        .ok_or(speed::Error::UnknownRange(id))?;
+                    let (duty, speed) = speed::reach(&mut fan, id, &range, rpm)?;
+                    writeln!(io::stdout(), "Fan {}: {} at {} duty", id, speed, duty)?;
+                }
+            }
+            Command::Set { value, fans, .. } => {
+                let value = value.expect("Either duty or speed is required");
+                let calibration = general_options.load_calibration()?;
+                let fans = fans_or_cpu(fans);
+                for id in &fans {
+                    match calibration::Calibration::spin_up_duty(calibration.as_ref(), *id) {
+                        Some(spin_up) if value > fan::Duty::min() && value < spin_up => {
+                            writeln!(
+                                io::stderr(),
+                                "Warning: Fan {} only becomes active from {} duty upwards. Setting duty below this may disable the fan entirely.",
+                                id, spin_up
+                            )?;
                         }
+                        _ => (),
                     }
                 }
 
-                if values.iter().all(|(should_print, _, _)| !should_print) {
-                    writeln!(
-                        io::stderr(),
-                        "Warning: No values are being printed, you might want to use `-a'. See `--help' for further information."
-                    )?;
+                let _lock = general_options.lock_writer()?;
+                let mut fan = fan::Control::new(general_options.open_ec()?);
+                for id in fans {
+                    fan.set_duty(id, value)?;
                 }
             }
-            Command::Set { value } => {
-                if value < fan::Duty::from_percentage(37.).unwrap() {
-                    writeln!(
-                        io::stderr(),
-                        "Warning: Fan only becomse active from 38% duty upwards. Setting duty below this will disable the fan entirely."
-                    )?;
-                }
+            Command::Reset { to, fans } => {
+                let _lock = general_options.lock_writer()?;
+                fan::Control::new(general_options.open_ec()?).hand_over(to, &fans_or_cpu(fans))?
+            }
+            Command::Auto { policies, options } => {
+                let load = |profile: Option<&str>| -> Result<_, config::Error> {
+                    let config = match &options.config {
+                        Some(path) => Some(config::File::load(path)?),
+                        None => None,
+                    };
+                    let settings =
+                        config::Settings::resolve(&options, &policies, config.as_ref(), profile)?;
+                    Ok((config, settings))
+                };
+
+                let (config, settings) = load(options.profile.as_deref())?;
+                let general_options = match &config {
+                    Some(config) => general_options.or(config),
+                    None => general_options.clone(),
+                };
 
-                fan::Control::new()?.set_duty(value)?
+                let _lock = general_options.lock_writer()?;
+                let server = ctl::Server::bind(&settings.socket).map_err(|err| {
+                    format!("Cannot listen on {}: {}", settings.socket.display(), err)
+                })?;
+
+                let signals = shutdown::Signals::register()?;
+                let ec = general_options.open_ec()?;
+                for id in settings.fan_ids() {
+                    ec.model().fan(id)?;
+                }
+                let recorder = match &options.record {
+                    Some(path) => Some(
+                        trace::Recorder::create(path, ec.model(), settings.sensors()).map_err(
+                            |err| format!("Cannot record to {}: {}", path.display(), err),
+                        )?,
+                    ),
+                    None => None,
+                };
+                let mut sensors = general_options.sensors();
+                for source in settings.sensors() {
+                    sensors.read(&source)?;
+                }
+                let calibration = general_options.load_calibration()?;
+                if settings.rpm {
+                    for id in settings.fan_ids() {
+                        speed::Range::of(calibration.as_ref(), settings.stall.max_rpm, id)
+                            .ok_or_else(|| {
+                                format!(
+                                    "{}, or give `--fan-max-rpm' to use `--rpm'",
+                                    speed::Error::UnknownRange(id)
+                                )
+                            })?;
+                    }
+                }
+                let fan = fan::Control::new(general_options.open_ec()?);
+                let dashboard = if settings.tui {
+                    Some(tui::Dashboard::new()?)
+                } else {
+                    None
+                };
+                let mut daemon = daemon::Daemon::new(
+                    ec,
+                    sensors,
+                    fan,
+                    settings,
+                    dashboard,
+                    recorder,
+                    calibration,
+                );
+                daemon.run(&signals, &server, |profile| {
+                    load(profile).map(|(_, settings)| settings)
+                });
             }
-            Command::Auto {
+            Command::Replay {
+                trace,
                 policies,
-                polling_interval,
-                moving_average: moving_average_backlog,
-                moving_median: moving_median_backlog,
-                min_fan_change,
-                max_unchanged_cycles,
-                monitor,
+                options,
             } => {
-                let mut ec = fs::OpenOptions::new()
-                    .read(true)
-                    .open(&general_options.ec_path)?;
-
-                let policy: Box<dyn fan::Policy<Input = utils::Temperature>> = if policies.linear {
-                    Box::new(fan::policy::Linear {
-                        slope: policies.linear_slope,
-                        offset: policies.linear_offset,
-                    })
-                } else if policies.exp {
-                    Box::new(fan::policy::Exponential {
-                        base: policies.exp_base,
-                        factor: policies.exp_factor,
-                    })
-                } else if policies.square {
-                    Box::new(fan::policy::Quadratic {
-                        factor: policies.square_factor,
-                    })
-                } else {
-                    unreachable!("This should be handled by structopt")
+                let config = match &options.config {
+                    Some(path) => Some(config::File::load(path)?),
+                    None => None,
+                };
+                let settings = config::Settings::resolve(
+                    &options,
+                    &policies,
+                    config.as_ref(),
+                    options.profile.as_deref(),
+                )?;
+                trace::replay(&trace, &settings)?;
+            }
+            Command::Simulate {
+                plant,
+                policies,
+                options,
+            } => {
+                let config = match &options.config {
+                    Some(path) => Some(config::File::load(path)?),
+                    None => None,
+                };
+                let general_options = match &config {
+                    Some(config) => general_options.or(config),
+                    None => general_options.clone(),
+                };
+                let model = model::Model::select(
+                    general_options.model.as_deref(),
+                    general_options.model_file.as_deref(),
+                    false,
+                )?;
+                let parameters = plant::Parameters {
+                    ambient: plant.ambient,
+                    heat_capacity: plant.heat_capacity,
+                    passive_cooling: plant.passive_cooling,
+                    fan_cooling: plant.fan_cooling,
                 };
 
-                let mut fan = fan::Control::new()?;
+                let mut profiles = vec![options.profile.clone()];
+                if plant.compare_profiles {
+                    let config = config
+                        .as_ref()
+                        .ok_or("`--compare-profiles' needs a configuration file")?;
+                    profiles.extend(config.profiles().map(|name| Some(name.to_owned())));
+                }
 
-                if monitor {
-                    write!(io::stdout(), "{:46} ", "CPU Temperature").ignore();
-                    if moving_average_backlog.is_some() {
-                        write!(io::stdout(), "{:46} ", "Running Average").ignore();
-                    } else if moving_median_backlog.is_some() {
-                        write!(io::stdout(), "{:46} ", "Running Median").ignore();
-                    }
-                    if min_fan_change > 0.0 {
-                        write!(io::stdout(), "{:56} ", "Fan Duty").ignore();
+                let mut statistics = Vec::new();
+                for profile in profiles {
+                    let settings = config::Settings::resolve(
+                        &options,
+                        &policies,
+                        config.as_ref(),
+                        profile.as_deref(),
+                    )?;
+                    for id in settings.fan_ids() {
+                        model.fan(id)?;
                     }
-                    writeln!(io::stdout(), "{} ", "Fan Duty").ignore();
+                    statistics.push(plant::simulate(
+                        profile.unwrap_or_else(|| "default".to_owned()),
+                        &settings,
+                        &parameters,
+                        &plant.load,
+                        &model,
+                    )?);
                 }
-
-                macro_rules! visualize {
-                    ($value: expr, $raw: expr, $min: expr, $max: expr) => {{
-                        if monitor {
-                            write!(io::stdout(), "{:6} ", $value).ignore();
-                            let mut bar = String::new();
-                            for _ in $min..$raw {
-                                bar.push('#');
-                            }
-                            write!(io::stdout(), "{:width$}", bar, width = $max - $min).ignore();
+                plant::print_statistics(&statistics)?;
+            }
+            Command::Ctl { socket, command } => {
+                let request = match command {
+                    CtlCommand::Status => ctl::Request::Status,
+                    CtlCommand::Override { duty, duration } => ctl::Request::Override(
+                        match duty.as_str() {
+                            "off" => None,
+                            _ => Some(fan::Duty::from_percentage_str(&duty)?),
+                        },
+                        duration.map(Into::into),
+                    ),
+                    CtlCommand::Profile { name } => ctl::Request::Profile(name),
+                };
+                write!(io::stdout(), "{}", ctl::request(&socket, &request)?)?;
+            }
+            Command::Ec { command } => {
+                let mut ec = general_options.open_ec()?;
+                match command {
+                    EcCommand::Dump => dump::print_dump(&ec.read()?, ec.model())?,
+                    EcCommand::Diff { interval } => {
+                        let interval: Duration = interval.into();
+                        let signals = shutdown::Signals::register()?;
+                        let started = Instant::now();
+                        let mut previous = ec.read()?;
+                        while !signals.terminate_requested() {
+                            thread::sleep(interval);
+                            let current = ec.read()?;
+                            dump::print_diff(started.elapsed(), &previous, &current, ec.model())?;
+                            previous = current;
                         }
-                        $value
-                    }};
+                    }
                 }
+            }
+            Command::Calibrate { fans, step, settle } => {
+                if !(step > 0.0 && step <= 100.0) {
+                    return Err(format!("Invalid step {}, expected 0 < step <= 100", step).into());
+                }
+                let path = general_options.calibration_path();
+                let mut calibration = general_options.load_calibration()?.unwrap_or_default();
 
-                // Infinite iterator. All errors are handeled, this will /never/ fail.
-                let temp_curve = iter::repeat_with(|| {
-                    ec.seek(io::SeekFrom::Start(0))?;
-                    let ec = ec::Registers::try_from(&mut ec as &mut dyn io::Read)?;
-                    Ok(ec.cpu_temp)
-                })
-                .map(|res: Result<_, io::Error>| {
-                    res.unwrap_or_else(|err| {
-                        writeln!(
-                            io::stderr(),
-                            "Error: Cannot read temperature: {}, assuming the worst",
-                            err
-                        )
-                        .ignore();
-                        utils::Temperature::max()
-                    })
-                })
-                .map(|temp| visualize!(temp, temp.as_degrees_celsius() as usize, 50, 90));
-
-                let normalized_temp_curve: Box<dyn Iterator<Item = utils::Temperature>> =
-                    if let Some(backlog) = moving_median_backlog {
-                        Box::new(temp_curve.moving_median(backlog))
-                    } else if let Some(backlog) = moving_average_backlog {
-                        Box::new(temp_curve.moving_average(backlog))
-                    } else {
-                        Box::new(temp_curve)
-                    };
-
-                let normalized_temp_curve: Box<dyn Iterator<Item = utils::Temperature>> =
-                    if moving_median_backlog.or(moving_average_backlog).is_some() {
-                        Box::new(normalized_temp_curve.map(|temp| {
-                            visualize!(temp, temp.as_degrees_celsius() as usize, 50, 90)
-                        }))
-                    } else {
-                        normalized_temp_curve
-                    };
-
-                let mut current_fan_duty = fan::Duty::min();
-                let mut last_target_fan_duty = fan::Duty::min();
-                let mut unchanged_cycles = 0;
-                let normalized_fan_duty_curve = normalized_temp_curve
-                    .map(|temp| policy.next_fan_duty(temp))
-                    .map(|duty| visualize!(duty, duty.as_percentage() as usize, 30, 80))
-                    .map(|duty| {
-                        let change_requested =
-                            (duty.as_percentage() - current_fan_duty.as_percentage()).abs() > 1.0;
-                        let changed = (duty.as_percentage() - last_target_fan_duty.as_percentage())
-                            .abs()
-                            <= 1.0;
-                        if change_requested && changed {
-                            unchanged_cycles += 1;
+                let _lock = general_options.lock_writer()?;
+                let signals = shutdown::Signals::register()?;
+                let mut ec = general_options.open_ec()?;
+                let fans = if fans.is_empty() {
+                    // A fan, that the model has a register for, may still not exist, so only
+                    // calibrate fans that are running
+                    let registers = ec.registers()?;
+                    let mut running = Vec::new();
+                    for (id, fan) in fan::Id::all().zip(&registers.fans) {
+                        if fan.speed.as_rpm() > 0 {
+                            running.push(id);
                         } else {
-                            last_target_fan_duty = duty;
-                            unchanged_cycles = 0;
+                            writeln!(
+                                io::stderr(),
+                                "Skipping fan {}, as it reports no speed, use `--fan {}' to calibrate it anyway",
+                                id, id
+                            )?;
                         }
-                        if (duty.as_percentage() - current_fan_duty.as_percentage()).abs()
-                            > min_fan_change
-                            || unchanged_cycles > max_unchanged_cycles
-                        {
-                            current_fan_duty = duty;
-                            duty
-                        } else {
-                            current_fan_duty
-                        }
-                    });
-
-                let normalized_fan_duty_curve: Box<dyn Iterator<Item = fan::Duty>> =
-                    if min_fan_change > 0.0 {
-                        Box::new(
-                            normalized_fan_duty_curve.map(|duty| {
-                                visualize!(duty, duty.as_percentage() as usize, 30, 30)
-                            }),
-                        )
-                    } else {
-                        Box::new(normalized_fan_duty_curve)
-                    };
-
-                normalized_fan_duty_curve.for_each(|duty| {
-                    fan.set_duty(duty).unwrap_or_else(|err| {
-                        writeln!(io::stderr(), "Error: Cannot set fan duty: {}", err).ignore()
-                    });
+                    }
+                    if running.is_empty() {
+                        return Err(
+                            "No fan reports a speed, select the fans to calibrate with `--fan'"
+                                .into(),
+                        );
+                    }
+                    running
+                } else {
+                    for id in &fans {
+                        ec.model().fan(*id)?;
+                    }
+                    fans
+                };
+                let mut fan = shutdown::Guard::new(
+                    fan::Control::new(general_options.open_ec()?),
+                    fan::Handover::Firmware,
+                    fans.clone(),
+                );
 
-                    if monitor {
-                        writeln!(io::stdout()).ignore()
-                    };
+                for id in fans {
+                    let result = calibration::calibrate(
+                        &mut fan,
+                        &mut *ec,
+                        &signals,
+                        id,
+                        step,
+                        settle.into(),
+                    )?;
+                    writeln!(
+                        io::stdout(),
+                        "Fan {}: starts at {}, stops below {}, {} RPM at full duty",
+                        id,
+                        optional_duty(result.spin_up_duty),
+                        optional_duty(result.stop_duty),
+                        result.max_rpm
+                    )?;
+                    calibration.fans.insert(id, result);
+                }
 
-                    thread::sleep(Duration::from_millis(polling_interval));
-                });
+                calibration.save(&path)?;
+                writeln!(io::stdout(), "Saved calibration to {}", path.display())?;
             }
         }
 
@@ -462,6 +1202,21 @@ impl Command {
     }
 }
 
-fn main() -> MainResult {
-    App::from_args().post_process().run()
+fn optional_duty(duty: Option<fan::Duty>) -> String {
+    duty.map_or("never".to_owned(), |duty| duty.to_string())
+}
+
+fn fans_or_cpu(fans: Vec<fan::Id>) -> Vec<fan::Id> {
+    if fans.is_empty() {
+        vec![fan::Id::CPU]
+    } else {
+        fans
+    }
+}
+
+fn main() {
+    if let Err(err) = App::from_args().post_process().run() {
+        writeln!(io::stderr(), "Error: {}", err).ignore();
+        process::exit(1);
+    }
 }
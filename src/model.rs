@@ -0,0 +1,162 @@
+use crate::{ec, fan};
+use derive_more::Display;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Where the kernel exposes the name of the mainboard
+pub const DMI_BOARD_NAME_PATH: &str = "/sys/class/dmi/id/board_name";
+
+/// Model used when the board is not known
+const DEFAULT_MODEL: &str = "clevo";
+
+const BUILTIN_MODELS: &str = include_str!("models.toml");
+
+/// Where a model keeps its values in the EC and how its fans are controlled
+///
+/// Register maps are read from TOML, with one table per model, named like the model. See
+/// `models.toml' for the built-in ones.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Model {
+    #[serde(skip)]
+    pub name: String,
+    /// DMI board names of the machines, this is the register map of
+    #[serde(default)]
+    boards: Vec<String>,
+    /// Register holding the CPU temperature, in degrees Celsius
+    pub cpu_temp: u8,
+    /// Register holding the GPU temperature, in degrees Celsius
+    pub gpu_temp: u8,
+    /// Command to set the duty of a fan, sent with the port of the fan
+    pub fan_control_cmd: u8,
+    /// Port of `fan_control_cmd', that hands all fans back to the EC firmware
+    pub fan_auto_port: u8,
+    /// Dividend to get the fan speed in RPM from the raw speed registers
+    pub rpm_magic: u32,
+    /// Registers of each fan, ordered by `fan::Id`
+    pub fans: Vec<FanMap>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FanMap {
+    /// Register holding the current duty, from 0 to 255
+    pub duty: u8,
+    /// Register holding the high byte of the raw fan speed
+    pub rpm_hi: u8,
+    /// Register holding the low byte of the raw fan speed
+    pub rpm_lo: u8,
+    /// Port to send `fan_control_cmd' to, to set the duty of this fan
+    pub port: u8,
+}
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "Cannot read register maps {}: {}", "_0.display()", _1)]
+    Read(PathBuf, io::Error),
+    #[display(fmt = "Invalid register maps {}: {}", "_0.display()", _1)]
+    Parse(PathBuf, toml::de::Error),
+    #[display(fmt = "Invalid register map of `{}': {}", _0, _1)]
+    Invalid(String, String),
+    #[display(fmt = "Unknown model `{}', expected one of {}", _0, _1)]
+    Unknown(String, String),
+}
+impl error::Error for Error {}
+
+impl Model {
+    /// Pick the register map to use
+    ///
+    /// Models from `file' take precedence over the built-in ones. Without `name', the model is
+    /// chosen by the DMI board name, unless `detect' is false. If nothing matches, the default
+    /// model is used.
+    pub fn select(name: Option<&str>, file: Option<&Path>, detect: bool) -> Result<Self, Error> {
+        let mut models = Self::parse(BUILTIN_MODELS)
+            .unwrap_or_else(|err| panic!("Invalid built-in register maps: {}", err));
+        if let Some(path) = file {
+            let source =
+                fs::read_to_string(path).map_err(|err| Error::Read(path.to_owned(), err))?;
+            let mut user =
+                Self::parse(&source).map_err(|err| Error::Parse(path.to_owned(), err))?;
+            user.append(&mut models);
+            models = user;
+        }
+
+        let model = match name {
+            Some(name) => match models.iter().position(|model| model.name == name) {
+                Some(position) => Ok(models.swap_remove(position)),
+                None => Err(Error::Unknown(name.to_owned(), Self::list(&models))),
+            },
+            None => {
+                let board = if detect {
+                    fs::read_to_string(DMI_BOARD_NAME_PATH)
+                        .map(|board| board.trim().to_owned())
+                        .ok()
+                } else {
+                    None
+                };
+                let default = models
+                    .iter()
+                    .position(|model| model.name == DEFAULT_MODEL)
+                    .expect("The default model is built in");
+                let position = board
+                    .and_then(|board| {
+                        models
+                            .iter()
+                            .position(|model| model.boards.contains(&board))
+                    })
+                    .unwrap_or(default);
+                Ok(models.swap_remove(position))
+            }
+        }?;
+
+        model.validate()?;
+        Ok(model)
+    }
+
+    fn parse(source: &str) -> Result<Vec<Self>, toml::de::Error> {
+        let models: BTreeMap<String, Model> = toml::from_str(source)?;
+        Ok(models
+            .into_iter()
+            .map(|(name, model)| Model { name, ..model })
+            .collect())
+    }
+
+    fn list(models: &[Self]) -> String {
+        let names: BTreeSet<_> = models.iter().map(|model| &model.name).collect();
+        names
+            .into_iter()
+            .map(|name| format!("`{}'", name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::Invalid(self.name.clone(), message));
+        if self.fans.is_empty() || self.fans.len() > ec::FAN_COUNT {
+            return invalid(format!(
+                "Expected between 1 and {} fans, got {}",
+                ec::FAN_COUNT,
+                self.fans.len()
+            ));
+        }
+        if self.rpm_magic == 0 {
+            return invalid("`rpm-magic' must not be 0".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Registers of the given fan, if the model has that fan
+    pub fn fan(&self, id: fan::Id) -> Result<&FanMap, ec::Error> {
+        self.fans.get(id.index()).ok_or(ec::Error::NoSuchFan(id))
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
@@ -0,0 +1,461 @@
+use crate::{config, daemon, ec, fan, model, sensor, speed, utils};
+use derive_more::Display;
+use std::{
+    cell::RefCell,
+    error, fmt,
+    io::{self, Write},
+    rc::Rc,
+    str::FromStr,
+    time::Duration,
+};
+
+/// Fan speed at full duty, as reported by the simulated EC
+const MAX_FAN_RPM: f64 = 5000.0;
+
+/// Heat put into the plant over time, as consecutive phases of constant power
+#[derive(Debug, Clone)]
+pub struct LoadProfile(Vec<(Duration, f64)>);
+
+#[derive(Debug, Display)]
+#[display(
+    fmt = "Invalid load profile `{}', expected <duration>:<watts>,... (e.g. \"1m:5,3m:45\")",
+    _0
+)]
+pub struct InvalidLoadProfile(String);
+impl error::Error for InvalidLoadProfile {}
+
+impl FromStr for LoadProfile {
+    type Err = InvalidLoadProfile;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidLoadProfile(s.to_owned());
+        let phases = s
+            .split(',')
+            .map(|phase| {
+                let (duration, power) = phase.split_once(':').ok_or_else(invalid)?;
+                let duration = humantime::parse_duration(duration.trim()).map_err(|_| invalid())?;
+                let power = f64::from_str(power.trim()).map_err(|_| invalid())?;
+                if !power.is_finite() || power < 0.0 || duration.is_zero() {
+                    return Err(invalid());
+                }
+                Ok((duration, power))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(LoadProfile(phases))
+    }
+}
+
+impl LoadProfile {
+    fn duration(&self) -> Duration {
+        self.0.iter().map(|(duration, _)| *duration).sum()
+    }
+
+    /// Power of the phase at the given time, after the end, that of the last phase
+    fn power_at(&self, time: Duration) -> f64 {
+        let mut end = Duration::from_secs(0);
+        for (duration, power) in &self.0 {
+            end += *duration;
+            if time < end {
+                return *power;
+            }
+        }
+        self.0.last().map_or(0.0, |(_, power)| *power)
+    }
+}
+
+/// Thermal behaviour of the simulated machine
+///
+/// The machine is a single thermal mass, heated by the load and cooled towards the ambient
+/// temperature, passively and by each fan in proportion to its duty. Sensors of the kernel read
+/// the same temperature as the CPU and GPU.
+#[derive(Debug, Clone)]
+pub struct Parameters {
+    pub ambient: f64,
+    /// Energy needed to heat the machine by one degree, in J/K
+    pub heat_capacity: f64,
+    /// Heat dissipated without fans, per degree above ambient, in W/K
+    pub passive_cooling: f64,
+    /// Heat dissipated by each fan at full duty, per degree above ambient, in W/K
+    pub fan_cooling: f64,
+}
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "`--{}' must be greater than 0, got {}", _0, _1)]
+    NotPositive(&'static str, f64),
+    #[display(fmt = "`--{}' must not be negative, got {}", _0, _1)]
+    Negative(&'static str, f64),
+    #[display(fmt = "`--ambient' must be a finite temperature, got {}", _0)]
+    Ambient(f64),
+    #[display(fmt = "The polling interval must be greater than 0")]
+    Interval,
+    #[display(fmt = "{}", _0)]
+    Ec(ec::Error),
+}
+impl error::Error for Error {}
+
+impl Parameters {
+    /// Check that the machine heats up and cools down, without dividing by zero
+    fn validate(&self) -> Result<(), Error> {
+        if !self.ambient.is_finite() {
+            return Err(Error::Ambient(self.ambient));
+        }
+        // The negation also catches NaN
+        if !(self.heat_capacity > 0.0 && self.heat_capacity.is_finite()) {
+            return Err(Error::NotPositive("heat-capacity", self.heat_capacity));
+        }
+        if !(self.passive_cooling > 0.0 && self.passive_cooling.is_finite()) {
+            return Err(Error::NotPositive("passive-cooling", self.passive_cooling));
+        }
+        if !(self.fan_cooling >= 0.0 && self.fan_cooling.is_finite()) {
+            return Err(Error::Negative("fan-cooling", self.fan_cooling));
+        }
+        Ok(())
+    }
+}
+
+struct Plant {
+    parameters: Parameters,
+    temp: f64,
+    registers: [u8; ec::EC_REG_SIZE],
+}
+
+impl Plant {
+    /// Let the given time pass, under the given load
+    fn advance(&mut self, model: &model::Model, power: f64, time: Duration) {
+        let duties: f64 = model
+            .fans
+            .iter()
+            .map(|fan| self.registers[fan.duty as usize] as f64 / 255.0)
+            .sum();
+        let conductance = self.parameters.passive_cooling + self.parameters.fan_cooling * duties;
+
+        // Solve the linear ODE exactly, so that long polling intervals stay stable
+        let steady = self.parameters.ambient + power / conductance;
+        let decay = (-conductance / self.parameters.heat_capacity * time.as_secs_f64()).exp();
+        self.temp = steady + (self.temp - steady) * decay;
+    }
+
+    fn update_registers(&mut self, model: &model::Model) {
+        let temp = self.temp.round().clamp(0.0, 255.0) as u8;
+        self.registers[model.cpu_temp as usize] = temp;
+        self.registers[model.gpu_temp as usize] = temp;
+
+        for fan in &model.fans {
+            let rpm = self.registers[fan.duty as usize] as f64 / 255.0 * MAX_FAN_RPM;
+            let raw = if rpm > 0.0 {
+                (model.rpm_magic as f64 / rpm).min(u16::MAX as f64) as u16
+            } else {
+                0
+            };
+            self.registers[fan.rpm_hi as usize] = (raw >> 8) as u8;
+            self.registers[fan.rpm_lo as usize] = raw as u8;
+        }
+    }
+}
+
+/// EC of the simulated machine, which can be shared with `fan::Control`
+#[derive(Clone)]
+struct Ec {
+    plant: Rc<RefCell<Plant>>,
+    model: Rc<model::Model>,
+}
+
+impl ec::Backend for Ec {
+    fn read(&mut self) -> Result<[u8; ec::EC_REG_SIZE], ec::Error> {
+        let mut plant = self.plant.borrow_mut();
+        plant.update_registers(&self.model);
+        Ok(plant.registers)
+    }
+
+    fn write(&mut self, cmd: u8, port: u8, value: u8) -> Result<(), ec::Error> {
+        if cmd != self.model.fan_control_cmd {
+            return Err(ec::Error::UnsupportedCommand(cmd, port));
+        }
+        match self.model.fans.iter().find(|fan| fan.port == port) {
+            Some(fan) => {
+                self.plant.borrow_mut().registers[fan.duty as usize] = value;
+                Ok(())
+            }
+            // There is no firmware, the fans just stay as they are
+            None if port == self.model.fan_auto_port => Ok(()),
+            None => Err(ec::Error::UnsupportedCommand(cmd, port)),
+        }
+    }
+
+    fn model(&self) -> &model::Model {
+        &self.model
+    }
+}
+
+/// How a configuration performed in a simulation
+pub struct Statistics {
+    pub name: String,
+    /// Highest temperature reached, in degrees Celsius
+    pub peak_temp: f64,
+    pub mean_temp: f64,
+    pub fans: Vec<FanStatistics>,
+}
+
+pub struct FanStatistics {
+    pub id: fan::Id,
+    /// Variance of the applied duty, in percent squared
+    pub duty_variance: f64,
+    /// Number of cycles, in which the duty was changed
+    pub changes: usize,
+}
+
+/// Run the fan control loop with the given settings against the simulated machine
+///
+/// The loop runs in simulated time, one cycle per polling interval, until the load profile
+/// ends. The machine starts at ambient temperature, with all fans off. With `--rpm', the fans
+/// reach their full speed of 5000 RPM at full duty.
+pub fn simulate(
+    name: String,
+    settings: &config::Settings,
+    parameters: &Parameters,
+    load: &LoadProfile,
+    model: &model::Model,
+) -> Result<Statistics, Error> {
+    parameters.validate()?;
+    if settings.polling_interval.is_zero() {
+        return Err(Error::Interval);
+    }
+
+    let mut ec = Ec {
+        plant: Rc::new(RefCell::new(Plant {
+            temp: parameters.ambient,
+            parameters: parameters.clone(),
+            registers: [0; ec::EC_REG_SIZE],
+        })),
+        model: Rc::new(model.clone()),
+    };
+    let mut control = fan::Control::new(Box::new(ec.clone()));
+    let mut pipeline = daemon::Pipeline::new(settings);
+    let range = speed::Range::new(MAX_FAN_RPM as u32);
+    let mut adjusters: Vec<_> = settings
+        .fans
+        .iter()
+        .map(|_| speed::Adjuster::default())
+        .collect();
+
+    let interval = settings.polling_interval;
+    let end = load.duration();
+    let mut time = Duration::from_secs(0);
+    let mut temps = Vec::new();
+    let mut duties: Vec<(fan::Id, Vec<f64>)> = settings
+        .fan_ids()
+        .into_iter()
+        .map(|id| (id, Vec::new()))
+        .collect();
+
+    while time < end {
+        let registers = ec::Backend::registers(&mut ec).map_err(Error::Ec)?;
+        let elapsed = if time.is_zero() {
+            Duration::from_secs(0)
+        } else {
+            interval
+        };
+        // Sensors of the kernel are not limited to whole degrees like the EC
+        let sensor_temp = utils::Temperature::from_degrees_celsius(ec.plant.borrow().temp);
+        let step = pipeline.step(
+            settings,
+            Some(registers.cpu_temp),
+            Some(registers.gpu_temp),
+            &sensor::substitute(settings.sensors(), Some(sensor_temp)),
+            elapsed,
+        );
+        for ((fan, (_, duties)), adjuster) in step.fans.iter().zip(&mut duties).zip(&mut adjusters)
+        {
+            let duty = if settings.rpm {
+                let speed = registers.fan(fan.id).map(|fan| fan.speed);
+                adjuster.next_duty(&range, range.target(fan.target_fan_duty), speed)
+            } else {
+                fan.target_fan_duty
+            };
+            control.set_duty(fan.id, duty).map_err(Error::Ec)?;
+            duties.push(duty.as_percentage());
+        }
+
+        let mut plant = ec.plant.borrow_mut();
+        plant.advance(model, load.power_at(time), interval);
+        temps.push(plant.temp);
+        time += interval;
+    }
+
+    Ok(Statistics {
+        name,
+        peak_temp: temps.iter().cloned().fold(parameters.ambient, f64::max),
+        mean_temp: mean(&temps),
+        fans: duties
+            .into_iter()
+            .map(|(id, duties)| {
+                let mean_duty = mean(&duties);
+                FanStatistics {
+                    id,
+                    duty_variance: mean(
+                        &duties
+                            .iter()
+                            .map(|duty| (duty - mean_duty).powi(2))
+                            .collect::<Vec<_>>(),
+                    ),
+                    changes: duties.windows(2).filter(|pair| pair[0] != pair[1]).count(),
+                }
+            })
+            .collect(),
+    })
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+}
+
+/// Print the statistics of each configuration as a table, with a row per fan
+pub fn print_statistics(statistics: &[Statistics]) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    let width = statistics
+        .iter()
+        .map(|statistics| statistics.name.len())
+        .fold("Configuration".len(), usize::max);
+
+    writeln!(
+        stdout,
+        "{:width$}  {:>9}  {:>9}  {:>3}  {:>13}  {:>11}",
+        "Configuration",
+        "Peak Temp",
+        "Mean Temp",
+        "Fan",
+        "Duty Variance",
+        "Fan Changes",
+        width = width
+    )?;
+    for statistics in statistics {
+        for fan in &statistics.fans {
+            writeln!(
+                stdout,
+                "{:width$}  {:>9}  {:>9}  {:>3}  {:>13.1}  {:>11}",
+                statistics.name,
+                Celsius(statistics.peak_temp),
+                Celsius(statistics.mean_temp),
+                fan.id,
+                fan.duty_variance,
+                fan.changes,
+                width = width
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+struct Celsius(f64);
+
+impl fmt::Display for Celsius {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("{:.1}°C", self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters() -> Parameters {
+        Parameters {
+            ambient: 25.0,
+            heat_capacity: 100.0,
+            passive_cooling: 1.0,
+            fan_cooling: 3.0,
+        }
+    }
+
+    #[test]
+    fn parse_load_profile() {
+        let load = LoadProfile::from_str("1m:5, 30s : 45.5,2m:0").unwrap();
+        assert_eq!(load.duration(), Duration::from_secs(210));
+        assert_eq!(load.power_at(Duration::from_secs(0)), 5.0);
+        assert_eq!(load.power_at(Duration::from_secs(59)), 5.0);
+        assert_eq!(load.power_at(Duration::from_secs(60)), 45.5);
+        assert_eq!(load.power_at(Duration::from_secs(100)), 0.0);
+        // After the end, the last phase goes on
+        assert_eq!(load.power_at(Duration::from_secs(1000)), 0.0);
+    }
+
+    #[test]
+    fn parse_invalid_load_profile() {
+        for load in &[
+            "", "1m", "1m:", ":5", "soon:5", "0s:5", "1m:-5", "1m:nan", "1m:inf", "1m:5,",
+        ] {
+            assert!(LoadProfile::from_str(load).is_err(), "{}", load);
+        }
+    }
+
+    #[test]
+    fn validate_parameters() {
+        assert!(parameters().validate().is_ok());
+        assert!(Parameters {
+            fan_cooling: 0.0,
+            ..parameters()
+        }
+        .validate()
+        .is_ok());
+
+        for invalid in &[
+            Parameters {
+                ambient: f64::NAN,
+                ..parameters()
+            },
+            Parameters {
+                heat_capacity: 0.0,
+                ..parameters()
+            },
+            Parameters {
+                passive_cooling: f64::NAN,
+                ..parameters()
+            },
+            Parameters {
+                passive_cooling: -1.0,
+                ..parameters()
+            },
+            Parameters {
+                fan_cooling: -1.0,
+                ..parameters()
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn plant_reaches_steady_state() {
+        let model = model::Model::select(Some("clevo"), None, false).unwrap();
+        let mut plant = Plant {
+            parameters: parameters(),
+            temp: 25.0,
+            registers: [0; ec::EC_REG_SIZE],
+        };
+
+        // Without fans, 20 W heat the machine by 20 K above ambient
+        plant.advance(&model, 20.0, Duration::from_secs(3600));
+        assert!((plant.temp - 45.0).abs() < 1e-6);
+
+        // The fan at full duty quadruples the cooling
+        plant.registers[model.fans[0].duty as usize] = 255;
+        plant.advance(&model, 20.0, Duration::from_secs(3600));
+        assert!((plant.temp - 30.0).abs() < 1e-6);
+
+        plant.update_registers(&model);
+        assert_eq!(plant.registers[model.cpu_temp as usize], 30);
+        let fan = &model.fans[0];
+        let speed = fan::Speed::from_raw_ec_bytes(
+            plant.registers[fan.rpm_lo as usize],
+            plant.registers[fan.rpm_hi as usize],
+            model.rpm_magic,
+        );
+        assert!((speed.as_rpm() as f64 - MAX_FAN_RPM).abs() < 10.0);
+    }
+}
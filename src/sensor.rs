@@ -0,0 +1,347 @@
+use crate::utils;
+use derive_more::Display;
+use std::{
+    collections::BTreeMap,
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+pub const DEFAULT_SYSFS_ROOT: &str = "/sys";
+
+/// Temperatures of sensors, if they could be read
+pub type Readings = BTreeMap<Source, Option<utils::Temperature>>;
+
+/// Temperature sensor of the kernel, read from sysfs instead of the EC
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    /// A `temp*_input' of the hwmon device with the given name, e.g. `coretemp' or `k10temp'
+    ///
+    /// The sensor is given by its label (e.g. `Package id 0' or `Tctl') or as `temp<n>'. Without
+    /// it, the sensor with the lowest number is used.
+    Hwmon {
+        chip: String,
+        sensor: Option<String>,
+    },
+    /// The thermal zone with the given type (e.g. `x86_pkg_temp') or number
+    ThermalZone(String),
+}
+
+#[derive(Debug, Display)]
+#[display(
+    fmt = "Invalid sensor `{}', expected hwmon:<chip>[:<sensor>] or thermal:<zone>",
+    _0
+)]
+pub struct InvalidSource(String);
+impl error::Error for InvalidSource {}
+
+impl FromStr for Source {
+    type Err = InvalidSource;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidSource(s.to_owned());
+        let (kind, name) = s.split_once(':').ok_or_else(invalid)?;
+        match kind {
+            "hwmon" => {
+                let (chip, sensor) = match name.split_once(':') {
+                    Some((chip, sensor)) => (chip, Some(sensor.to_owned())),
+                    None => (name, None),
+                };
+                if chip.is_empty() || sensor.as_deref() == Some("") {
+                    return Err(invalid());
+                }
+                Ok(Source::Hwmon {
+                    chip: chip.to_owned(),
+                    sensor,
+                })
+            }
+            "thermal" if !name.is_empty() => Ok(Source::ThermalZone(name.to_owned())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Hwmon {
+                chip,
+                sensor: Some(sensor),
+            } => write!(f, "hwmon:{}:{}", chip, sensor),
+            Source::Hwmon { chip, sensor: None } => write!(f, "hwmon:{}", chip),
+            Source::ThermalZone(zone) => write!(f, "thermal:{}", zone),
+        }
+    }
+}
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "Cannot find sensor {} in {}", _0, "_1.display()")]
+    NotFound(Source, PathBuf),
+    #[display(fmt = "Cannot read {}: {}", "_0.display()", _1)]
+    Read(PathBuf, io::Error),
+    #[display(fmt = "Invalid temperature `{}' in {}", _1, "_0.display()")]
+    Invalid(PathBuf, String),
+}
+impl error::Error for Error {}
+
+/// Reads temperature sensors from sysfs
+///
+/// The files of the sensors are looked up once and then read directly, until reading them fails.
+/// They are looked up again then, as reloading a driver (e.g. coretemp) renumbers the hwmon
+/// devices.
+pub struct Sensors {
+    root: PathBuf,
+    paths: BTreeMap<Source, PathBuf>,
+}
+
+impl Sensors {
+    /// Use the sysfs mounted at `root', usually /sys
+    pub fn new(root: &Path) -> Self {
+        Sensors {
+            root: root.to_owned(),
+            paths: BTreeMap::new(),
+        }
+    }
+
+    pub fn read(&mut self, source: &Source) -> Result<utils::Temperature, Error> {
+        let path = match self.paths.get(source) {
+            Some(path) => path.clone(),
+            None => {
+                let path = self.find(source)?;
+                self.paths.insert(source.clone(), path.clone());
+                path
+            }
+        };
+
+        let content = fs::read_to_string(&path).map_err(|err| {
+            self.paths.remove(source);
+            Error::Read(path.clone(), err)
+        })?;
+        let millidegrees = i64::from_str(content.trim())
+            .map_err(|_| Error::Invalid(path.clone(), content.trim().to_owned()))?;
+        Ok(utils::Temperature::from_millidegrees_celsius(millidegrees))
+    }
+
+    /// Find the file holding the temperature of the sensor
+    fn find(&self, source: &Source) -> Result<PathBuf, Error> {
+        let not_found = || Error::NotFound(source.clone(), self.root.clone());
+        match source {
+            Source::Hwmon { chip, sensor } => {
+                let device = devices(&self.root.join("class/hwmon"), "hwmon")?
+                    .into_iter()
+                    .find(|(_, device)| read_name(&device.join("name")).as_deref() == Some(chip))
+                    .ok_or_else(not_found)?
+                    .1;
+                let mut inputs: Vec<(usize, PathBuf)> = fs::read_dir(&device)
+                    .map_err(|err| Error::Read(device.clone(), err))?
+                    .filter_map(|entry| {
+                        let name = entry.ok()?.file_name().into_string().ok()?;
+                        let number = name.strip_prefix("temp")?.strip_suffix("_input")?;
+                        Some((usize::from_str(number).ok()?, device.join(&name)))
+                    })
+                    .collect();
+                inputs.sort();
+
+                inputs
+                    .into_iter()
+                    .find(|(number, _)| match sensor {
+                        Some(sensor) => {
+                            *sensor == format!("temp{}", number)
+                                || read_name(&device.join(format!("temp{}_label", number))).as_ref()
+                                    == Some(sensor)
+                        }
+                        None => true,
+                    })
+                    .map(|(_, path)| path)
+                    .ok_or_else(not_found)
+            }
+            Source::ThermalZone(zone) => devices(&self.root.join("class/thermal"), "thermal_zone")?
+                .into_iter()
+                .find(|(number, device)| {
+                    *zone == number.to_string()
+                        || read_name(&device.join("type")).as_ref() == Some(zone)
+                })
+                .map(|(_, device)| device.join("temp"))
+                .ok_or_else(not_found),
+        }
+    }
+}
+
+/// Readings of the given sensors, all at the same temperature
+///
+/// Used in place of the sensors, where they cannot be read, e.g. when replaying a trace.
+pub fn substitute(sources: Vec<Source>, temp: Option<utils::Temperature>) -> Readings {
+    sources.into_iter().map(|source| (source, temp)).collect()
+}
+
+/// Devices of a sysfs class, e.g. `hwmon0', ordered by their number
+fn devices(class: &Path, prefix: &str) -> Result<Vec<(usize, PathBuf)>, Error> {
+    let mut devices: Vec<_> = fs::read_dir(class)
+        .map_err(|err| Error::Read(class.to_owned(), err))?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            let number = usize::from_str(name.strip_prefix(prefix)?).ok()?;
+            Some((number, entry.path()))
+        })
+        .collect();
+    devices.sort();
+    Ok(devices)
+}
+
+/// Read a file holding a name, e.g. the `name' of a hwmon device, if it exists
+fn read_name(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|name| name.trim_end().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sysfs tree with an ACPI and a coretemp hwmon device and two thermal zones
+    fn sysfs() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let write = |path: &str, content: &str| {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+        write("class/hwmon/hwmon0/name", "acpitz\n");
+        write("class/hwmon/hwmon0/temp1_input", "40000\n");
+        write("class/hwmon/hwmon2/name", "coretemp\n");
+        write("class/hwmon/hwmon2/temp1_label", "Package id 0\n");
+        write("class/hwmon/hwmon2/temp1_input", "67500\n");
+        write("class/hwmon/hwmon2/temp2_label", "Core 0\n");
+        write("class/hwmon/hwmon2/temp2_input", "61250\n");
+        write("class/hwmon/hwmon2/temp10_input", "50000\n");
+        write("class/hwmon/hwmon10/name", "coretemp\n");
+        write("class/hwmon/hwmon10/temp1_input", "99000\n");
+        write("class/thermal/thermal_zone0/type", "acpitz\n");
+        write("class/thermal/thermal_zone0/temp", "40000\n");
+        write("class/thermal/thermal_zone1/type", "x86_pkg_temp\n");
+        write("class/thermal/thermal_zone1/temp", "68000\n");
+        root
+    }
+
+    fn read(root: &Path, source: &str) -> Result<f64, Error> {
+        Sensors::new(root)
+            .read(&Source::from_str(source).unwrap())
+            .map(|temp| temp.as_degrees_celsius())
+    }
+
+    #[test]
+    fn parse_source() {
+        for source in &[
+            "hwmon:coretemp",
+            "hwmon:coretemp:Package id 0",
+            "hwmon:k10temp:temp2",
+            "thermal:x86_pkg_temp",
+            "thermal:0",
+        ] {
+            assert_eq!(Source::from_str(source).unwrap().to_string(), *source);
+        }
+        for source in &[
+            "coretemp",
+            "hwmon:",
+            "hwmon::temp1",
+            "hwmon:coretemp:",
+            "thermal:",
+            "acpi:0",
+        ] {
+            assert!(Source::from_str(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn hwmon_by_label() {
+        let root = sysfs();
+        assert_eq!(
+            read(root.path(), "hwmon:coretemp:Package id 0").unwrap(),
+            67.5
+        );
+        assert_eq!(read(root.path(), "hwmon:coretemp:Core 0").unwrap(), 61.25);
+        assert_eq!(read(root.path(), "hwmon:acpitz").unwrap(), 40.0);
+    }
+
+    #[test]
+    fn hwmon_by_number() {
+        let root = sysfs();
+        assert_eq!(read(root.path(), "hwmon:coretemp:temp2").unwrap(), 61.25);
+        assert_eq!(read(root.path(), "hwmon:coretemp:temp10").unwrap(), 50.0);
+    }
+
+    #[test]
+    fn hwmon_lowest_numbers_first() {
+        // hwmon2 before hwmon10 and temp1 before temp10, even though not in lexical order
+        assert_eq!(read(sysfs().path(), "hwmon:coretemp").unwrap(), 67.5);
+    }
+
+    #[test]
+    fn thermal_zone() {
+        let root = sysfs();
+        assert_eq!(read(root.path(), "thermal:x86_pkg_temp").unwrap(), 68.0);
+        assert_eq!(read(root.path(), "thermal:0").unwrap(), 40.0);
+        assert_eq!(read(root.path(), "thermal:1").unwrap(), 68.0);
+    }
+
+    #[test]
+    fn not_found() {
+        let root = sysfs();
+        for source in &[
+            "hwmon:k10temp",
+            "hwmon:coretemp:Core 1",
+            "hwmon:coretemp:temp3",
+            "thermal:2",
+            "thermal:iwlwifi",
+        ] {
+            assert!(
+                matches!(read(root.path(), source), Err(Error::NotFound(..))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn missing_class() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            read(root.path(), "hwmon:coretemp"),
+            Err(Error::Read(..))
+        ));
+        assert!(matches!(
+            read(root.path(), "thermal:0"),
+            Err(Error::Read(..))
+        ));
+    }
+
+    #[test]
+    fn invalid_temperature() {
+        let root = sysfs();
+        fs::write(
+            root.path().join("class/thermal/thermal_zone0/temp"),
+            "hot\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            read(root.path(), "thermal:0"),
+            Err(Error::Invalid(..))
+        ));
+    }
+
+    #[test]
+    fn renumbered_device() {
+        let root = sysfs();
+        let hwmon = root.path().join("class/hwmon");
+        let source = Source::from_str("hwmon:coretemp:Core 0").unwrap();
+        let mut sensors = Sensors::new(root.path());
+        assert_eq!(sensors.read(&source).unwrap().as_degrees_celsius(), 61.25);
+
+        // Like reloading the driver
+        fs::rename(hwmon.join("hwmon2"), hwmon.join("hwmon3")).unwrap();
+        assert!(matches!(sensors.read(&source), Err(Error::Read(..))));
+        assert_eq!(sensors.read(&source).unwrap().as_degrees_celsius(), 61.25);
+    }
+}
@@ -0,0 +1,407 @@
+use crate::{ec, fan, sensor, utils, ShowOptions, ShowValues};
+use derive_more::Display;
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::{
+    error, fmt,
+    io::{self, Write},
+    str::FromStr,
+};
+
+/// Output format of `clevo-fan show'
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// `Label: value' lines, as selected by the value flags
+    Text,
+    /// One JSON object per sample, on a single line
+    Json,
+    /// One CSV row per sample, after a header row
+    Csv,
+}
+
+#[derive(Debug, Display)]
+#[display(fmt = "Invalid output format `{}', expected text, json or csv", _0)]
+pub struct InvalidFormat(String);
+impl error::Error for InvalidFormat {}
+
+impl FromStr for Format {
+    type Err = InvalidFormat;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(InvalidFormat(s.to_owned())),
+        }
+    }
+}
+
+/// All values of the EC registers, decoded with explicit units and raw
+///
+/// The keys are the keys in JSON and the column names in CSV output. Values of the fans are
+/// prefixed by the fan number, e.g. `fan1_duty_percent', unless the model has a single fan, see
+/// `fan_names'. Sensors of the kernel are named after the sensor, e.g.
+/// `hwmon_coretemp_temp1_temp_celsius' for `hwmon:coretemp:temp1'. Temperatures are given in the
+/// unit chosen by `--units', which is part of their key.
+struct Record(Vec<(String, Value)>);
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Value {
+    Float(f64),
+    Integer(u32),
+}
+
+impl Record {
+    fn new(
+        ec: &ec::Registers,
+        sensors: &[(sensor::Source, utils::Temperature)],
+        unit: utils::Unit,
+    ) -> Self {
+        let mut record = vec![
+            (
+                format!("cpu_temp_{}", unit.name()),
+                Value::Float(ec.cpu_temp.as_unit(unit)),
+            ),
+            (
+                "cpu_temp_raw".to_owned(),
+                Value::Integer(ec.raw.cpu_temp as u32),
+            ),
+            (
+                format!("gpu_temp_{}", unit.name()),
+                Value::Float(ec.gpu_temp.as_unit(unit)),
+            ),
+            (
+                "gpu_temp_raw".to_owned(),
+                Value::Integer(ec.raw.gpu_temp as u32),
+            ),
+        ];
+        for ((id, fan), raw) in fan::Id::all().zip(&ec.fans).zip(&ec.raw.fans) {
+            let (prefix, _) = fan_names(ec, id);
+            let key = |name| format!("{}_{}", prefix, name);
+            record.extend(vec![
+                (key("duty_percent"), Value::Float(fan.duty.as_percentage())),
+                (key("duty_raw"), Value::Integer(raw.duty as u32)),
+                (key("speed_rpm"), Value::Integer(fan.speed.as_rpm())),
+                (key("speed_raw_hi"), Value::Integer(raw.rpms_hi as u32)),
+                (key("speed_raw_lo"), Value::Integer(raw.rpms_lo as u32)),
+            ]);
+        }
+        for (source, temp) in sensors {
+            let name: String = source
+                .to_string()
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() {
+                        c.to_ascii_lowercase()
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            record.push((
+                format!("{}_temp_{}", name, unit.name()),
+                Value::Float(temp.as_unit(unit)),
+            ));
+        }
+
+        Record(record)
+    }
+
+    /// Write the record as a CSV row, preceded by the header row, if it is not written yet
+    fn write_csv<W: io::Write>(
+        &self,
+        writer: &mut csv::Writer<W>,
+        header_written: &mut bool,
+    ) -> csv::Result<()> {
+        if !*header_written {
+            writer.write_record(self.0.iter().map(|(key, _)| key))?;
+            *header_written = true;
+        }
+        writer.write_record(self.0.iter().map(|(_, value)| value.to_string()))?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Prefix of the keys and labels of the values of a fan, e.g. `fan2' and `Fan 2'
+///
+/// On models with a single fan, they are `fan' and `Fan', as they were before there was support
+/// for multiple fans, so that scripts parsing the output keep working.
+fn fan_names(ec: &ec::Registers, id: fan::Id) -> (String, String) {
+    if ec.fans.len() == 1 {
+        ("fan".to_owned(), "Fan".to_owned())
+    } else {
+        (format!("fan{}", id), format!("Fan {}", id))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Float(value) => write!(f, "{:?}", value),
+            Value::Integer(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl Serialize for Record {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in &self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Prints samples of the EC registers to stdout in the requested format
+///
+/// Successive samples continue the same output, e.g. the CSV header is only printed once. When
+/// watching, text output is put on a single line per sample.
+pub struct Printer {
+    values: ShowValues,
+    options: ShowOptions,
+    output: Output,
+}
+
+enum Output {
+    Text,
+    Json,
+    Csv {
+        writer: Box<csv::Writer<io::Stdout>>,
+        header_written: bool,
+    },
+}
+
+impl Printer {
+    pub fn new(values: ShowValues, options: ShowOptions) -> utils::FlexibleResult<Self> {
+        if options.in_place && options.format != Format::Text {
+            return Err("`--in-place' only works with the text format".into());
+        }
+        if options.format == Format::Text
+            && !(values.cpu_temp
+                || values.gpu_temp
+                || values.fan_duty
+                || values.fan_speed
+                || !values.sensors.is_empty())
+        {
+            writeln!(
+                io::stderr(),
+                "Warning: No values are being printed, you might want to use `-a'. See `--help' for further information."
+            )?;
+        }
+
+        Ok(Printer {
+            output: match options.format {
+                Format::Text => Output::Text,
+                Format::Json => Output::Json,
+                Format::Csv => Output::Csv {
+                    writer: Box::new(csv::Writer::from_writer(io::stdout())),
+                    header_written: false,
+                },
+            },
+            values,
+            options,
+        })
+    }
+
+    pub fn print(
+        &mut self,
+        ec: &ec::Registers,
+        sensors: &[(sensor::Source, utils::Temperature)],
+    ) -> utils::FlexibleResult<()> {
+        match &mut self.output {
+            Output::Text => self.print_text(ec, sensors)?,
+            Output::Json => {
+                serde_json::to_writer(io::stdout(), &Record::new(ec, sensors, self.options.units))?;
+                writeln!(io::stdout())?;
+            }
+            Output::Csv {
+                writer,
+                header_written,
+            } => Record::new(ec, sensors, self.options.units).write_csv(writer, header_written)?,
+        }
+
+        Ok(())
+    }
+
+    /// End the output, after the last sample
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.options.in_place {
+            writeln!(io::stdout())?;
+        }
+        Ok(())
+    }
+
+    fn print_text(
+        &self,
+        ec: &ec::Registers,
+        sensors: &[(sensor::Source, utils::Temperature)],
+    ) -> io::Result<()> {
+        let unit = self.options.units;
+        let cpu_temp = ec.cpu_temp.in_unit(unit);
+        let gpu_temp = ec.gpu_temp.in_unit(unit);
+        let sensor_temps: Vec<_> = sensors
+            .iter()
+            .map(|(source, temp)| (source, temp.in_unit(unit)))
+            .collect();
+        let mut values: Vec<(_, &dyn fmt::Display, _)> = vec![
+            (self.values.cpu_temp, &cpu_temp, "CPU Temp".to_owned()),
+            (self.values.gpu_temp, &gpu_temp, "GPU Temp".to_owned()),
+        ];
+        for (id, fan) in fan::Id::all().zip(&ec.fans) {
+            if self.values.fans.is_empty() || self.values.fans.contains(&id) {
+                let (_, label) = fan_names(ec, id);
+                values.push((self.values.fan_duty, &fan.duty, format!("{} Duty", label)));
+                values.push((
+                    self.values.fan_speed,
+                    &fan.speed,
+                    format!("{} Speed", label),
+                ));
+            }
+        }
+        for (source, temp) in &sensor_temps {
+            values.push((true, temp, format!("{} Temp", source)));
+        }
+
+        let single_line = self.options.watch || self.options.count.is_some();
+
+        let mut stdout = io::stdout();
+        if self.options.in_place {
+            // Return to the start of the line and clear it
+            write!(stdout, "\r\x1b[K")?;
+        }
+        let mut first = true;
+        for (should_print, value, label) in values.iter() {
+            if *should_print {
+                if single_line && !first {
+                    write!(stdout, "  ")?;
+                }
+                first = false;
+
+                if !self.options.hide_labels {
+                    write!(stdout, "{}: ", label)?;
+                }
+                if self.options.hide_units {
+                    write!(stdout, "{:#}", value)?;
+                } else {
+                    write!(stdout, "{}", value)?;
+                }
+                if !single_line {
+                    writeln!(stdout)?;
+                }
+            }
+        }
+        if single_line && !self.options.in_place {
+            writeln!(stdout)?;
+        }
+
+        stdout.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model;
+
+    /// Registers of the given model at 70°C CPU and 50°C GPU, with the first fan at half duty
+    fn registers(model: &str) -> ec::Registers {
+        let model = model::Model::select(Some(model), None, false).unwrap();
+        let mut buf = [0; ec::EC_REG_SIZE];
+        buf[model.cpu_temp as usize] = 70;
+        buf[model.gpu_temp as usize] = 50;
+        buf[model.fans[0].duty as usize] = 0x80;
+        buf[model.fans[0].rpm_hi as usize] = 0x03;
+        buf[model.fans[0].rpm_lo as usize] = 0x52;
+        ec::Registers::decode(&buf, &model)
+    }
+
+    fn keys(record: &Record) -> Vec<&str> {
+        record.0.iter().map(|(key, _)| key.as_str()).collect()
+    }
+
+    #[test]
+    fn single_fan() {
+        let ec = registers("clevo");
+        assert_eq!(
+            fan_names(&ec, fan::Id::CPU),
+            ("fan".to_owned(), "Fan".to_owned())
+        );
+        let record = Record::new(&ec, &[], utils::Unit::Celsius);
+        assert_eq!(
+            keys(&record),
+            vec![
+                "cpu_temp_celsius",
+                "cpu_temp_raw",
+                "gpu_temp_celsius",
+                "gpu_temp_raw",
+                "fan_duty_percent",
+                "fan_duty_raw",
+                "fan_speed_rpm",
+                "fan_speed_raw_hi",
+                "fan_speed_raw_lo",
+            ]
+        );
+    }
+
+    #[test]
+    fn dual_fan() {
+        let ec = registers("clevo-dual");
+        assert_eq!(
+            fan_names(&ec, fan::Id::GPU),
+            ("fan2".to_owned(), "Fan 2".to_owned())
+        );
+        let sensors = [(
+            sensor::Source::from_str("hwmon:coretemp:temp1").unwrap(),
+            utils::Temperature::from_degrees_celsius(60.0),
+        )];
+        let record = Record::new(&ec, &sensors, utils::Unit::Kelvin);
+        assert_eq!(
+            keys(&record)[..6],
+            [
+                "cpu_temp_kelvin",
+                "cpu_temp_raw",
+                "gpu_temp_kelvin",
+                "gpu_temp_raw",
+                "fan1_duty_percent",
+                "fan1_duty_raw",
+            ]
+        );
+        assert_eq!(
+            keys(&record)[8..10],
+            ["fan1_speed_raw_lo", "fan2_duty_percent"]
+        );
+        assert_eq!(
+            keys(&record).last(),
+            Some(&"hwmon_coretemp_temp1_temp_kelvin")
+        );
+    }
+
+    #[test]
+    fn json() {
+        let record = Record::new(&registers("clevo"), &[], utils::Unit::Celsius);
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"cpu_temp_celsius":70.0,"cpu_temp_raw":70,"gpu_temp_celsius":50.0,"gpu_temp_raw":50,"fan_duty_percent":50.19607843137255,"fan_duty_raw":128,"fan_speed_rpm":2536,"fan_speed_raw_hi":3,"fan_speed_raw_lo":82}"#
+        );
+    }
+
+    #[test]
+    fn csv() {
+        let record = Record::new(&registers("clevo"), &[], utils::Unit::Celsius);
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        let mut header_written = false;
+        record.write_csv(&mut writer, &mut header_written).unwrap();
+        record.write_csv(&mut writer, &mut header_written).unwrap();
+        let row = "70.0,70,50.0,50,50.19607843137255,128,2536,3,82\n";
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            format!(
+                "cpu_temp_celsius,cpu_temp_raw,gpu_temp_celsius,gpu_temp_raw,fan_duty_percent,\
+                 fan_duty_raw,fan_speed_rpm,fan_speed_raw_hi,fan_speed_raw_lo\n{}{}",
+                row, row
+            )
+        );
+    }
+}
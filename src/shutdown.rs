@@ -0,0 +1,90 @@
+use crate::{fan, utils::ResultExt};
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM};
+use std::{
+    io::{self, Write},
+    ops,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Signals that have been caught
+///
+/// Instead of terminating the process, SIGINT and SIGTERM only raise a flag, so the fan loop can
+/// finish its current cycle and hand over the fan before exiting. SIGHUP requests reloading the
+/// configuration.
+pub struct Signals {
+    terminate: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+}
+
+impl Signals {
+    pub fn register() -> io::Result<Self> {
+        let terminate = Arc::new(AtomicBool::new(false));
+        for signal in &[SIGINT, SIGTERM] {
+            signal_hook::flag::register(*signal, Arc::clone(&terminate))?;
+        }
+        let reload = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGHUP, Arc::clone(&reload))?;
+
+        Ok(Signals { terminate, reload })
+    }
+
+    pub fn terminate_requested(&self) -> bool {
+        self.terminate.load(Ordering::Relaxed)
+    }
+
+    /// Whether a reload was requested since the last call
+    pub fn reload_requested(&self) -> bool {
+        self.reload.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Fan control, that is handed over when going out of scope
+///
+/// This happens on every way out of the fan loop, including panics, so the fan never stays at
+/// whatever duty was set last.
+pub struct Guard {
+    fan: fan::Control,
+    handover: fan::Handover,
+    fans: Vec<fan::Id>,
+}
+
+impl Guard {
+    pub fn new(fan: fan::Control, handover: fan::Handover, fans: Vec<fan::Id>) -> Self {
+        Guard {
+            fan,
+            handover,
+            fans,
+        }
+    }
+
+    pub fn set_handover(&mut self, handover: fan::Handover, fans: Vec<fan::Id>) {
+        self.handover = handover;
+        self.fans = fans;
+    }
+}
+
+impl ops::Deref for Guard {
+    type Target = fan::Control;
+    fn deref(&self) -> &Self::Target {
+        &self.fan
+    }
+}
+
+impl ops::DerefMut for Guard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.fan
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.fan
+            .hand_over(self.handover, &self.fans)
+            .unwrap_or_else(|err| {
+                writeln!(io::stderr(), "Error: Cannot hand over fan control: {}", err).ignore()
+            });
+    }
+}
@@ -0,0 +1,350 @@
+use crate::{calibration, ec, fan};
+use derive_more::Display;
+use std::{
+    error, thread,
+    time::{Duration, Instant},
+};
+
+/// Share of the speed error, in percent of the maximum speed, that is added to the duty each cycle
+const GAIN: f64 = 0.3;
+/// Relative speed error, up to which a target speed is considered reached
+const TOLERANCE: f64 = 0.03;
+/// Number of consecutive readings within tolerance, before a target speed is considered reached
+const READINGS_IN_TOLERANCE: usize = 3;
+/// How often to adjust the duty while trying to reach a speed
+const ADJUST_INTERVAL: Duration = Duration::from_millis(500);
+/// How long to try to reach a speed, before giving up
+const REACH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(
+        fmt = "Speed range of fan {} is unknown, run `clevo-fan calibrate' first",
+        _0
+    )]
+    UnknownRange(fan::Id),
+    #[display(
+        fmt = "Fan {} cannot run at {} RPM, only at 0 or from {} to {} RPM",
+        _0,
+        _1,
+        "_2.min_rpm",
+        "_2.max_rpm"
+    )]
+    OutOfRange(fan::Id, u32, Range),
+    #[display(
+        fmt = "Fan {} did not reach {} RPM, it is at {} with {} duty",
+        _0,
+        _1,
+        _2,
+        _3
+    )]
+    NotReached(fan::Id, u32, fan::Speed, fan::Duty),
+    #[display(fmt = "Cannot read the speed of fan {}", _0)]
+    NoSpeed(fan::Id),
+    #[display(fmt = "{}", _0)]
+    Ec(ec::Error),
+}
+impl error::Error for Error {}
+
+/// Speeds a fan can run at, besides standing still
+#[derive(Debug, Clone)]
+pub struct Range {
+    pub min_rpm: u32,
+    pub max_rpm: u32,
+    /// Calibrated speed at each duty, for a first guess of the duty needed for a speed
+    curve: Vec<calibration::Point>,
+}
+
+impl Range {
+    /// Range of a fan, assuming the speed is proportional to the duty
+    pub fn new(max_rpm: u32) -> Self {
+        Range {
+            min_rpm: 0,
+            max_rpm,
+            curve: Vec::new(),
+        }
+    }
+
+    /// Range of a fan, as calibrated, else up to `max_rpm', if known
+    pub fn of(
+        calibration: Option<&calibration::Calibration>,
+        max_rpm: Option<u32>,
+        id: fan::Id,
+    ) -> Option<Self> {
+        match calibration.and_then(|calibration| calibration.fans.get(&id)) {
+            Some(fan) if fan.max_rpm > 0 => Some(Range {
+                min_rpm: fan
+                    .curve
+                    .iter()
+                    .map(|point| point.rpm)
+                    .filter(|rpm| *rpm > 0)
+                    .min()
+                    .unwrap_or(fan.max_rpm),
+                max_rpm: fan.max_rpm,
+                curve: fan.curve.clone(),
+            }),
+            _ => max_rpm.filter(|max_rpm| *max_rpm > 0).map(Range::new),
+        }
+    }
+
+    pub fn contains(&self, rpm: u32) -> bool {
+        rpm == 0 || (self.min_rpm..=self.max_rpm).contains(&rpm)
+    }
+
+    /// Speed, that a policy requests by the given duty
+    ///
+    /// The duty is the share of the maximum speed. Speeds below the minimum, other than 0, are
+    /// raised to it.
+    pub fn target(&self, duty: fan::Duty) -> u32 {
+        let rpm = (duty.as_percentage() / 100.0 * self.max_rpm as f64).round() as u32;
+        if rpm == 0 {
+            0
+        } else {
+            rpm.max(self.min_rpm)
+        }
+    }
+
+    /// Speed expected at the given duty, as calibrated, else proportional to the duty
+    pub fn expected_rpm(&self, duty: fan::Duty) -> f64 {
+        let duty = duty.as_percentage();
+        self.curve
+            .windows(2)
+            .find(|segment| {
+                let (start, end) = (
+                    segment[0].duty.as_percentage(),
+                    segment[1].duty.as_percentage(),
+                );
+                start < end && start <= duty && duty <= end
+            })
+            .map(|segment| {
+                let (start, end) = (&segment[0], &segment[1]);
+                let position = (duty - start.duty.as_percentage())
+                    / (end.duty.as_percentage() - start.duty.as_percentage());
+                start.rpm as f64 + position * (end.rpm as f64 - start.rpm as f64)
+            })
+            .unwrap_or_else(|| duty / 100.0 * self.max_rpm as f64)
+    }
+
+    /// Duty expected to result in the given speed, in percent
+    fn expected_duty(&self, rpm: u32) -> f64 {
+        self.curve
+            .windows(2)
+            .find(|segment| {
+                segment[0].rpm < segment[1].rpm && segment[0].rpm <= rpm && rpm <= segment[1].rpm
+            })
+            .map(|segment| {
+                let (start, end) = (&segment[0], &segment[1]);
+                let position = (rpm - start.rpm) as f64 / (end.rpm - start.rpm) as f64;
+                start.duty.as_percentage()
+                    + position * (end.duty.as_percentage() - start.duty.as_percentage())
+            })
+            .unwrap_or_else(|| rpm as f64 / self.max_rpm as f64 * 100.0)
+    }
+}
+
+/// Closed loop, adjusting the duty of a fan until it runs at a target speed
+///
+/// The duty is the one expected from the calibration (or proportional to the speed), corrected by
+/// the accumulated difference between the target speed and the speed read back from the EC.
+#[derive(Debug, Default)]
+pub struct Adjuster {
+    /// Correction of the expected duty, in percent
+    correction: f64,
+}
+
+impl Adjuster {
+    /// Determine the duty for the next cycle
+    ///
+    /// `speed` is the speed read back after applying the previous duty, if it could be read.
+    pub fn next_duty(
+        &mut self,
+        range: &Range,
+        target: u32,
+        speed: Option<fan::Speed>,
+    ) -> fan::Duty {
+        if target == 0 {
+            self.correction = 0.0;
+            return fan::Duty::min();
+        }
+
+        let expected = range.expected_duty(target);
+        if let Some(speed) = speed {
+            let error = (target as f64 - speed.as_rpm() as f64) / range.max_rpm as f64 * 100.0;
+            // Only correct as far as the duty can go, so that the correction does not wind up
+            self.correction = (self.correction + GAIN * error).clamp(-expected, 100.0 - expected);
+        }
+        fan::Duty::from_saturating_percentage(expected + self.correction)
+    }
+}
+
+/// Adjust the duty of a fan until it runs at the given speed, returning the duty needed for it
+///
+/// Fails if the speed is outside of the range of the fan, or not reached in time.
+pub fn reach(
+    fan: &mut fan::Control,
+    id: fan::Id,
+    range: &Range,
+    target: u32,
+) -> Result<(fan::Duty, fan::Speed), Error> {
+    if !range.contains(target) {
+        return Err(Error::OutOfRange(id, target, range.clone()));
+    }
+
+    let started = Instant::now();
+    let mut adjuster = Adjuster::default();
+    let mut speed = None;
+    let mut readings_in_tolerance = 0;
+    loop {
+        let duty = adjuster.next_duty(range, target, speed);
+        fan.set_duty(id, duty).map_err(Error::Ec)?;
+        thread::sleep(ADJUST_INTERVAL);
+
+        let current = fan
+            .ec()
+            .registers()
+            .map_err(Error::Ec)?
+            .fan(id)
+            .ok_or(Error::NoSpeed(id))?
+            .speed;
+        speed = Some(current);
+
+        let error = (current.as_rpm() as f64 - target as f64).abs();
+        if error <= target as f64 * TOLERANCE {
+            readings_in_tolerance += 1;
+            if readings_in_tolerance >= READINGS_IN_TOLERANCE {
+                return Ok((duty, current));
+            }
+        } else {
+            readings_in_tolerance = 0;
+        }
+
+        if started.elapsed() >= REACH_TIMEOUT {
+            return Err(Error::NotReached(id, target, current, duty));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn duty(percentage: f64) -> fan::Duty {
+        fan::Duty::from_saturating_percentage(percentage)
+    }
+
+    fn speed(rpm: u32) -> fan::Speed {
+        fan::Speed::from_raw_ec_bytes(1, 0, rpm)
+    }
+
+    /// Range of a fan, that starts at 40% duty and then speeds up linearly to 4000 RPM
+    fn calibrated() -> Range {
+        let curve = [(0.0, 0), (30.0, 0), (40.0, 1200), (100.0, 4000)]
+            .iter()
+            .map(|(percentage, rpm)| calibration::Point {
+                duty: duty(*percentage),
+                rpm: *rpm,
+            })
+            .collect();
+        let mut calibration = calibration::Calibration {
+            fans: BTreeMap::new(),
+        };
+        calibration.fans.insert(
+            fan::Id::CPU,
+            calibration::FanCalibration {
+                spin_up_duty: Some(duty(40.0)),
+                stop_duty: Some(duty(35.0)),
+                max_rpm: 4000,
+                curve,
+            },
+        );
+        Range::of(Some(&calibration), Some(6000), fan::Id::CPU).unwrap()
+    }
+
+    #[test]
+    fn range_of() {
+        let range = calibrated();
+        assert_eq!((range.min_rpm, range.max_rpm), (1200, 4000));
+
+        let range = Range::of(None, Some(6000), fan::Id::CPU).unwrap();
+        assert_eq!((range.min_rpm, range.max_rpm), (0, 6000));
+        assert!(Range::of(None, Some(0), fan::Id::CPU).is_none());
+        assert!(Range::of(None, None, fan::Id::CPU).is_none());
+    }
+
+    #[test]
+    fn contains() {
+        let range = calibrated();
+        assert!(range.contains(0));
+        assert!(!range.contains(1000));
+        assert!(range.contains(1200));
+        assert!(range.contains(4000));
+        assert!(!range.contains(4100));
+    }
+
+    #[test]
+    fn target() {
+        let range = calibrated();
+        assert_eq!(range.target(duty(0.0)), 0);
+        // Speeds the fan cannot run at are raised to its minimum
+        assert_eq!(range.target(duty(10.0)), 1200);
+        assert_eq!(range.target(duty(50.0)), 2000);
+        assert_eq!(range.target(duty(100.0)), 4000);
+    }
+
+    #[test]
+    fn expected_speed_and_duty() {
+        let range = calibrated();
+        assert_eq!(range.expected_rpm(duty(20.0)), 0.0);
+        assert_eq!(range.expected_rpm(duty(35.0)), 600.0);
+        assert_eq!(range.expected_rpm(duty(70.0)), 2600.0);
+        assert_eq!(range.expected_duty(600), 35.0);
+        assert_eq!(range.expected_duty(2600), 70.0);
+
+        // Without a calibration, the speed is taken as proportional to the duty
+        let range = Range::new(4000);
+        assert_eq!(range.expected_rpm(duty(35.0)), 1400.0);
+        assert_eq!(range.expected_duty(1000), 25.0);
+    }
+
+    #[test]
+    fn adjuster_anti_windup() {
+        let range = Range::new(4000);
+        let mut adjuster = Adjuster::default();
+        assert_eq!(adjuster.next_duty(&range, 2000, None), duty(50.0));
+        // A stalled fan drives the duty up to 100%, but not the correction any further
+        for _ in 0..100 {
+            adjuster.next_duty(&range, 2000, Some(speed(0)));
+        }
+        assert_eq!(adjuster.next_duty(&range, 2000, None), duty(100.0));
+        // Once the fan runs too fast, the duty comes down right away
+        let next = adjuster.next_duty(&range, 2000, Some(speed(4000)));
+        assert!((next.as_percentage() - 85.0).abs() < 1e-9);
+
+        assert_eq!(adjuster.next_duty(&range, 0, Some(speed(4000))), duty(0.0));
+        assert_eq!(adjuster.next_duty(&range, 2000, None), duty(50.0));
+    }
+
+    #[test]
+    fn adjuster_convergence() {
+        // The fan is much slower than the assumed proportional speed at low duties
+        let fan = |duty: fan::Duty| speed((4000.0 * (duty.as_percentage() / 100.0).powi(2)) as u32);
+        let range = Range::new(4000);
+        let mut adjuster = Adjuster::default();
+        let mut current = None;
+        for _ in 0..50 {
+            current = Some(fan(adjuster.next_duty(&range, 1000, current)));
+        }
+        let rpm = current.unwrap().as_rpm() as f64;
+        assert!((rpm - 1000.0).abs() <= 1000.0 * TOLERANCE, "{}", rpm);
+
+        // A calibrated fan gets the right duty right away
+        let range = calibrated();
+        let mut adjuster = Adjuster::default();
+        assert_eq!(adjuster.next_duty(&range, 2600, None), duty(70.0));
+        assert_eq!(
+            adjuster.next_duty(&range, 2600, Some(speed(2600))),
+            duty(70.0)
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use crate::{config, fan, speed};
+use std::time::Instant;
+
+/// Fraction of the expected speed, below which a fan is considered stalled
+const MIN_SPEED_RATIO: f64 = 0.5;
+
+/// Change of the state of a fan, found by the `Detector`
+pub enum Event {
+    /// The fan does not spin as it should, for the given reason
+    Stalled(String),
+    Recovered,
+}
+
+/// How a speed reading compares to the duty of the fan
+enum Reading {
+    /// The fan does not spin as it should, for the given reason
+    Stalled(String),
+    Spinning,
+    /// The duty is too low to tell, whether the fan should spin
+    Unknown,
+}
+
+/// Notices when a fan does not spin as fast as its duty demands
+///
+/// A fan is stalled, when it is at 0 RPM or far below the speed expected from its duty for some
+/// time, although its duty is above the spin-up threshold. It has recovered, once it spins again
+/// and its speed matches its duty.
+#[derive(Default)]
+pub struct Detector {
+    /// Duty the fan was last set to
+    duty: Option<fan::Duty>,
+    /// When the fan was first seen not spinning as it should
+    since: Option<Instant>,
+    stalled: bool,
+}
+
+impl Detector {
+    /// Remember the duty the fan was set to, which the next speed readings are checked against
+    pub fn applied(&mut self, duty: fan::Duty) {
+        self.duty = Some(duty);
+    }
+
+    pub fn stalled(&self) -> bool {
+        self.stalled
+    }
+
+    /// Check a speed reading, returning whether the fan stalled or recovered by it
+    ///
+    /// The fan has to spin at duties of at least `min_duty', see `config::Stall::min_duty'. The
+    /// expected speed is taken from `range', the calibrated or configured speed range of the fan,
+    /// if it is known. Otherwise, only a fan standing still is noticed.
+    pub fn check(
+        &mut self,
+        settings: &config::Stall,
+        min_duty: Option<fan::Duty>,
+        range: Option<&speed::Range>,
+        speed: fan::Speed,
+        now: Instant,
+    ) -> Option<Event> {
+        let duty = self.duty?;
+        match Self::read(min_duty, range, duty, speed) {
+            Reading::Stalled(problem) => {
+                let since = *self.since.get_or_insert(now);
+                if !self.stalled && now.duration_since(since) >= settings.time {
+                    self.stalled = true;
+                    return Some(Event::Stalled(problem));
+                }
+                None
+            }
+            Reading::Spinning => {
+                self.since = None;
+                if self.stalled {
+                    self.stalled = false;
+                    return Some(Event::Recovered);
+                }
+                None
+            }
+            // A stalled fan stays stalled until it is seen spinning again
+            Reading::Unknown => {
+                self.since = None;
+                None
+            }
+        }
+    }
+
+    fn read(
+        min_duty: Option<fan::Duty>,
+        range: Option<&speed::Range>,
+        duty: fan::Duty,
+        speed: fan::Speed,
+    ) -> Reading {
+        let should_spin = matches!(min_duty, Some(min_duty) if duty >= min_duty);
+        if speed.as_rpm() == 0 {
+            return if should_spin {
+                Reading::Stalled(format!("{} at {} duty", speed, duty))
+            } else {
+                Reading::Unknown
+            };
+        }
+        if !should_spin {
+            return Reading::Spinning;
+        }
+
+        match range.map(|range| range.expected_rpm(duty)) {
+            Some(expected) if (speed.as_rpm() as f64) < expected * MIN_SPEED_RATIO => {
+                Reading::Stalled(format!(
+                    "{} at {} duty, expected about {:.0} RPM",
+                    speed, duty, expected
+                ))
+            }
+            _ => Reading::Spinning,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration;
+    use std::{collections::BTreeMap, time::Duration};
+
+    fn settings() -> config::Stall {
+        config::Stall {
+            min_duty: None,
+            time: Duration::from_secs(10),
+            max_rpm: None,
+            action: config::StallAction::None,
+            hook: None,
+        }
+    }
+
+    fn duty(percentage: f64) -> Option<fan::Duty> {
+        Some(fan::Duty::from_saturating_percentage(percentage))
+    }
+
+    fn speed(rpm: u32) -> fan::Speed {
+        fan::Speed::from_raw_ec_bytes(1, 0, rpm)
+    }
+
+    /// Check readings of the given speeds, one per second, returning the seconds with an event
+    fn check(
+        detector: &mut Detector,
+        range: Option<&speed::Range>,
+        speeds: &[u32],
+        start: Instant,
+    ) -> Vec<(u64, &'static str)> {
+        (0..)
+            .zip(speeds)
+            .filter_map(|(second, rpm)| {
+                let now = start + Duration::from_secs(second);
+                match detector.check(&settings(), duty(40.0), range, speed(*rpm), now)? {
+                    Event::Stalled(_) => Some((second, "stalled")),
+                    Event::Recovered => Some((second, "recovered")),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn standing_still() {
+        let mut detector = Detector::default();
+        let start = Instant::now();
+        // Nothing to check against, before any duty was applied
+        assert_eq!(check(&mut detector, None, &[0; 12], start), vec![]);
+
+        detector.applied(duty(50.0).unwrap());
+        assert_eq!(
+            check(&mut detector, None, &[0; 12], start),
+            vec![(10, "stalled")]
+        );
+        assert!(detector.stalled());
+    }
+
+    #[test]
+    fn too_slow() {
+        let range = speed::Range::new(4000);
+        let mut detector = Detector::default();
+        detector.applied(duty(50.0).unwrap());
+        let start = Instant::now();
+        // Spinning at 1000 RPM or more is enough, short drops are not
+        let mut speeds = vec![1000, 900, 900, 1100];
+        speeds.extend([900; 12].iter());
+        assert_eq!(
+            check(&mut detector, Some(&range), &speeds, start),
+            vec![(14, "stalled")]
+        );
+        // Without a known speed range, any speed is enough
+        let mut detector = Detector::default();
+        detector.applied(duty(50.0).unwrap());
+        assert_eq!(check(&mut detector, None, &speeds, start), vec![]);
+    }
+
+    #[test]
+    fn below_min_duty() {
+        let mut detector = Detector::default();
+        detector.applied(duty(30.0).unwrap());
+        let start = Instant::now();
+        assert_eq!(check(&mut detector, None, &[0; 12], start), vec![]);
+        assert!(!detector.stalled());
+
+        // Fans, that do not spin at any duty, never stall
+        let now = start + Duration::from_secs(20);
+        detector.applied(duty(100.0).unwrap());
+        assert!(detector
+            .check(&settings(), None, None, speed(0), start)
+            .is_none());
+        assert!(detector
+            .check(&settings(), None, None, speed(0), now)
+            .is_none());
+    }
+
+    #[test]
+    fn recovered() {
+        let mut detector = Detector::default();
+        detector.applied(duty(50.0).unwrap());
+        let start = Instant::now();
+        assert_eq!(
+            check(&mut detector, None, &[0; 11], start),
+            vec![(10, "stalled")]
+        );
+
+        // The stalled fan stays stalled, as long as its duty is too low to tell
+        detector.applied(duty(20.0).unwrap());
+        assert_eq!(check(&mut detector, None, &[0; 3], start), vec![]);
+        assert!(detector.stalled());
+
+        detector.applied(duty(50.0).unwrap());
+        assert_eq!(
+            check(&mut detector, None, &[0, 2000, 2000], start),
+            vec![(1, "recovered")]
+        );
+        assert!(!detector.stalled());
+    }
+
+    #[test]
+    fn min_duty() {
+        let fan = calibration::FanCalibration {
+            spin_up_duty: duty(25.0),
+            stop_duty: duty(15.0),
+            max_rpm: 4000,
+            curve: Vec::new(),
+        };
+        let mut calibration = calibration::Calibration {
+            fans: BTreeMap::new(),
+        };
+        calibration.fans.insert(fan::Id::CPU, fan.clone());
+        calibration.fans.insert(
+            fan::Id::GPU,
+            calibration::FanCalibration {
+                spin_up_duty: None,
+                ..fan
+            },
+        );
+
+        let mut settings = settings();
+        assert_eq!(settings.min_duty(None, fan::Id::CPU), duty(40.0));
+        assert_eq!(
+            settings.min_duty(Some(&calibration), fan::Id::CPU),
+            duty(25.0)
+        );
+        assert_eq!(settings.min_duty(Some(&calibration), fan::Id::GPU), None);
+
+        settings.min_duty = duty(60.0);
+        assert_eq!(
+            settings.min_duty(Some(&calibration), fan::Id::CPU),
+            duty(60.0)
+        );
+    }
+}
@@ -0,0 +1,394 @@
+use crate::{
+    config,
+    daemon::{self, Status},
+    ec, fan, model, sensor, utils,
+};
+use derive_more::Display;
+use std::{
+    error, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Writes each cycle of `clevo-fan auto' to a CSV file, see `--record'
+///
+/// Each row holds the time since starting, the raw registers, the sensors of the kernel, the
+/// smoothed temperatures and the duties of each fan of the model. Values that are not known, e.g.
+/// because the EC could not be read or the fan is not controlled, are left empty.
+pub struct Recorder {
+    writer: csv::Writer<fs::File>,
+    started: Instant,
+    fans: Vec<fan::Id>,
+    sensors: Vec<sensor::Source>,
+}
+
+impl Recorder {
+    /// Create the trace, with a column for each of `sensors', named like `<sensor>_celsius'
+    pub fn create(
+        path: &Path,
+        model: &model::Model,
+        sensors: Vec<sensor::Source>,
+    ) -> Result<Self, csv::Error> {
+        let fans: Vec<_> = fan::Id::all().take(model.fans.len()).collect();
+
+        let mut header = vec![
+            "time_seconds".to_owned(),
+            "cpu_temp_raw".to_owned(),
+            "gpu_temp_raw".to_owned(),
+        ];
+        for id in &fans {
+            for name in &["duty_raw", "speed_raw_hi", "speed_raw_lo"] {
+                header.push(format!("fan{}_{}", id, name));
+            }
+        }
+        for source in &sensors {
+            header.push(format!("{}_celsius", source));
+        }
+        header.push("smoothed_cpu_temp_celsius".to_owned());
+        header.push("smoothed_gpu_temp_celsius".to_owned());
+        for id in &fans {
+            header.push(format!("fan{}_target_duty_percent", id));
+            header.push(format!("fan{}_duty_percent", id));
+        }
+
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(&header)?;
+        writer.flush()?;
+
+        Ok(Recorder {
+            writer,
+            started: Instant::now(),
+            fans,
+            sensors,
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        registers: Option<&ec::Registers>,
+        sensors: &sensor::Readings,
+        status: &Status,
+    ) -> Result<(), csv::Error> {
+        fn optional<T: ToString>(value: Option<T>) -> String {
+            value.map_or(String::new(), |value| value.to_string())
+        }
+        let celsius =
+            |temp: Option<utils::Temperature>| optional(temp.map(|temp| temp.as_degrees_celsius()));
+
+        let raw = registers.map(|ec| &ec.raw);
+        let mut row = vec![
+            self.started.elapsed().as_secs_f64().to_string(),
+            optional(raw.map(|raw| raw.cpu_temp)),
+            optional(raw.map(|raw| raw.gpu_temp)),
+        ];
+        for id in &self.fans {
+            let fan = raw.and_then(|raw| raw.fans.get(id.index()));
+            row.push(optional(fan.map(|fan| fan.duty)));
+            row.push(optional(fan.map(|fan| fan.rpms_hi)));
+            row.push(optional(fan.map(|fan| fan.rpms_lo)));
+        }
+        for source in &self.sensors {
+            row.push(celsius(sensors.get(source).copied().flatten()));
+        }
+        row.push(celsius(status.smoothed_cpu_temp));
+        row.push(celsius(status.smoothed_gpu_temp));
+        for id in &self.fans {
+            let fan = status.fans.iter().find(|fan| fan.id == *id);
+            row.push(optional(fan.map(|fan| fan.target_fan_duty.as_percentage())));
+            row.push(optional(fan.map(|fan| fan.fan_duty.as_percentage())));
+        }
+
+        self.writer.write_record(&row)?;
+        // Keep the trace usable, should we get killed
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "Cannot read trace {}: {}", "_0.display()", _1)]
+    Read(PathBuf, csv::Error),
+    #[display(fmt = "Invalid trace {}: {}", "_0.display()", _1)]
+    Invalid(PathBuf, String),
+    #[display(fmt = "{}", _0)]
+    Write(csv::Error),
+}
+impl error::Error for Error {}
+
+/// One cycle of a recorded trace, as far as needed for replaying it
+struct Sample {
+    time: f64,
+    cpu_temp: Option<utils::Temperature>,
+    gpu_temp: Option<utils::Temperature>,
+    sensors: sensor::Readings,
+    /// Applied duty of each fan, in percent, if recorded
+    duties: Vec<(fan::Id, Option<f64>)>,
+}
+
+fn read(path: &Path) -> Result<Vec<Sample>, Error> {
+    let read_error = |err| Error::Read(path.to_owned(), err);
+    let invalid = |message: String| Error::Invalid(path.to_owned(), message);
+
+    let mut reader = csv::Reader::from_path(path).map_err(read_error)?;
+    let header = reader.headers().map_err(read_error)?.clone();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|column| column == name)
+            .ok_or_else(|| invalid(format!("Missing column `{}'", name)))
+    };
+    let time = column("time_seconds")?;
+    let cpu_temp = column("cpu_temp_raw")?;
+    let gpu_temp = column("gpu_temp_raw")?;
+    let duties: Vec<_> = fan::Id::all()
+        .filter_map(|id| {
+            let name = format!("fan{}_duty_percent", id);
+            header
+                .iter()
+                .position(|column| column == name)
+                .map(|column| (id, column))
+        })
+        .collect();
+    // Traces recorded before sensors of the kernel were recorded simply have none of them
+    let sensors: Vec<_> = header
+        .iter()
+        .enumerate()
+        .filter_map(|(column, name)| {
+            let source = sensor::Source::from_str(name.strip_suffix("_celsius")?).ok()?;
+            Some((source, column))
+        })
+        .collect();
+
+    let mut samples = Vec::new();
+    for (row, record) in reader.records().enumerate() {
+        let record = record.map_err(read_error)?;
+        let field = |column: usize| record.get(column).unwrap_or("");
+        let line = row + 2;
+        let temp = |column: usize| match field(column) {
+            "" => Ok(None),
+            value => u8::from_str(value)
+                .map(|value| Some(utils::Temperature::from_degrees_celsius(value as f64)))
+                .map_err(|_| invalid(format!("Invalid temperature `{}' in line {}", value, line))),
+        };
+        let celsius = |column: usize| match field(column) {
+            "" => Ok(None),
+            value => f64::from_str(value)
+                .ok()
+                .filter(|value| value.is_finite())
+                .map(|value| Some(utils::Temperature::from_degrees_celsius(value)))
+                .ok_or_else(|| {
+                    invalid(format!("Invalid temperature `{}' in line {}", value, line))
+                }),
+        };
+
+        samples.push(Sample {
+            time: f64::from_str(field(time))
+                .map_err(|_| invalid(format!("Invalid time `{}' in line {}", field(time), line)))?,
+            cpu_temp: temp(cpu_temp)?,
+            gpu_temp: temp(gpu_temp)?,
+            sensors: sensors
+                .iter()
+                .map(|(source, column)| Ok((source.clone(), celsius(*column)?)))
+                .collect::<Result<_, Error>>()?,
+            duties: duties
+                .iter()
+                .map(|(id, column)| (*id, f64::from_str(field(*column)).ok()))
+                .collect(),
+        });
+    }
+
+    Ok(samples)
+}
+
+/// Feed a recorded trace through filters and policies, as `clevo-fan auto' would have
+///
+/// The resulting fan duties are printed as CSV, next to the duties recorded, or as bars with
+/// `--monitor'. The time between samples is taken from the trace, so time-dependent policies
+/// behave as they would have. Sensors of the kernel are read from the trace, those not recorded in
+/// it get the CPU temperature.
+pub fn replay(path: &Path, settings: &config::Settings) -> Result<(), Error> {
+    replay_to(path, settings, io::stdout())
+}
+
+fn replay_to<W: io::Write>(
+    path: &Path,
+    settings: &config::Settings,
+    output: W,
+) -> Result<(), Error> {
+    let samples = read(path)?;
+    let mut pipeline = daemon::Pipeline::new(settings);
+
+    let mut writer = csv::Writer::from_writer(output);
+    if settings.monitor {
+        daemon::print_monitor_header(settings);
+    } else {
+        let mut header = vec![
+            "time_seconds".to_owned(),
+            "cpu_temp_celsius".to_owned(),
+            "gpu_temp_celsius".to_owned(),
+            "smoothed_cpu_temp_celsius".to_owned(),
+            "smoothed_gpu_temp_celsius".to_owned(),
+        ];
+        for id in settings.fan_ids() {
+            header.push(format!("fan{}_policy_duty_percent", id));
+            header.push(format!("fan{}_duty_percent", id));
+            header.push(format!("fan{}_recorded_duty_percent", id));
+        }
+        writer.write_record(&header).map_err(Error::Write)?;
+    }
+
+    let mut last_time: Option<f64> = None;
+    for sample in samples {
+        let elapsed = last_time.map_or(0.0, |last| (sample.time - last).max(0.0));
+        last_time = Some(sample.time);
+        let mut sensors = sensor::substitute(settings.sensors(), sample.cpu_temp);
+        sensors.extend(sample.sensors);
+        let step = pipeline.step(
+            settings,
+            sample.cpu_temp,
+            sample.gpu_temp,
+            &sensors,
+            Duration::from_secs_f64(elapsed),
+        );
+
+        if settings.monitor {
+            daemon::print_monitor_line(settings, sample.cpu_temp, &step, None);
+            continue;
+        }
+
+        let celsius = |temp: Option<utils::Temperature>| {
+            temp.map_or(String::new(), |temp| temp.as_degrees_celsius().to_string())
+        };
+        let mut row = vec![
+            sample.time.to_string(),
+            celsius(sample.cpu_temp),
+            celsius(sample.gpu_temp),
+            celsius(step.smoothed_cpu_temp),
+            celsius(step.smoothed_gpu_temp),
+        ];
+        for fan in &step.fans {
+            row.push(fan.policy_fan_duty.as_percentage().to_string());
+            row.push(fan.target_fan_duty.as_percentage().to_string());
+            row.push(
+                sample
+                    .duties
+                    .iter()
+                    .find(|(id, _)| *id == fan.id)
+                    .and_then(|(_, duty)| *duty)
+                    .map_or(String::new(), |duty| duty.to_string()),
+            );
+        }
+        writer.write_record(&row).map_err(Error::Write)?;
+    }
+
+    writer.flush().map_err(|err| Error::Write(err.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AutoOptions, Policies};
+
+    fn celsius(temp: Option<f64>) -> Option<utils::Temperature> {
+        temp.map(utils::Temperature::from_degrees_celsius)
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let model = model::Model::select(Some("clevo"), None, false).unwrap();
+        let source = sensor::Source::from_str("thermal:0").unwrap();
+        let options = AutoOptions {
+            input: Some(config::Input::from_str("max(cpu,thermal:0)").unwrap()),
+            moving_average: Some(2.into()),
+            ..AutoOptions::default()
+        };
+        let policies = Policies {
+            curve: Some("40:0,80:100".to_owned().into()),
+            ..Policies::default()
+        };
+        let settings = config::Settings::resolve(&options, &policies, None, None).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.csv");
+        let mut recorder = Recorder::create(&path, &model, settings.sensors()).unwrap();
+        let mut pipeline = daemon::Pipeline::new(&settings);
+        let mut recorded = Vec::new();
+        let samples = [
+            (Some(50), Some(45.5)),
+            (Some(60), None),
+            (None, Some(72.25)),
+            (Some(75), Some(70.0)),
+            (Some(55), Some(58.0)),
+        ];
+        for (cpu_temp, sensor_temp) in samples.iter() {
+            let registers = cpu_temp.map(|temp| {
+                let mut buf = [0; ec::EC_REG_SIZE];
+                buf[model.cpu_temp as usize] = temp;
+                buf[model.gpu_temp as usize] = 40;
+                ec::Registers::decode(&buf, &model)
+            });
+            let cpu_temp = registers.as_ref().map(|registers| registers.cpu_temp);
+            let gpu_temp = registers.as_ref().map(|registers| registers.gpu_temp);
+            let mut sensors = sensor::Readings::new();
+            sensors.insert(source.clone(), celsius(*sensor_temp));
+
+            let step = pipeline.step(
+                &settings,
+                cpu_temp,
+                gpu_temp,
+                &sensors,
+                Duration::from_secs(1),
+            );
+            let status = Status {
+                cpu_temp,
+                gpu_temp,
+                smoothed_cpu_temp: step.smoothed_cpu_temp,
+                smoothed_gpu_temp: step.smoothed_gpu_temp,
+                fans: step
+                    .fans
+                    .iter()
+                    .map(|fan| daemon::FanStatus {
+                        id: fan.id,
+                        target_fan_duty: fan.target_fan_duty,
+                        fan_duty: fan.target_fan_duty,
+                        target_rpm: None,
+                        fan_speed: None,
+                        stalled: false,
+                    })
+                    .collect(),
+            };
+            recorder
+                .record(registers.as_ref(), &sensors, &status)
+                .unwrap();
+            recorded.push(step.fans[0].target_fan_duty.as_percentage());
+        }
+
+        let mut output = Vec::new();
+        replay_to(&path, &settings, &mut output).unwrap();
+        let mut reader = csv::Reader::from_reader(output.as_slice());
+        let header = reader.headers().unwrap().clone();
+        let column = |name| header.iter().position(|column| column == name).unwrap();
+        let (duty, recorded_duty) = (
+            column("fan1_duty_percent"),
+            column("fan1_recorded_duty_percent"),
+        );
+        let rows: Vec<_> = reader
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                let percent = |column| f64::from_str(&record[column]).unwrap();
+                (percent(duty), percent(recorded_duty))
+            })
+            .collect();
+
+        assert_eq!(rows.len(), recorded.len());
+        for ((replayed, recorded_in_trace), recorded) in rows.iter().zip(&recorded) {
+            assert_eq!(replayed, recorded);
+            assert_eq!(recorded_in_trace, recorded);
+        }
+        // The duties do follow the temperatures, so that the comparison means something
+        assert!(recorded.windows(2).any(|pair| pair[0] != pair[1]));
+    }
+}
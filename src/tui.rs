@@ -0,0 +1,317 @@
+use crate::{daemon::Status, utils};
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        cursor,
+        event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Style},
+    symbols,
+    text::Line,
+    widgets::{Axis, Block, Borders, Chart, Dataset, GraphType, Paragraph, Wrap},
+    Frame, Terminal,
+};
+use std::{
+    collections::VecDeque,
+    io,
+    time::{Duration, Instant},
+};
+
+/// How far back the charts reach
+const HISTORY: Duration = Duration::from_secs(300);
+/// Number of log messages kept
+const LOG_LENGTH: usize = 100;
+
+/// Name, color and points of a line in a chart
+type Series = (String, Color, Vec<(f64, f64)>);
+
+/// Colors of the lines of each fan, as target and applied duty
+const FAN_COLORS: [(Color, Color); 2] =
+    [(Color::Cyan, Color::Green), (Color::Blue, Color::Magenta)];
+
+/// Full-screen view of `clevo-fan auto'
+///
+/// Shows charts of the temperature, fan duty and fan speed over the last minutes, the current
+/// state of the fan control loop and a log of the errors, that would otherwise go to stderr.
+/// The terminal is restored when dropped.
+pub struct Dashboard {
+    terminal: Terminal<CrosstermBackend<io::Stdout>>,
+    started: Instant,
+    history: VecDeque<(f64, Status)>,
+    log: VecDeque<String>,
+}
+
+impl Dashboard {
+    pub fn new() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let terminal = execute!(io::stdout(), EnterAlternateScreen)
+            .and_then(|_| Terminal::new(CrosstermBackend::new(io::stdout())))
+            .and_then(|mut terminal| terminal.clear().map(|_| terminal))
+            .inspect_err(|_| restore())?;
+
+        Ok(Dashboard {
+            terminal,
+            started: Instant::now(),
+            history: VecDeque::new(),
+            log: VecDeque::new(),
+        })
+    }
+
+    fn seconds(&self, time: Instant) -> f64 {
+        (time - self.started).as_secs_f64()
+    }
+
+    pub fn push(&mut self, status: Status) {
+        let now = self.seconds(Instant::now());
+        self.history.push_back((now, status));
+        while self
+            .history
+            .front()
+            .is_some_and(|(time, _)| now - time > HISTORY.as_secs_f64())
+        {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn log(&mut self, message: String) {
+        let now = self.seconds(Instant::now());
+        self.log.push_back(format!("[{:8.1}s] {}", now, message));
+        if self.log.len() > LOG_LENGTH {
+            self.log.pop_front();
+        }
+    }
+
+    /// Whether the user asked to quit, by pressing `q', Escape or Ctrl-C
+    ///
+    /// In raw mode, Ctrl-C does not raise SIGINT, so this has to be polled regularly. Does not
+    /// block.
+    pub fn quit_requested(&mut self) -> io::Result<bool> {
+        while event::poll(Duration::from_secs(0))? {
+            if let Event::Key(key) = event::read()? {
+                let quit = match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => true,
+                    KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+                    _ => false,
+                };
+                if quit && key.kind == KeyEventKind::Press {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Redraw the whole screen, with `info` as lines of the status pane
+    pub fn draw(&mut self, info: &str) -> io::Result<()> {
+        let Dashboard {
+            terminal,
+            history,
+            log,
+            ..
+        } = self;
+        terminal.draw(|frame| Self::render(frame, history, log, info))?;
+        Ok(())
+    }
+
+    fn render(
+        frame: &mut Frame,
+        history: &VecDeque<(f64, Status)>,
+        log: &VecDeque<String>,
+        info: &str,
+    ) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(8)])
+            .split(frame.area());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(0), Constraint::Length(48)])
+            .split(rows[0]);
+        let charts = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Ratio(1, 3); 3])
+            .split(columns[0]);
+
+        let end = history.back().map_or(0.0, |(time, _)| *time);
+        let x_bounds = [end - HISTORY.as_secs_f64(), end];
+
+        let series = |value: &dyn Fn(&Status) -> Option<f64>| -> Vec<(f64, f64)> {
+            history
+                .iter()
+                .filter_map(|(time, status)| value(status).map(|value| (*time, value)))
+                .collect()
+        };
+
+        let celsius = |temp: Option<utils::Temperature>| temp.map(|temp| temp.as_degrees_celsius());
+        let temps = vec![
+            (
+                "CPU".to_owned(),
+                Color::Red,
+                series(&|status| celsius(status.cpu_temp)),
+            ),
+            (
+                "CPU smoothed".to_owned(),
+                Color::Yellow,
+                series(&|status| celsius(status.smoothed_cpu_temp)),
+            ),
+            (
+                "GPU".to_owned(),
+                Color::LightRed,
+                series(&|status| celsius(status.gpu_temp)),
+            ),
+            (
+                "GPU smoothed".to_owned(),
+                Color::LightYellow,
+                series(&|status| celsius(status.smoothed_gpu_temp)),
+            ),
+        ];
+        let (min_temp, max_temp) = temps
+            .iter()
+            .flat_map(|(_, _, data)| data)
+            .fold((f64::MAX, f64::MIN), |(min, max), (_, temp)| {
+                (min.min(*temp), max.max(*temp))
+            });
+        let temp_bounds = if min_temp <= max_temp {
+            [(min_temp - 5.0).floor(), (max_temp + 5.0).ceil()]
+        } else {
+            [30.0, 90.0]
+        };
+        Self::render_chart(
+            frame,
+            charts[0],
+            "Temperature (°C)",
+            temps,
+            x_bounds,
+            temp_bounds,
+        );
+
+        // The fans of the most recent cycle, in case of reconfiguration
+        let fans: Vec<_> = history.back().map_or(Vec::new(), |(_, status)| {
+            status.fans.iter().map(|fan| fan.id).collect()
+        });
+        let fan = |status: &Status, id| status.fans.iter().find(|fan| fan.id == id).cloned();
+
+        let mut duties = Vec::new();
+        let mut speeds = Vec::new();
+        for id in fans {
+            let (target_color, applied_color) = FAN_COLORS[id.index() % FAN_COLORS.len()];
+            duties.push((
+                format!("fan {} target", id),
+                target_color,
+                series(&|status| fan(status, id).map(|fan| fan.target_fan_duty.as_percentage())),
+            ));
+            duties.push((
+                format!("fan {} applied", id),
+                applied_color,
+                series(&|status| fan(status, id).map(|fan| fan.fan_duty.as_percentage())),
+            ));
+            speeds.push((
+                format!("fan {}", id),
+                applied_color,
+                series(&|status| {
+                    fan(status, id)
+                        .and_then(|fan| fan.fan_speed)
+                        .map(|speed| speed.as_rpm() as f64)
+                }),
+            ));
+        }
+        Self::render_chart(
+            frame,
+            charts[1],
+            "Fan Duty (%)",
+            duties,
+            x_bounds,
+            [0.0, 100.0],
+        );
+
+        let max_speed = speeds
+            .iter()
+            .flat_map(|(_, _, data)| data)
+            .map(|(_, rpm)| *rpm)
+            .fold(0.0, f64::max);
+        Self::render_chart(
+            frame,
+            charts[2],
+            "Fan Speed (RPM)",
+            speeds,
+            x_bounds,
+            [0.0, (max_speed * 1.1).max(1000.0).ceil()],
+        );
+
+        frame.render_widget(
+            Paragraph::new(info.lines().map(Line::from).collect::<Vec<_>>())
+                .wrap(Wrap { trim: false })
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(" Status (q to quit) "),
+                ),
+            columns[1],
+        );
+
+        let visible = rows[1].height.saturating_sub(2) as usize;
+        frame.render_widget(
+            Paragraph::new(
+                log.iter()
+                    .skip(log.len().saturating_sub(visible))
+                    .map(|line| Line::from(line.as_str()))
+                    .collect::<Vec<_>>(),
+            )
+            .block(Block::default().borders(Borders::ALL).title(" Log ")),
+            rows[1],
+        );
+    }
+
+    fn render_chart(
+        frame: &mut Frame,
+        area: Rect,
+        title: &str,
+        series: Vec<Series>,
+        x_bounds: [f64; 2],
+        y_bounds: [f64; 2],
+    ) {
+        let datasets = series
+            .iter()
+            .filter(|(_, _, data)| !data.is_empty())
+            .map(|(name, color, data)| {
+                Dataset::default()
+                    .name(name.clone())
+                    .marker(symbols::Marker::Braille)
+                    .graph_type(GraphType::Line)
+                    .style(Style::default().fg(*color))
+                    .data(data)
+            })
+            .collect();
+
+        let chart = Chart::new(datasets)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!(" {} ", title)),
+            )
+            .x_axis(Axis::default().bounds(x_bounds).labels(vec![
+                Line::from(format!("-{}s", HISTORY.as_secs())),
+                Line::from("now"),
+            ]))
+            .y_axis(Axis::default().bounds(y_bounds).labels(vec![
+                Line::from(format!("{}", y_bounds[0])),
+                Line::from(format!("{}", y_bounds[1])),
+            ]));
+        frame.render_widget(chart, area);
+    }
+}
+
+fn restore() {
+    terminal::disable_raw_mode().ok();
+    execute!(io::stdout(), LeaveAlternateScreen, cursor::Show).ok();
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        restore();
+    }
+}
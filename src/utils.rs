@@ -1,11 +1,43 @@
 use derive_more::{Display, From};
-use std::{cmp, collections::VecDeque, error::Error, fmt, iter, ops};
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    cmp,
+    collections::{BTreeMap, VecDeque},
+    error::Error,
+    fmt, iter, ops, str,
+    time::Duration,
+};
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+/// A temperature, as read from the EC or the kernel
+///
+/// Temperatures are totally ordered like `f64::total_cmp`, so that they can be sorted, e.g. by
+/// `MovingMedian`. A NaN, which no sensor should report, is thus neither lost nor does it
+/// compare equal to everything else.
+#[derive(Debug, Clone, Copy)]
 pub struct Temperature {
     degrees_celsius: f64,
 }
 
+impl PartialEq for Temperature {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Temperature {}
+
+impl PartialOrd for Temperature {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Temperature {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.degrees_celsius.total_cmp(&other.degrees_celsius)
+    }
+}
+
 impl ops::Div<usize> for Temperature {
     type Output = Self;
     fn div(self, rhs: usize) -> Self::Output {
@@ -25,31 +57,115 @@ impl iter::Sum<Temperature> for Temperature {
 
 impl fmt::Display for Temperature {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:.1}", self.degrees_celsius)?;
-        if !f.alternate() {
-            write!(f, "°C")?;
-        }
-
-        Ok(())
+        fmt::Display::fmt(&self.in_unit(Unit::Celsius), f)
     }
 }
 
 impl Temperature {
-    pub fn from_degrees_celsius(degrees_celsius: u8) -> Self {
+    pub fn from_degrees_celsius(degrees_celsius: f64) -> Self {
+        Self { degrees_celsius }
+    }
+
+    pub fn from_millidegrees_celsius(millidegrees_celsius: i64) -> Self {
         Self {
-            degrees_celsius: degrees_celsius as f64,
+            degrees_celsius: millidegrees_celsius as f64 / 1000.0,
         }
     }
 
-    pub fn as_degrees_celsius(&self) -> u8 {
-        self.degrees_celsius as u8
+    pub fn as_degrees_celsius(&self) -> f64 {
+        self.degrees_celsius
     }
 
+    /// Highest temperature the EC can report, assumed when a temperature cannot be read
+    ///
+    /// This is finite, so that policies depending on the history of the temperature, like the PID
+    /// controller, recover once the temperature can be read again.
     pub const fn max() -> Self {
         Self {
-            degrees_celsius: f64::MAX,
+            degrees_celsius: u8::MAX as f64,
         }
     }
+
+    pub fn as_unit(&self, unit: Unit) -> f64 {
+        match unit {
+            Unit::Celsius => self.degrees_celsius,
+            Unit::Fahrenheit => self.degrees_celsius * 9.0 / 5.0 + 32.0,
+            Unit::Kelvin => self.degrees_celsius + 273.15,
+        }
+    }
+
+    /// Display the temperature in the given unit, the alternate form leaving out the unit
+    pub fn in_unit(self, unit: Unit) -> InUnit {
+        InUnit { temp: self, unit }
+    }
+}
+
+/// Unit to display temperatures in
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Unit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+#[derive(Debug, Display)]
+#[display(fmt = "Invalid unit `{}', expected celsius, fahrenheit or kelvin", _0)]
+pub struct InvalidUnit(String);
+impl Error for InvalidUnit {}
+
+impl str::FromStr for Unit {
+    type Err = InvalidUnit;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "celsius" | "c" => Ok(Unit::Celsius),
+            "fahrenheit" | "f" => Ok(Unit::Fahrenheit),
+            "kelvin" | "k" => Ok(Unit::Kelvin),
+            _ => Err(InvalidUnit(s.to_owned())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Unit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let unit = String::deserialize(deserializer)?;
+        str::FromStr::from_str(&unit).map_err(de::Error::custom)
+    }
+}
+
+impl Unit {
+    /// Name of the unit, as used in the keys of machine-readable output
+    pub fn name(self) -> &'static str {
+        match self {
+            Unit::Celsius => "celsius",
+            Unit::Fahrenheit => "fahrenheit",
+            Unit::Kelvin => "kelvin",
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Unit::Celsius => "°C",
+            Unit::Fahrenheit => "°F",
+            Unit::Kelvin => " K",
+        }
+    }
+}
+
+/// A temperature displayed in a unit, see `Temperature::in_unit`
+pub struct InUnit {
+    temp: Temperature,
+    unit: Unit,
+}
+
+impl fmt::Display for InUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = format!("{:.1}", self.temp.as_unit(self.unit));
+        if !f.alternate() {
+            text.push_str(self.unit.symbol());
+        }
+        f.pad(&text)
+    }
 }
 
 pub type FlexibleResult<T> = Result<T, Box<dyn Error>>;
@@ -67,109 +183,552 @@ impl<T, E> ResultExt for Result<T, E> {
 pub struct SyscallError(nc::syscalls::Errno);
 impl Error for SyscallError {}
 
-pub struct MovingAverage<I>
+/// Filter of a series of values, e.g. the temperature read in each cycle
+///
+/// Filters can be chained, each one filtering the values put out by the previous one.
+pub trait Filter<T> {
+    /// Add a value, read `elapsed' after the previous one, and get the filtered value
+    fn push(&mut self, value: T, elapsed: Duration) -> T;
+}
+
+/// Moving average over the most recent values
+pub struct MovingAverage<T> {
+    window_size: usize,
+    buf: VecDeque<T>,
+}
+
+impl<T> MovingAverage<T>
 where
-    // Repeat type constrainst for more ergonomic error message
-    I: Iterator,
-    I::Item: Copy + iter::Sum<I::Item> + ops::Div<usize, Output = I::Item>,
+    T: Copy + iter::Sum<T> + ops::Div<usize, Output = T>,
 {
-    data: I,
+    pub fn new(window_size: usize) -> Self {
+        MovingAverage {
+            window_size,
+            buf: VecDeque::new(),
+        }
+    }
+
+    /// Add a value to the window and get the resulting average
+    pub fn push(&mut self, value: T) -> T {
+        self.buf.push_back(value);
+        if self.buf.len() > self.window_size {
+            self.buf.pop_front();
+        }
+
+        let sum: T = self.buf.iter().copied().sum();
+        sum / self.buf.len()
+    }
+
+    /// Change the window size, keeping as many of the most recent values as fit
+    pub fn resize(&mut self, window_size: usize) {
+        self.window_size = window_size;
+        while self.buf.len() > window_size {
+            self.buf.pop_front();
+        }
+    }
+}
+
+/// Moving median over the most recent values
+///
+/// The window is split into its lower and upper half, each kept sorted, so that adding a value
+/// takes logarithmic time, regardless of the window size.
+pub struct MovingMedian<T> {
     window_size: usize,
-    buf: VecDeque<I::Item>,
+    buf: VecDeque<T>,
+    /// Values below the median, with their number of occurrences
+    lower: Multiset<T>,
+    /// The median and the values above it
+    upper: Multiset<T>,
 }
 
-impl<I> Iterator for MovingAverage<I>
+impl<T> MovingMedian<T>
 where
-    I: Iterator,
-    I::Item: Copy + iter::Sum<I::Item> + ops::Div<usize, Output = I::Item>,
+    T: Clone + Ord,
 {
-    type Item = I::Item;
-    fn next(&mut self) -> Option<Self::Item> {
-        self.data.next().map(|value| {
-            self.buf.push_back(value);
-            if self.buf.len() > self.window_size {
-                self.buf.pop_front();
-            }
+    pub fn new(window_size: usize) -> Self {
+        MovingMedian {
+            window_size,
+            buf: VecDeque::new(),
+            lower: Multiset::new(),
+            upper: Multiset::new(),
+        }
+    }
+
+    /// Add a value to the window and get the resulting median
+    ///
+    /// Of an even number of values, the upper one of the two in the middle is the median.
+    pub fn push(&mut self, value: T) -> T {
+        match self.upper.first() {
+            Some(median) if value < *median => self.lower.insert(value.clone()),
+            _ => self.upper.insert(value.clone()),
+        }
+        self.buf.push_back(value);
+        self.shrink();
+        self.balance();
+
+        self.upper
+            .first()
+            .cloned()
+            .expect("The window holds at least the pushed value")
+    }
 
-            let sum: I::Item = self.buf.iter().map(Clone::clone).sum();
-            let avg = sum / self.buf.len();
+    /// Change the window size, keeping as many of the most recent values as fit
+    pub fn resize(&mut self, window_size: usize) {
+        self.window_size = window_size;
+        self.shrink();
+        self.balance();
+    }
 
-            avg
-        })
+    /// Drop the oldest values, that do not fit into the window
+    fn shrink(&mut self) {
+        // The most recent value is kept even in a window of size 0, so that there is a median
+        while self.buf.len() > self.window_size.max(1) {
+            if let Some(value) = self.buf.pop_front() {
+                if !self.lower.remove(&value) {
+                    self.upper.remove(&value);
+                }
+            }
+        }
+    }
+
+    /// Move values between the halves, until the lower one holds the lower half of the window
+    fn balance(&mut self) {
+        let lower_len = self.buf.len() / 2;
+        while self.lower.len() > lower_len {
+            if let Some(value) = self.lower.pop_last() {
+                self.upper.insert(value);
+            }
+        }
+        while self.lower.len() < lower_len {
+            if let Some(value) = self.upper.pop_first() {
+                self.lower.insert(value);
+            }
+        }
     }
 }
 
-pub trait MovingAverageIteratorExt<I> {
-    fn moving_average(self, window_size: usize) -> MovingAverage<I>
-    where
-        I: Iterator,
-        I::Item: Copy + iter::Sum<I::Item> + ops::Div<usize, Output = I::Item>;
+/// Sorted values, with repetitions
+struct Multiset<T> {
+    counts: BTreeMap<T, usize>,
+    len: usize,
 }
 
-impl<I> MovingAverageIteratorExt<I> for I {
-    fn moving_average(self, window_size: usize) -> MovingAverage<I>
+impl<T: Ord> Multiset<T> {
+    fn new() -> Self {
+        Multiset {
+            counts: BTreeMap::new(),
+            len: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn first(&self) -> Option<&T> {
+        self.counts.keys().next()
+    }
+
+    fn insert(&mut self, value: T) {
+        *self.counts.entry(value).or_insert(0) += 1;
+        self.len += 1;
+    }
+
+    /// Remove one occurrence of the value, returning whether there was one
+    fn remove(&mut self, value: &T) -> bool {
+        match self.counts.get_mut(value) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(value);
+                }
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pop_first(&mut self) -> Option<T>
     where
-        I: Iterator,
-        I::Item: Copy + iter::Sum<I::Item> + ops::Div<usize, Output = I::Item>,
+        T: Clone,
     {
-        MovingAverage {
-            data: self,
-            window_size,
-            buf: VecDeque::new(),
-        }
+        let value = self.counts.keys().next()?.clone();
+        self.remove(&value);
+        Some(value)
+    }
+
+    fn pop_last(&mut self) -> Option<T>
+    where
+        T: Clone,
+    {
+        let value = self.counts.keys().next_back()?.clone();
+        self.remove(&value);
+        Some(value)
     }
 }
 
-pub struct MovingMedian<I>
+impl<T> Filter<T> for MovingAverage<T>
 where
-    // Repeat type constrainst for more ergonomic error message
-    I: Iterator,
-    I::Item: Clone + cmp::PartialOrd,
+    T: Copy + iter::Sum<T> + ops::Div<usize, Output = T>,
 {
-    data: I,
-    window_size: usize,
-    buf: VecDeque<I::Item>,
+    fn push(&mut self, value: T, _elapsed: Duration) -> T {
+        MovingAverage::push(self, value)
+    }
 }
 
-impl<I> Iterator for MovingMedian<I>
+impl<T> Filter<T> for MovingMedian<T>
 where
-    I: Iterator,
-    I::Item: Clone + cmp::PartialOrd,
+    T: Clone + Ord,
 {
-    type Item = I::Item;
-    fn next(&mut self) -> Option<Self::Item> {
-        self.data.next().map(|value| {
-            self.buf.push_back(value);
-            if self.buf.len() > self.window_size {
-                self.buf.pop_front();
+    fn push(&mut self, value: T, _elapsed: Duration) -> T {
+        MovingMedian::push(self, value)
+    }
+}
+
+/// Exponential moving average, weighting each temperature by how long ago it was read
+///
+/// The average follows a sudden change of the temperature by 63% within the time constant,
+/// regardless of how often temperatures are read.
+pub struct ExponentialMovingAverage {
+    time_constant: Duration,
+    average: Option<Temperature>,
+}
+
+impl ExponentialMovingAverage {
+    pub fn new(time_constant: Duration) -> Self {
+        ExponentialMovingAverage {
+            time_constant,
+            average: None,
+        }
+    }
+
+    /// Change the time constant, keeping the current average
+    pub fn set_time_constant(&mut self, time_constant: Duration) {
+        self.time_constant = time_constant;
+    }
+}
+
+impl Filter<Temperature> for ExponentialMovingAverage {
+    fn push(&mut self, value: Temperature, elapsed: Duration) -> Temperature {
+        let average = match self.average {
+            Some(average) if !self.time_constant.is_zero() => {
+                let weight =
+                    1.0 - (-elapsed.as_secs_f64() / self.time_constant.as_secs_f64()).exp();
+                Temperature::from_degrees_celsius(
+                    average.degrees_celsius
+                        + weight * (value.degrees_celsius - average.degrees_celsius),
+                )
             }
+            _ => value,
+        };
+        self.average = Some(average);
+        average
+    }
+}
 
-            let mut buf = Vec::from(self.buf.clone());
-            buf.sort_by(|a, b| a.partial_cmp(b).unwrap_or(cmp::Ordering::Less));
-            let median = buf.remove(buf.len() / 2);
+/// Number of consecutive temperatures beyond the maximum jump, after which `SpikeRejection' takes
+/// them as a real change
+const SPIKE_LENGTH: usize = 3;
 
-            median
-        })
+/// Drops temperatures jumping too far away from the previous one, in place of which the previous
+/// one is repeated
+///
+/// Temperatures staying away for `SPIKE_LENGTH' readings are no spike, but a real change, and let
+/// through.
+pub struct SpikeRejection {
+    /// Largest change between two readings, in degrees Celsius
+    max_jump: f64,
+    last: Option<Temperature>,
+    rejected: usize,
+}
+
+impl SpikeRejection {
+    pub fn new(max_jump: f64) -> Self {
+        SpikeRejection {
+            max_jump,
+            last: None,
+            rejected: 0,
+        }
+    }
+
+    /// Change the largest change let through, keeping the previous temperature
+    pub fn set_max_jump(&mut self, max_jump: f64) {
+        self.max_jump = max_jump;
     }
 }
 
-pub trait MovingMedianIteratorExt<I> {
-    fn moving_median(self, window_size: usize) -> MovingMedian<I>
-    where
-        I: Iterator,
-        I::Item: Clone + cmp::PartialOrd;
+impl Filter<Temperature> for SpikeRejection {
+    fn push(&mut self, value: Temperature, _elapsed: Duration) -> Temperature {
+        match self.last {
+            Some(last)
+                if (value.degrees_celsius - last.degrees_celsius).abs() > self.max_jump
+                    && self.rejected + 1 < SPIKE_LENGTH =>
+            {
+                self.rejected += 1;
+                last
+            }
+            _ => {
+                self.rejected = 0;
+                self.last = Some(value);
+                value
+            }
+        }
+    }
 }
 
-impl<I> MovingMedianIteratorExt<I> for I {
-    fn moving_median(self, window_size: usize) -> MovingMedian<I>
-    where
-        I: Iterator,
-        I::Item: Clone + cmp::PartialOrd,
-    {
-        MovingMedian {
-            data: self,
-            window_size,
-            buf: VecDeque::new(),
+/// Limits how fast the temperature can change, following larger changes only gradually
+pub struct RateLimit {
+    /// Largest change, in degrees Celsius per second
+    max_rate: f64,
+    last: Option<Temperature>,
+}
+
+impl RateLimit {
+    pub fn new(max_rate: f64) -> Self {
+        RateLimit {
+            max_rate,
+            last: None,
         }
     }
+
+    /// Change the largest rate of change, keeping the previous temperature
+    pub fn set_max_rate(&mut self, max_rate: f64) {
+        self.max_rate = max_rate;
+    }
+}
+
+impl Filter<Temperature> for RateLimit {
+    fn push(&mut self, value: Temperature, elapsed: Duration) -> Temperature {
+        let limited = match self.last {
+            Some(last) => {
+                let max_change = self.max_rate * elapsed.as_secs_f64();
+                Temperature::from_degrees_celsius(value.degrees_celsius.clamp(
+                    last.degrees_celsius - max_change,
+                    last.degrees_celsius + max_change,
+                ))
+            }
+            None => value,
+        };
+        self.last = Some(limited);
+        limited
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moving median, sorting the whole window for each value
+    struct SortingMovingMedian {
+        window_size: usize,
+        buf: VecDeque<Temperature>,
+    }
+
+    impl SortingMovingMedian {
+        fn new(window_size: usize) -> Self {
+            SortingMovingMedian {
+                window_size,
+                buf: VecDeque::new(),
+            }
+        }
+
+        fn push(&mut self, value: Temperature) -> Temperature {
+            self.buf.push_back(value);
+            self.resize(self.window_size);
+            let mut sorted = Vec::from(self.buf.clone());
+            sorted.sort();
+            sorted[sorted.len() / 2]
+        }
+
+        fn resize(&mut self, window_size: usize) {
+            self.window_size = window_size;
+            while self.buf.len() > window_size.max(1) {
+                self.buf.pop_front();
+            }
+        }
+    }
+
+    /// Temperatures between 50 and 50 + `spread` degrees, in whole degrees so that many repeat
+    fn temperatures(count: usize, spread: u32) -> Vec<Temperature> {
+        let mut state: u32 = 0x2545_f491;
+        (0..count)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                Temperature::from_degrees_celsius(50.0 + (state % spread) as f64)
+            })
+            .collect()
+    }
+
+    fn compare(window_size: usize, temps: &[Temperature]) {
+        let mut median = MovingMedian::new(window_size);
+        let mut sorting = SortingMovingMedian::new(window_size);
+        for (i, temp) in temps.iter().enumerate() {
+            assert_eq!(
+                median.push(*temp),
+                sorting.push(*temp),
+                "window {}, value {}",
+                window_size,
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn moving_median() {
+        for window_size in 1..=12 {
+            compare(window_size, &temperatures(200, 1000));
+        }
+        compare(61, &temperatures(500, 1000));
+    }
+
+    #[test]
+    fn moving_median_duplicates() {
+        for window_size in 1..=12 {
+            compare(window_size, &temperatures(200, 3));
+        }
+        compare(
+            4,
+            &[Temperature::from_degrees_celsius(60.0); 10]
+                .iter()
+                .copied()
+                .chain(temperatures(20, 2))
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn moving_median_window_0() {
+        // Like a window of 1, the most recent value is the median
+        compare(0, &temperatures(50, 10));
+    }
+
+    #[test]
+    fn moving_median_resize() {
+        let temps = temperatures(300, 20);
+        let mut median = MovingMedian::new(5);
+        let mut sorting = SortingMovingMedian::new(5);
+        for (i, chunk) in temps.chunks(30).enumerate() {
+            let window_size = [9, 2, 0, 15, 1, 7, 30, 3, 12, 4][i];
+            median.resize(window_size);
+            sorting.resize(window_size);
+            for temp in chunk {
+                assert_eq!(median.push(*temp), sorting.push(*temp));
+            }
+        }
+    }
+
+    #[test]
+    fn moving_median_nan() {
+        let mut temps = temperatures(100, 20);
+        for i in (0..temps.len()).step_by(7) {
+            temps[i] = Temperature::from_degrees_celsius(f64::NAN);
+        }
+        for window_size in 1..=8 {
+            compare(window_size, &temps);
+        }
+
+        // A NaN leaves the window again, like any other value
+        let mut median = MovingMedian::new(3);
+        median.push(Temperature::from_degrees_celsius(f64::NAN));
+        for temp in &temperatures(3, 1) {
+            median.push(*temp);
+        }
+        assert_eq!(median.lower.len() + median.upper.len(), 3);
+        assert_eq!(
+            median.upper.first().map(Temperature::as_degrees_celsius),
+            Some(50.0)
+        );
+    }
+
+    #[test]
+    fn temperature_order() {
+        let temp = Temperature::from_degrees_celsius;
+        assert!(temp(40.0) < temp(60.5));
+        assert_eq!(temp(f64::NAN), temp(f64::NAN));
+        assert!(temp(f64::NAN) > temp(f64::INFINITY));
+        assert!(temp(f64::NAN) != temp(60.0));
+    }
+
+    fn celsius(temp: Temperature) -> f64 {
+        temp.as_degrees_celsius()
+    }
+
+    #[test]
+    fn exponential_moving_average() {
+        let temp = Temperature::from_degrees_celsius;
+        let mut average = ExponentialMovingAverage::new(Duration::from_secs(10));
+        assert_eq!(
+            celsius(average.push(temp(50.0), Duration::from_secs(0))),
+            50.0
+        );
+        // 63% of the way within the time constant
+        let expected = 50.0 + 10.0 * (1.0 - (-1.0f64).exp());
+        let pushed = average.push(temp(60.0), Duration::from_secs(10));
+        assert!((celsius(pushed) - expected).abs() < 1e-9);
+        // No time passed, nothing changes
+        assert_eq!(average.push(temp(90.0), Duration::from_secs(0)), pushed);
+    }
+
+    #[test]
+    fn exponential_moving_average_independent_of_sampling() {
+        let temp = Temperature::from_degrees_celsius;
+        let mut once = ExponentialMovingAverage::new(Duration::from_secs(4));
+        let mut often = ExponentialMovingAverage::new(Duration::from_secs(4));
+        once.push(temp(40.0), Duration::from_secs(0));
+        often.push(temp(40.0), Duration::from_secs(0));
+
+        let once = once.push(temp(70.0), Duration::from_secs(6));
+        let mut last = temp(40.0);
+        for _ in 0..12 {
+            last = often.push(temp(70.0), Duration::from_millis(500));
+        }
+        assert!((celsius(once) - celsius(last)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn exponential_moving_average_without_time_constant() {
+        let temp = Temperature::from_degrees_celsius;
+        let mut average = ExponentialMovingAverage::new(Duration::from_secs(0));
+        average.push(temp(40.0), Duration::from_secs(1));
+        assert_eq!(
+            celsius(average.push(temp(70.0), Duration::from_secs(1))),
+            70.0
+        );
+    }
+
+    #[test]
+    fn spike_rejection() {
+        let temp = Temperature::from_degrees_celsius;
+        let mut spikes = SpikeRejection::new(5.0);
+        let mut push = |value| celsius(spikes.push(temp(value), Duration::from_secs(1)));
+        assert_eq!(push(50.0), 50.0);
+        assert_eq!(push(54.0), 54.0);
+        // A single spike is dropped
+        assert_eq!(push(80.0), 54.0);
+        assert_eq!(push(55.0), 55.0);
+        // A jump lasting for `SPIKE_LENGTH' temperatures is a real change
+        for _ in 1..SPIKE_LENGTH {
+            assert_eq!(push(70.0), 55.0);
+        }
+        assert_eq!(push(70.0), 70.0);
+        assert_eq!(push(71.0), 71.0);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let temp = Temperature::from_degrees_celsius;
+        let mut limit = RateLimit::new(2.0);
+        let mut push =
+            |value, millis| celsius(limit.push(temp(value), Duration::from_millis(millis)));
+        assert_eq!(push(50.0, 0), 50.0);
+        assert_eq!(push(60.0, 1000), 52.0);
+        assert_eq!(push(60.0, 2000), 56.0);
+        assert_eq!(push(40.0, 500), 55.0);
+        assert_eq!(push(40.0, 0), 55.0);
+        // Changes within the limit pass unchanged
+        assert_eq!(push(54.0, 1000), 54.0);
+    }
 }